@@ -1,18 +1,36 @@
-use crossbeam_channel::Sender;
-use image::DynamicImage;
 use nokhwa::{
-    pixel_format::{RgbAFormat, RgbFormat},
+    pixel_format::RgbFormat,
     utils::{CameraFormat, RequestedFormat, RequestedFormatType, Resolution},
     Camera as Cam,
 };
 
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::config::CameraControls;
+use crate::frame::{BufferPool, Frame};
+use crate::health::HealthCounters;
+use crate::mailbox::FrameSender;
+use crate::reload::LiveConfig;
+use crate::robot::RobotState;
+
+/// How long the camera can go without sending a frame before it is given up on and opened again
+pub const CAMERA_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to wait before asking again when the camera has no frame, so a failing camera doesn't spin a core
+pub const FRAME_RETRY_DELAY: Duration = Duration::from_millis(5);
+
 pub struct Camera {
     pub camera: Cam,
     pub index: u32,
 }
 
 impl Camera {
-    pub fn new(index: u32) -> Result<Self, ()> {
+    pub fn new(index: u32, controls: &CameraControls) -> Result<Self, ()> {
         // Setting the Camera Input format
         // let format = RequestedFormatType::AbsoluteHighestResolution;
         let format = RequestedFormatType::Exact(CameraFormat::new(
@@ -25,40 +43,93 @@ impl Camera {
 
         //Creates the camera with given settings
         match Cam::new(nokhwa::utils::CameraIndex::Index(index), format) {
-            Ok(mut camera) => {
-                let _ = camera.set_camera_control(
-                    nokhwa::utils::KnownCameraControl::Brightness,
-                    nokhwa::utils::ControlValueSetter::Integer(100),
-                );
-                let _ = camera.set_camera_control(
-                    nokhwa::utils::KnownCameraControl::Exposure,
-                    nokhwa::utils::ControlValueSetter::Integer(0),
-                );
-                let _ = camera.set_camera_control(
-                    nokhwa::utils::KnownCameraControl::Gain,
-                    nokhwa::utils::ControlValueSetter::Integer(100),
-                );
-                Ok(Camera { camera, index })
+            Ok(camera) => {
+                let mut camera = Camera { camera, index };
+                camera.set_controls(controls);
+                Ok(camera)
             }
             Err(_err) => Err(()),
         }
     }
 
+    /// Sets brightness, exposure and gain, controls the camera doesn't support are skipped
+    pub fn set_controls(&mut self, controls: &CameraControls) {
+        let _ = self.camera.set_camera_control(
+            nokhwa::utils::KnownCameraControl::Brightness,
+            nokhwa::utils::ControlValueSetter::Integer(controls.brightness),
+        );
+        let _ = self.camera.set_camera_control(
+            nokhwa::utils::KnownCameraControl::Exposure,
+            nokhwa::utils::ControlValueSetter::Integer(controls.exposure),
+        );
+        let _ = self.camera.set_camera_control(
+            nokhwa::utils::KnownCameraControl::Gain,
+            nokhwa::utils::ControlValueSetter::Integer(controls.gain),
+        );
+    }
+
     pub fn start_stream(&mut self) {
         let _ = self.camera.open_stream();
     }
 
-    pub fn _stop_stream(&mut self) {
+    pub fn stop_stream(&mut self) {
         let _ = self.camera.stop_stream();
     }
 
-    pub fn callback_thread(&mut self, tx: Sender<DynamicImage>) {
-        loop {
-            if let Ok(frame) = self.camera.frame() {
-                let image = frame.decode_image::<RgbAFormat>().unwrap();
-                let image = DynamicImage::from(image);
-                let _ = tx.send(image);
+    /// Sends the luma of every frame until shutdown or until the camera stops sending frames for `CAMERA_TIMEOUT`,
+    /// then closes the stream. Returns true if the camera was lost. Camera controls are updated whenever the live
+    /// config or the robot switch to a pipeline with different ones.
+    pub fn callback_thread(
+        &mut self,
+        tx: &FrameSender<Frame>,
+        live_rx: &mut watch::Receiver<LiveConfig>,
+        robot_rx: &mut watch::Receiver<RobotState>,
+        health: &HealthCounters,
+        shutdown: &CancellationToken,
+    ) -> bool {
+        let pool = BufferPool::new();
+        let mut controls = pipeline_controls(live_rx, robot_rx);
+        let mut last_frame = Instant::now();
+        let mut lost = false;
+        while !shutdown.is_cancelled() {
+            // The robot side closes without a subscriber, that just means it never changes
+            if live_rx.has_changed().unwrap_or(false) || robot_rx.has_changed().unwrap_or(false) {
+                let new_controls = pipeline_controls(live_rx, robot_rx);
+                if new_controls != controls {
+                    self.set_controls(&new_controls);
+                    info!("Updated Camera Controls! {:?}", new_controls);
+                    controls = new_controls;
+                }
+            }
+            match self.camera.frame() {
+                Ok(buffer) => {
+                    last_frame = Instant::now();
+                    match Frame::decode(buffer, &pool) {
+                        Ok(frame) => tx.send(frame),
+                        Err(err) => {
+                            warn!("Dropped a frame [{}]", err);
+                            health.decode_failed();
+                        }
+                    }
+                }
+                Err(_) if last_frame.elapsed() > CAMERA_TIMEOUT => {
+                    lost = true;
+                    break;
+                }
+                Err(_) => thread::sleep(FRAME_RETRY_DELAY),
             }
         }
+        self.stop_stream();
+        info!("Camera Stream Closed!");
+        lost
     }
 }
+
+/// Camera controls of the pipeline that should be running
+fn pipeline_controls(
+    live_rx: &mut watch::Receiver<LiveConfig>,
+    robot_rx: &mut watch::Receiver<RobotState>,
+) -> CameraControls {
+    let robot = robot_rx.borrow_and_update().clone();
+    live_rx.borrow_and_update().select(&robot).1.camera_controls.clone()
+}
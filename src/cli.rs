@@ -0,0 +1,150 @@
+//! # Command line interface
+//!
+//! Everything the binary can do is a subcommand, with `run` being the default when none is given so the
+//! deployed service keeps working as is. Any subcommand that needs the config takes the same `--config` and
+//! `--calibration` paths and the same overrides, so deploy scripts can change a value without editing the JSON.
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::config::{Config, OutputKind};
+use crate::{CAL_FILE_NAME, CONFIG_FILE_NAME};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "AprilTag vision for the robot")]
+pub struct Cli {
+    /// Path to the config JSON
+    #[arg(long, global = true, default_value = CONFIG_FILE_NAME)]
+    pub config: PathBuf,
+    /// Path to the camera calibration JSON
+    #[arg(long, global = true, default_value = CAL_FILE_NAME)]
+    pub calibration: PathBuf,
+    #[command(flatten)]
+    pub overrides: Overrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the vision pipeline (the default)
+    Run,
+    /// List the cameras that can be opened and their indexes
+    ListCameras,
+    /// Capture checkerboard images from the camera for `calibration.py`
+    Calibrate {
+        /// Directory to save the images in
+        #[arg(long, default_value = "images-webcam")]
+        output: PathBuf,
+        /// Number of images to capture
+        #[arg(long, default_value_t = 30)]
+        count: u32,
+        /// Time between captures in milliseconds, move the board around in between
+        #[arg(long, default_value_t = 1000)]
+        interval_ms: u64,
+    },
+    /// Send results recorded by the file log output to the configured outputs again
+    Replay {
+        /// File log to replay
+        log: PathBuf,
+        /// Playback speed, 2.0 plays twice as fast
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+    /// Load the config and calibration, report any problems and exit
+    CheckConfig,
+    /// Time every stage of the pipeline on images from disk or rendered frames, across detector settings
+    Bench(BenchArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// Image file or directory of images, synthetic frames are rendered when left out
+    pub images: Option<PathBuf>,
+    /// Times to run through all of the frames for every combination of settings
+    #[arg(long, default_value_t = 10)]
+    pub iterations: u32,
+    /// Number of synthetic frames to render
+    #[arg(long, default_value_t = 60)]
+    pub frames: u32,
+    /// Detector thread counts to try, comma separated, the config's when left out
+    #[arg(long, value_delimiter = ',')]
+    pub threads: Vec<u8>,
+    /// Decimations to try, comma separated, the config's when left out
+    #[arg(long, value_delimiter = ',')]
+    pub decimation: Vec<f32>,
+    /// Also write the results as JSON to this file, for comparing coprocessors
+    #[arg(long)]
+    pub json: Option<PathBuf>,
+}
+
+/// Values that replace the ones loaded from the config
+#[derive(Debug, Clone, Args)]
+pub struct Overrides {
+    /// Camera to open
+    #[arg(long, global = true)]
+    pub camera_index: Option<u32>,
+    /// Serial port for the `Serial` output
+    #[arg(long, global = true)]
+    pub serial_port: Option<String>,
+    /// Port for the `Server` output
+    #[arg(long, global = true)]
+    pub server_port: Option<u16>,
+    /// Address (`ip:port`) for the `Udp` output
+    #[arg(long, global = true)]
+    pub udp_target: Option<String>,
+    /// NetworkTables server address
+    #[arg(long, global = true)]
+    pub nt_ip: Option<std::net::Ipv4Addr>,
+    /// Outputs to run, replaces the list in the config (repeat for more than one)
+    #[arg(long = "output", global = true, value_parser = parse_output)]
+    pub outputs: Vec<OutputKind>,
+    /// WPILib field layout JSON
+    #[arg(long, global = true)]
+    pub field_layout: Option<String>,
+    /// Pipeline to run when the robot hasn't asked for one
+    #[arg(long, global = true)]
+    pub pipeline: Option<String>,
+    /// Log level for everything without its own level in `logging.modules` (`error`, `warn`, `info`, `debug`, `trace`)
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+}
+
+impl Overrides {
+    /// Writes every override that was given into the config
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(index) = self.camera_index {
+            config.camera_index = index;
+        }
+        if let Some(port) = &self.serial_port {
+            config.interface.serial_port = port.clone();
+        }
+        if let Some(port) = self.server_port {
+            config.interface.server_port = port;
+        }
+        if let Some(target) = &self.udp_target {
+            config.interface.udp_target = target.clone();
+        }
+        if let Some(ip) = self.nt_ip {
+            config.interface.nt_ip = ip.octets();
+        }
+        if !self.outputs.is_empty() {
+            config.interface.outputs = self.outputs.clone();
+        }
+        if let Some(path) = &self.field_layout {
+            config.field_layout = Some(path.clone());
+        }
+        if let Some(pipeline) = &self.pipeline {
+            config.active_pipeline = Some(pipeline.clone());
+        }
+        if let Some(level) = &self.log_level {
+            config.logging.level = level.clone();
+        }
+    }
+}
+
+/// Parses an output by the same name it has in the config
+fn parse_output(name: &str) -> Result<OutputKind, String> {
+    serde_json::from_value(serde_json::Value::String(name.to_string()))
+        .map_err(|_| format!("unknown output `{name}`"))
+}
@@ -0,0 +1,492 @@
+//! # Subcommands
+//!
+//! Everything the binary does besides running the pipeline, see `cli::Command` for what each one is for.
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use image::{DynamicImage, RgbImage};
+use nokhwa::pixel_format::RgbFormat;
+use nokhwa::utils::{ApiBackend, FrameFormat, Resolution};
+use nokhwa::Buffer;
+use serde::Serialize;
+use tokio::runtime::Handle;
+use tokio_util::sync::CancellationToken;
+
+use crate::camera::{Camera, CAMERA_TIMEOUT, FRAME_RETRY_DELAY};
+use crate::cli::{BenchArgs, Overrides};
+use crate::config::{CameraCalibration, Config, ConfigProblem, Severity};
+use crate::field::FieldLayout;
+use crate::health::HealthCounters;
+use crate::frame::{BufferPool, Frame};
+use crate::mailbox::mailbox;
+use crate::process::{Process, VisionFrame};
+use crate::reload::{live_config_channel, LiveConfig};
+use crate::robot::robot_state_channel;
+use crate::shutdown;
+use crate::sink::{HealthLine, Sinks};
+use crate::synthetic;
+
+/// File extensions `bench` loads as images
+pub const IMAGE_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "bmp"];
+
+// --- Implementation of list-cameras ---
+/// Prints every camera the native backend can find
+pub fn list_cameras() -> ExitCode {
+    match nokhwa::query(ApiBackend::Auto) {
+        Ok(cameras) if cameras.is_empty() => {
+            println!("No cameras found!");
+            ExitCode::SUCCESS
+        }
+        Ok(cameras) => {
+            for camera in cameras {
+                println!(
+                    "[{}] {} ({})",
+                    camera.index(),
+                    camera.human_name(),
+                    camera.description()
+                );
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            println!("Couldn't query cameras [{}]", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+// --- Implementation of list-cameras ---
+
+// --- Implementation of calibrate ---
+/// Saves `count` frames from the camera into `output` for `calibration.py` to work out the intrinsics from
+pub fn calibrate(config: &Config, output: &Path, count: u32, interval: Duration) -> ExitCode {
+    if let Err(err) = std::fs::create_dir_all(output) {
+        println!("Couldn't create {:?} [{}]", output, err);
+        return ExitCode::FAILURE;
+    }
+
+    let Ok(mut camera) = Camera::new(config.camera_index, &config.camera_controls) else {
+        println!("Couldn't open camera {}!", config.camera_index);
+        return ExitCode::FAILURE;
+    };
+    camera.start_stream();
+
+    println!("Capturing {} images, move the checkerboard around between them...", count);
+    let mut saved = 0;
+    while saved < count {
+        std::thread::sleep(interval);
+        let Some(image) = capture(&mut camera) else {
+            println!("Camera {} stopped sending frames! [{}/{}]", config.camera_index, saved, count);
+            camera.stop_stream();
+            return ExitCode::FAILURE;
+        };
+
+        let path = output.join(format!("calibration-{:03}.jpg", saved));
+        match DynamicImage::from(image).save(&path) {
+            Ok(()) => {
+                saved += 1;
+                println!("Saved {:?} [{}/{}]", path, saved, count);
+            }
+            Err(err) => println!("Couldn't save {:?} [{}]", path, err),
+        }
+    }
+    camera.stop_stream();
+
+    println!("Done! Run `python calibration.py` on {:?} and copy cam-cal.json into configs/", output);
+    ExitCode::SUCCESS
+}
+
+/// Waits up to `CAMERA_TIMEOUT` for a frame that decodes
+fn capture(camera: &mut Camera) -> Option<RgbImage> {
+    let start = Instant::now();
+    while start.elapsed() < CAMERA_TIMEOUT {
+        let image = camera.camera.frame().and_then(|frame| frame.decode_image::<RgbFormat>());
+        if let Ok(image) = image {
+            return Some(image);
+        }
+        std::thread::sleep(FRAME_RETRY_DELAY);
+    }
+    None
+}
+// --- Implementation of calibrate ---
+
+// --- Implementation of replay ---
+/// Sends every result in a file log to the configured outputs again, paced like they were recorded.
+///
+/// Results are stamped with the time they get replayed so the latency the robot works out stays meaningful.
+pub async fn replay(config: &Config, field: Option<FieldLayout>, log: &Path, speed: f64) -> ExitCode {
+    let text = match tokio::fs::read_to_string(log).await {
+        Ok(text) => text,
+        Err(err) => {
+            println!("Couldn't read {:?} [{}]", log, err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut frames = vec![];
+    for (line_number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<VisionFrame>(line) {
+            Ok(frame) => frames.push(frame),
+            // Health statuses from the original run, the replay sends none since nothing is being measured
+            Err(_) if serde_json::from_str::<HealthLine<serde::de::IgnoredAny>>(line).is_ok() => {}
+            Err(err) => println!("Skipping line {} [{}]", line_number + 1, err),
+        }
+    }
+    println!("Replaying {} results from {:?}", frames.len(), log);
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn(shutdown::cancel_on_signal(shutdown.clone()));
+    let sinks = Sinks::spawn(
+        &Handle::current(),
+        &config.interface,
+        field,
+        HealthCounters::new(),
+        shutdown.clone(),
+    );
+
+    let speed = if speed > 0.0 { speed } else { 1.0 };
+    let start = Instant::now();
+    let first = frames.first().map(|frame| frame.timestamp).unwrap_or(0.0);
+    for mut frame in frames {
+        let offset = Duration::from_secs_f64(((frame.timestamp - first) / speed).max(0.0));
+        tokio::select! {
+            _ = tokio::time::sleep_until((start + offset).into()) => {}
+            _ = shutdown.cancelled() => break,
+        }
+        frame.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_secs_f64();
+        sinks.publish(&frame);
+    }
+
+    sinks.close().await;
+    println!("Replay Done!");
+    ExitCode::SUCCESS
+}
+// --- Implementation of replay ---
+
+// --- Implementation of check-config ---
+/// Loads everything `run` would and reports every problem with it, fails if any of them are errors
+pub fn check_config(config_path: &Path, calibration_path: &Path, overrides: &Overrides) -> ExitCode {
+    let mut ok = true;
+
+    match CameraCalibration::load_from_file(calibration_path) {
+        Ok(calibration) => ok &= report(calibration_path, calibration.validate()),
+        Err(err) => {
+            println!("{}: {}", calibration_path.display(), err);
+            ok = false;
+        }
+    }
+
+    match Config::load_from_file(config_path) {
+        Ok(mut config) => {
+            overrides.apply(&mut config);
+            ok &= report(config_path, config.validate());
+        }
+        Err(err) => {
+            println!("{}", err);
+            ok = false;
+        }
+    }
+
+    if ok {
+        println!("Config OK!");
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Prints every problem with the file, returns false if any of them are errors
+fn report(file: &Path, problems: Vec<ConfigProblem>) -> bool {
+    if problems.is_empty() {
+        println!("{}: OK", file.display());
+    }
+    let mut ok = true;
+    for problem in problems {
+        ok &= problem.severity != Severity::Error;
+        println!("{}: {}", file.display(), problem);
+    }
+    ok
+}
+// --- Implementation of check-config ---
+
+// --- Implementation of bench ---
+/// Latency of a stage across every frame, in milliseconds
+#[derive(Debug, Serialize)]
+pub struct Percentiles {
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    fn of(mut samples: Vec<f64>) -> Self {
+        if samples.is_empty() {
+            return Percentiles {
+                mean: 0.0,
+                p50: 0.0,
+                p90: 0.0,
+                p99: 0.0,
+                max: 0.0,
+            };
+        }
+        samples.sort_by(f64::total_cmp);
+        let at = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+        Percentiles {
+            mean: samples.iter().sum::<f64>() / samples.len() as f64,
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+/// Results for one combination of detector settings
+#[derive(Debug, Serialize)]
+pub struct BenchRun {
+    pub threads: u8,
+    pub decimation: f32,
+    pub frames: usize,
+    /// Frames per second through decoding and the whole pipeline, one frame at a time
+    pub fps: f64,
+    pub tags_per_frame: f64,
+    /// Decoding the camera's JPEG to luma, which the capture thread does
+    pub decode: Percentiles,
+    pub preprocess: Percentiles,
+    pub detection: Percentiles,
+    pub pose: Percentiles,
+    pub total: Percentiles,
+}
+
+/// Everything `bench --json` writes
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub cpus: usize,
+    /// Where the frames came from, a path or `synthetic`
+    pub source: String,
+    pub iterations: u32,
+    pub runs: Vec<BenchRun>,
+}
+
+/// Runs every frame through decoding and the pipeline `iterations` times for every combination of thread count and
+/// decimation, and prints how long each stage took
+pub fn bench(config: &Config, calibration: &CameraCalibration, field: Option<FieldLayout>, args: &BenchArgs) -> ExitCode {
+    let (source, buffers) = match &args.images {
+        Some(images) => match load_buffers(images) {
+            Ok(buffers) if !buffers.is_empty() => (images.display().to_string(), buffers),
+            Ok(_) => {
+                println!("No images found in {:?}", images);
+                return ExitCode::FAILURE;
+            }
+            Err(err) => {
+                println!("Couldn't read {:?} [{}]", images, err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => match synthetic::render_frames(args.frames, &config.detection_config.families, calibration) {
+            Some(buffers) => ("synthetic".to_string(), buffers),
+            None => {
+                println!("Couldn't render {:?} tags!", config.detection_config.families);
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+    println!("Loaded {} frames from {}!", buffers.len(), source);
+
+    let live = LiveConfig::new(config, calibration);
+    let active = &live.pipelines[&live.active_pipeline].detection_config.detector;
+    let threads = if args.threads.is_empty() { vec![active.threads] } else { args.threads.clone() };
+    let decimations = if args.decimation.is_empty() { vec![active.decimation] } else { args.decimation.clone() };
+
+    let mut runs = vec![];
+    for &thread_count in threads.iter() {
+        for &decimation in decimations.iter() {
+            let mut live = live.clone();
+            if let Some(pipeline) = live.pipelines.get_mut(&live.active_pipeline) {
+                pipeline.detection_config.detector.threads = thread_count;
+                pipeline.detection_config.detector.decimation = decimation;
+            }
+            let run = bench_run(live, field.clone(), &buffers, args.iterations);
+            print_run(&run);
+            runs.push(run);
+        }
+    }
+
+    if let Some(path) = &args.json {
+        let report = BenchReport {
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            cpus: std::thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+            source,
+            iterations: args.iterations,
+            runs,
+        };
+        let written = serde_json::to_string_pretty(&report)
+            .map_err(|err| err.to_string())
+            .and_then(|json| std::fs::write(path, json).map_err(|err| err.to_string()));
+        match written {
+            Ok(()) => println!("Wrote {:?}", path),
+            Err(err) => {
+                println!("Couldn't write {:?} [{}]", path, err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Times every stage for every frame with one set of settings
+fn bench_run(live: LiveConfig, field: Option<FieldLayout>, buffers: &[Buffer], iterations: u32) -> BenchRun {
+    let detector = &live.pipelines[&live.active_pipeline].detection_config.detector;
+    let (threads, decimation) = (detector.threads, detector.decimation);
+
+    // Nothing feeds or reads these, the bench hands frames to the process directly
+    let (_image_tx, image_rx) = mailbox::<Frame>();
+    let (data_tx, _data_rx) = tokio::sync::mpsc::channel(1);
+    let (_robot_tx, robot_rx) = robot_state_channel();
+    let (_live_tx, live_rx) = live_config_channel(live);
+    let mut process = Process::new(image_rx, data_tx, field, robot_rx, live_rx);
+    let pool = BufferPool::new();
+
+    let mut decode = vec![];
+    let mut preprocess = vec![];
+    let mut detection = vec![];
+    let mut pose = vec![];
+    let mut total = vec![];
+    let mut detections = 0;
+    let start = Instant::now();
+    for _ in 0..iterations {
+        for buffer in buffers.iter() {
+            let decode_start = Instant::now();
+            let Ok(image) = Frame::decode(buffer.clone(), &pool) else {
+                continue;
+            };
+            let decode_ms = decode_start.elapsed().as_secs_f64() * 1000.0;
+
+            let frame = process.process_frame(&image);
+            decode.push(decode_ms);
+            preprocess.push(frame.stages.preprocess);
+            detection.push(frame.stages.detection);
+            pose.push(frame.stages.pose);
+            total.push(decode_ms + frame.pipeline_latency);
+            detections += frame.targets.len();
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let frames = total.len();
+    BenchRun {
+        threads,
+        decimation,
+        frames,
+        fps: frames as f64 / elapsed,
+        tags_per_frame: detections as f64 / frames.max(1) as f64,
+        decode: Percentiles::of(decode),
+        preprocess: Percentiles::of(preprocess),
+        detection: Percentiles::of(detection),
+        pose: Percentiles::of(pose),
+        total: Percentiles::of(total),
+    }
+}
+
+fn print_run(run: &BenchRun) {
+    println!(
+        "Threads: {}, Decimation: {}, Frames: {}, Throughput: {:.1} fps, Tags per Frame: {:.2}",
+        run.threads, run.decimation, run.frames, run.fps, run.tags_per_frame
+    );
+    println!("    {:<12}{:>8}{:>8}{:>8}{:>8}{:>8}", "Stage (ms)", "mean", "p50", "p90", "p99", "max");
+    let stages = [
+        ("decode", &run.decode),
+        ("preprocess", &run.preprocess),
+        ("detection", &run.detection),
+        ("pose", &run.pose),
+        ("total", &run.total),
+    ];
+    for (name, stage) in stages {
+        println!(
+            "    {:<12}{:>8.2}{:>8.2}{:>8.2}{:>8.2}{:>8.2}",
+            name, stage.mean, stage.p50, stage.p90, stage.p99, stage.max
+        );
+    }
+}
+
+/// Every image as the camera would send it. JPEGs are used as they are, anything else gets encoded to one.
+fn load_buffers(path: &Path) -> std::io::Result<Vec<Buffer>> {
+    let mut buffers = vec![];
+    for path in image_paths(path)? {
+        let is_jpeg = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| matches!(ext.to_lowercase().as_str(), "jpg" | "jpeg"));
+        let buffer = if is_jpeg {
+            image::image_dimensions(&path)
+                .ok()
+                .zip(std::fs::read(&path).ok())
+                .map(|((width, height), jpeg)| Buffer::new(Resolution::new(width, height), &jpeg, FrameFormat::MJPEG))
+        } else {
+            image::open(&path).ok().and_then(|image| synthetic::mjpeg_buffer(&image.to_rgb8()))
+        };
+        match buffer {
+            Some(buffer) => buffers.push(buffer),
+            None => println!("Skipping {:?}", path),
+        }
+    }
+    Ok(buffers)
+}
+
+/// The file itself, or every image in the directory sorted by name
+fn image_paths(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(path)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+// --- Implementation of bench ---
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_of_samples() {
+        // Out of order on purpose
+        let samples: Vec<f64> = (1..=100).rev().map(f64::from).collect();
+        let percentiles = Percentiles::of(samples);
+        assert_eq!(percentiles.mean, 50.5);
+        assert_eq!(percentiles.p50, 51.0);
+        assert_eq!(percentiles.p90, 90.0);
+        assert_eq!(percentiles.p99, 99.0);
+        assert_eq!(percentiles.max, 100.0);
+    }
+
+    #[test]
+    fn percentiles_of_one_sample() {
+        let percentiles = Percentiles::of(vec![4.0]);
+        assert_eq!([percentiles.mean, percentiles.p50, percentiles.p99, percentiles.max], [4.0; 4]);
+    }
+
+    #[test]
+    fn percentiles_of_nothing() {
+        let percentiles = Percentiles::of(vec![]);
+        assert_eq!([percentiles.mean, percentiles.p50, percentiles.p99, percentiles.max], [0.0; 4]);
+    }
+}
@@ -1,10 +1,15 @@
 use apriltag::*;
 use imageproc::geometric_transformations::Projection;
-use nalgebra::Matrix3x1;
+use nalgebra::{Isometry3, Matrix3x1, Translation3, UnitQuaternion};
 use serde::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::Path;
 use thiserror::Error;
 
+use crate::field::FieldLayout;
+
 #[derive(Error, Debug)]
 pub enum CalibrationError {
     #[error("Failed to convert into projection matrix: {0}")]
@@ -15,6 +20,84 @@ pub enum CalibrationError {
     LoadError(String),
 }
 
+/// Error loading the config, everything in here is meant to be read by a person
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    /// The file couldn't be read at all
+    #[error("Failed to read {file}: {source}")]
+    Read {
+        file: String,
+        source: std::io::Error,
+    },
+    /// The file isn't valid JSON or doesn't match the config layout, `path` is where in the JSON it went wrong
+    #[error("{file}: {path}: {message}")]
+    Parse {
+        file: String,
+        path: String,
+        message: String,
+    },
+    /// The config loaded but has values that can't work
+    #[error("{} problem(s) in the config:\n{}", .0.len(), .0.iter().map(|p| p.to_string()).collect::<Vec<_>>().join("\n"))]
+    Invalid(Vec<ConfigProblem>),
+    /// An edited config couldn't be saved
+    #[error("Failed to write {file}: {source}")]
+    Write {
+        file: String,
+        source: std::io::Error,
+    },
+}
+
+/// How bad a config problem is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Something that may be fine, like a serial port that isn't plugged in yet
+    Warning,
+    /// Something that can't work, the config gets rejected
+    Error,
+}
+
+/// A single problem found in the config, `path` is the JSON path of the value (e.g. `interface.nt_port`)
+#[derive(Debug, Clone)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+}
+
+impl ConfigProblem {
+    fn error<P: Into<String>, M: Into<String>>(path: P, message: M) -> Self {
+        ConfigProblem {
+            severity: Severity::Error,
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    fn warning<P: Into<String>, M: Into<String>>(path: P, message: M) -> Self {
+        ConfigProblem {
+            severity: Severity::Warning,
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}: {}", severity, self.path, self.message)
+    }
+}
+
+/// Horizontal field of view of `CameraCalibration::nominal`, about what the usual FRC webcams have
+pub const NOMINAL_FOV_DEGREES: f64 = 70.0;
+
+/// Tag size of `CameraCalibration::nominal`, the 6.5 inch tags on an FRC field
+pub const NOMINAL_TAG_SIZE: f64 = 0.1651;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CameraCalibration {
     /// The camera calibration matrix/Intrinsic camera matrix
@@ -73,6 +156,24 @@ impl CameraCalibration {
         }
     }
 
+    /// A lens without distortion, centered on a `width` x `height` frame with a `NOMINAL_FOV_DEGREES` field of view.
+    ///
+    /// Only good for frames that were drawn through it, like the ones `bench` renders.
+    pub fn nominal(width: u32, height: u32) -> Self {
+        let focal = width as f64 / 2.0 / (NOMINAL_FOV_DEGREES.to_radians() / 2.0).tan();
+        let (cx, cy) = (width as f64 / 2.0, height as f64 / 2.0);
+        CameraCalibration {
+            mtx: vec![vec![focal, 0.0, cx], vec![0.0, focal, cy], vec![0.0, 0.0, 1.0]],
+            dist: vec![vec![0.0; 5]],
+            fx: focal,
+            fy: focal,
+            cx,
+            cy,
+            tagsize: NOMINAL_TAG_SIZE,
+            ..Default::default()
+        }
+    }
+
     /// Principle focal point of the camera in pixels
     pub fn fx(&self) -> f64 {
         self.fx
@@ -93,6 +194,14 @@ impl CameraCalibration {
         self.cy
     }
 
+    /// Every problem with the values in the calibration
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+        check_positive("", &[("fx", self.fx), ("fy", self.fy), ("tagsize", self.tagsize)], &mut problems);
+        check_non_negative("", &[("cx", self.cx), ("cy", self.cy)], &mut problems);
+        problems
+    }
+
     /// Creates a tag params struct from given calibration
     pub fn tag_params(&self) -> TagParams {
         TagParams {
@@ -189,34 +298,1066 @@ impl From<&CameraCalibration> for TagParams {
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct DetectionConfig {
     pub families: AprilTagFamily,
+    /// Tags to keep when the robot is on the red alliance, empty keeps every tag
+    #[serde(default)]
+    pub red_tags: Vec<u32>,
+    /// Tags to keep when the robot is on the blue alliance, empty keeps every tag
+    #[serde(default)]
+    pub blue_tags: Vec<u32>,
+    /// How standard deviations get estimated for every pose
+    #[serde(default)]
+    pub uncertainty: UncertaintyConfig,
+    /// AprilTag detector settings
+    #[serde(default)]
+    pub detector: DetectorParams,
+    /// Detecting on a crop around the tags found in the last frame
+    #[serde(default)]
+    pub roi: RoiConfig,
+}
+
+impl DetectionConfig {
+    fn validate(&self, section: &str, problems: &mut Vec<ConfigProblem>) {
+        self.detector.validate(&value_path(section, "detector"), problems);
+        self.uncertainty.validate(&value_path(section, "uncertainty"), problems);
+        if self.roi.enabled {
+            self.roi.validate(&value_path(section, "roi"), problems);
+        }
+    }
+}
+
+/// Region of interest tracking, see `roi` for how the crop is picked
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RoiConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Space added around each predicted tag on every side, in multiples of the tag's size in pixels
+    #[serde(default = "default_roi_margin")]
+    pub margin: f64,
+    /// Smallest crop width and height in pixels, so small or far tags still get some room to move
+    #[serde(default = "default_roi_min_size")]
+    pub min_size: u32,
+    /// Every this many frames the whole frame is searched again to pick up tags that came into view
+    #[serde(default = "default_roi_rescan_interval")]
+    pub rescan_interval: u32,
+    /// Decimation used on crops, they are small enough that most of the detail can be kept
+    #[serde(default = "default_roi_decimation")]
+    pub decimation: f32,
+    /// Crops covering more than this fraction of the frame search the whole frame instead
+    #[serde(default = "default_roi_max_area")]
+    pub max_area: f64,
+}
+
+impl Default for RoiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            margin: default_roi_margin(),
+            min_size: default_roi_min_size(),
+            rescan_interval: default_roi_rescan_interval(),
+            decimation: default_roi_decimation(),
+            max_area: default_roi_max_area(),
+        }
+    }
+}
+
+impl RoiConfig {
+    fn validate(&self, section: &str, problems: &mut Vec<ConfigProblem>) {
+        check_non_negative(section, &[("margin", self.margin)], problems);
+        check_positive(section, &[("rescan_interval", self.rescan_interval as f64)], problems);
+        if !(self.decimation.is_finite() && self.decimation >= 1.0) {
+            problems.push(ConfigProblem::error(value_path(section, "decimation"), "must be at least 1"));
+        }
+        if !(self.max_area > 0.0 && self.max_area <= 1.0) {
+            problems.push(ConfigProblem::error(value_path(section, "max_area"), "must be above 0 and at most 1"));
+        }
+    }
+}
+
+fn default_roi_margin() -> f64 {
+    1.0
+}
+
+fn default_roi_min_size() -> u32 {
+    128
+}
+
+fn default_roi_rescan_interval() -> u32 {
+    10
+}
+
+fn default_roi_decimation() -> f32 {
+    1.0
+}
+
+fn default_roi_max_area() -> f64 {
+    0.5
+}
+
+/// Settings passed straight through to the AprilTag detector, the defaults are the library's own
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DetectorParams {
+    /// Threads the detector uses
+    #[serde(default = "default_threads")]
+    pub threads: u8,
+    /// Detect quads on an image this many times smaller, faster but worse at small or far tags
+    #[serde(default = "default_decimation")]
+    pub decimation: f32,
+    /// Gaussian blur applied before detection in pixels, 0 turns it off
+    #[serde(default)]
+    pub sigma: f32,
+    /// Snap quad edges to strong gradients, helps with decimation
+    #[serde(default = "default_refine_edges")]
+    pub refine_edges: bool,
+    /// Sharpening applied to the decoded tag image
+    #[serde(default = "default_sharpening")]
+    pub sharpening: f64,
+    /// Tags with a decision margin at or below this are thrown out as false positives
+    #[serde(default = "default_min_decision_margin")]
+    pub min_decision_margin: f32,
+}
+
+impl Default for DetectorParams {
+    fn default() -> Self {
+        Self {
+            threads: default_threads(),
+            decimation: default_decimation(),
+            sigma: 0.0,
+            refine_edges: default_refine_edges(),
+            sharpening: default_sharpening(),
+            min_decision_margin: default_min_decision_margin(),
+        }
+    }
+}
+
+impl DetectorParams {
+    fn validate(&self, section: &str, problems: &mut Vec<ConfigProblem>) {
+        if self.threads == 0 {
+            problems.push(ConfigProblem::error(value_path(section, "threads"), "must be at least 1"));
+        }
+        if !(self.decimation.is_finite() && self.decimation >= 1.0) {
+            problems.push(ConfigProblem::error(value_path(section, "decimation"), "must be at least 1"));
+        }
+        let non_negative = [
+            ("sigma", self.sigma as f64),
+            ("sharpening", self.sharpening),
+            ("min_decision_margin", self.min_decision_margin as f64),
+        ];
+        check_non_negative(section, &non_negative, problems);
+    }
+}
+
+fn default_threads() -> u8 {
+    5
+}
+
+fn default_decimation() -> f32 {
+    2.0
+}
+
+fn default_refine_edges() -> bool {
+    true
+}
+
+fn default_sharpening() -> f64 {
+    0.25
+}
+
+fn default_min_decision_margin() -> f32 {
+    crate::process::MIN_DECISION_MARGIN
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(default)]
     pub camera_index: u32,
+    #[serde(default)]
     pub detection_config: DetectionConfig,
-    pub interface: InterfaceConfig
+    #[serde(default)]
+    pub interface: InterfaceConfig,
+    /// Path to a WPILib AprilTag field layout JSON, needed for field relative robot poses
+    #[serde(default)]
+    pub field_layout: Option<String>,
+    /// Where the camera is mounted on the robot
+    #[serde(default)]
+    pub robot_to_camera: CameraMount,
+    #[serde(default)]
+    pub tracking: TrackingConfig,
+    /// Controls set on the camera when it opens
+    #[serde(default)]
+    pub camera_controls: CameraControls,
+    /// Pipelines that can be switched to while running, on top of the `default` one made from `detection_config`
+    /// and `camera_controls`
+    #[serde(default)]
+    pub pipelines: BTreeMap<String, PipelineConfig>,
+    /// Pipeline to run when the robot hasn't asked for one, `None` runs the `default` pipeline
+    #[serde(default)]
+    pub active_pipeline: Option<String>,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub stream: StreamConfig,
+    #[serde(default)]
+    pub dashboard: DashboardConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
 }
 
-impl Config {
-    pub fn load_from_file<T: AsRef<Path>>(path: T) -> Result<Self, CalibrationError> {
-        let json_text = std::fs::read_to_string(path).unwrap();
-        match serde_json::from_str(&json_text) {
-            Ok(v) => Ok(v),
-            Err(_e) => Err(CalibrationError::LoadError(_e.to_string())),
+/// Log verbosity and where the log goes on top of the console
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct LoggingConfig {
+    /// Level for everything without its own level in `modules`: `error`, `warn`, `info`, `debug` or `trace`
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// Levels for single modules by their path, like `vision::sink` or `nokhwa`
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+    /// Directory to also write the log to, rotated every `rotation`
+    #[serde(default)]
+    pub directory: Option<String>,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// Oldest log files are deleted once there are more than this, 0 keeps them all
+    #[serde(default = "default_max_log_files")]
+    pub max_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            modules: BTreeMap::new(),
+            directory: None,
+            rotation: LogRotation::default(),
+            max_files: default_max_log_files(),
         }
     }
 }
 
+impl LoggingConfig {
+    fn validate(&self, problems: &mut Vec<ConfigProblem>) {
+        if !is_log_level(&self.level) {
+            problems.push(ConfigProblem::error("logging.level", format!("`{}` isn't a log level", self.level)));
+        }
+        for (module, level) in self.modules.iter() {
+            let path = value_path("logging.modules", module);
+            if module.is_empty() || module.contains(|c: char| c.is_whitespace() || c == ',' || c == '=') {
+                problems.push(ConfigProblem::error(path.clone(), "isn't a module path"));
+            }
+            if !is_log_level(level) {
+                problems.push(ConfigProblem::error(path, format!("`{level}` isn't a log level")));
+            }
+        }
+        if let Some(directory) = &self.directory {
+            if directory.is_empty() {
+                problems.push(ConfigProblem::error("logging.directory", "leave it out to not log to a file"));
+            } else if Path::new(directory).exists() && !Path::new(directory).is_dir() {
+                problems.push(ConfigProblem::error(
+                    "logging.directory",
+                    format!("{directory} is a file, not a directory"),
+                ));
+            }
+        }
+    }
+}
+
+/// How often the log file starts over in a new file
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    /// Always the same file, which is only ever appended to
+    Never,
+}
+
+/// Whether the level is one `tracing` knows, in any case
+pub fn is_log_level(level: &str) -> bool {
+    ["error", "warn", "info", "debug", "trace", "off"].contains(&level.to_lowercase().as_str())
+}
+
+/// HTTP endpoint Prometheus can scrape the pipeline's counters from
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Port the metrics are served on at `/metrics`
+    #[serde(default = "default_metrics_port")]
+    pub port: u16,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: default_metrics_port(),
+        }
+    }
+}
+
+/// MJPEG stream of the camera with the detections drawn on, for the driver station dashboard
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct StreamConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Port the stream is served on at `/stream.mjpg`
+    #[serde(default = "default_stream_port")]
+    pub port: u16,
+    /// Most frames per second sent, the rest of the camera's frames are skipped to save CPU and bandwidth
+    #[serde(default = "default_stream_fps")]
+    pub fps: f64,
+    /// JPEG quality from 1 to 100
+    #[serde(default = "default_stream_quality")]
+    pub quality: u8,
+    /// Width frames are scaled down to before they are sent, 0 sends them at full size
+    #[serde(default = "default_stream_width")]
+    pub width: u32,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: default_stream_port(),
+            fps: default_stream_fps(),
+            quality: default_stream_quality(),
+            width: default_stream_width(),
+        }
+    }
+}
+
+impl StreamConfig {
+    fn validate(&self, problems: &mut Vec<ConfigProblem>) {
+        if !(self.fps.is_finite() && self.fps > 0.0) {
+            problems.push(ConfigProblem::error("stream.fps", "must be greater than 0"));
+        }
+        if !(1..=100).contains(&self.quality) {
+            problems.push(ConfigProblem::error("stream.quality", "must be between 1 and 100"));
+        }
+    }
+}
+
+/// Web page for watching and tuning the pipeline from a laptop on the robot network
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DashboardConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_dashboard_port")]
+    pub port: u16,
+}
+
+impl Default for DashboardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: default_dashboard_port(),
+        }
+    }
+}
+
+/// Name of the pipeline made from the top level `detection_config` and `camera_controls`
+pub const DEFAULT_PIPELINE: &str = "default";
+
+/// A named set of detection settings, camera controls and outputs that can be switched to while running
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct PipelineConfig {
+    /// Pipeline index the robot can select this pipeline with, on top of its name
+    #[serde(default)]
+    pub index: Option<i64>,
+    #[serde(default)]
+    pub detection_config: DetectionConfig,
+    #[serde(default)]
+    pub camera_controls: CameraControls,
+    /// Outputs results go to while this pipeline runs, `None` sends them to every output in `interface.outputs`
+    #[serde(default)]
+    pub outputs: Option<Vec<OutputKind>>,
+}
+
+/// Camera controls, values are in whatever units the camera driver uses
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CameraControls {
+    #[serde(default = "default_brightness")]
+    pub brightness: i64,
+    #[serde(default)]
+    pub exposure: i64,
+    #[serde(default = "default_gain")]
+    pub gain: i64,
+}
+
+impl Default for CameraControls {
+    fn default() -> Self {
+        Self {
+            brightness: default_brightness(),
+            exposure: 0,
+            gain: default_gain(),
+        }
+    }
+}
+
+fn default_brightness() -> i64 {
+    100
+}
+
+fn default_gain() -> i64 {
+    100
+}
+
+/// Transform from the robot center to the camera lens, in WPILib conventions (x forward, y left, z up)
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct CameraMount {
+    /// Camera position from the robot center in meters
+    pub translation: [f64; 3],
+    /// Camera roll, pitch and yaw in degrees
+    pub rotation: [f64; 3],
+}
+
+impl CameraMount {
+    /// The mount as an isometry from the robot frame to the camera frame
+    pub fn isometry(&self) -> Isometry3<f64> {
+        let [x, y, z] = self.translation;
+        let [roll, pitch, yaw] = self.rotation.map(f64::to_radians);
+        Isometry3::from_parts(
+            Translation3::new(x, y, z),
+            UnitQuaternion::from_euler_angles(roll, pitch, yaw),
+        )
+    }
+}
+
+impl Config {
+    /// Loads the config JSON file from the given path.
+    ///
+    /// This only checks that the JSON matches the config layout, use `check` for whether the values make sense.
+    pub fn load_from_file<T: AsRef<Path>>(path: T) -> Result<Self, ConfigError> {
+        let file = path.as_ref().display().to_string();
+        let json_text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(source) => return Err(ConfigError::Read { file, source }),
+        };
+
+        let deserializer = &mut serde_json::Deserializer::from_str(&json_text);
+        match serde_path_to_error::deserialize(deserializer) {
+            Ok(config) => Ok(config),
+            Err(err) => {
+                let path = match err.path().to_string() {
+                    path if path == "." => "(root)".to_string(),
+                    path => path,
+                };
+                Err(ConfigError::Parse {
+                    file,
+                    path,
+                    message: err.into_inner().to_string(),
+                })
+            }
+        }
+    }
+
+    /// Validates the config, returning the warnings if there are no errors and every problem if there are
+    pub fn check(&self) -> Result<Vec<ConfigProblem>, ConfigError> {
+        let problems = self.validate();
+        if problems.iter().any(|problem| problem.severity == Severity::Error) {
+            Err(ConfigError::Invalid(problems))
+        } else {
+            Ok(problems)
+        }
+    }
+
+    /// Every pipeline by name, including the `default` one
+    pub fn pipelines(&self) -> BTreeMap<String, PipelineConfig> {
+        let mut pipelines = self.pipelines.clone();
+        pipelines.insert(
+            DEFAULT_PIPELINE.to_string(),
+            PipelineConfig {
+                index: None,
+                detection_config: self.detection_config.clone(),
+                camera_controls: self.camera_controls.clone(),
+                outputs: None,
+            },
+        );
+        pipelines
+    }
+
+    /// Name of the pipeline to run when the robot hasn't asked for one
+    pub fn active_pipeline(&self) -> &str {
+        self.active_pipeline.as_deref().unwrap_or(DEFAULT_PIPELINE)
+    }
+
+    /// Every problem with the values in the config
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+        self.detection_config.validate("detection_config", &mut problems);
+        self.validate_pipelines(&mut problems);
+        if self.tracking.enabled {
+            self.tracking.validate(&mut problems);
+        }
+        self.interface.validate(&mut problems);
+        self.logging.validate(&mut problems);
+
+        if self.stream.enabled {
+            self.stream.validate(&mut problems);
+        }
+        self.validate_ports(&mut problems);
+
+        if let Some(path) = &self.field_layout {
+            if let Err(err) = FieldLayout::load_from_file(path) {
+                problems.push(ConfigProblem::error("field_layout", format!("can't load {path}: {err}")));
+            }
+        }
+
+        let mount = &self.robot_to_camera;
+        if !mount.translation.iter().chain(mount.rotation.iter()).all(|v| v.is_finite()) {
+            problems.push(ConfigProblem::error("robot_to_camera", "every value must be a finite number"));
+        }
+        problems
+    }
+
+    /// Every server that is turned on needs a port of its own
+    fn validate_ports(&self, problems: &mut Vec<ConfigProblem>) {
+        let mut ports = vec![];
+        if self.interface.outputs.contains(&OutputKind::Server) {
+            ports.push(("interface.server_port", self.interface.server_port));
+        }
+        if self.metrics.enabled {
+            ports.push(("metrics.port", self.metrics.port));
+        }
+        if self.stream.enabled {
+            ports.push(("stream.port", self.stream.port));
+        }
+        if self.dashboard.enabled {
+            ports.push(("dashboard.port", self.dashboard.port));
+        }
+
+        for (i, (path, port)) in ports.iter().enumerate() {
+            // The server port is checked along with the rest of the interface
+            if *port == 0 && *path != "interface.server_port" {
+                problems.push(ConfigProblem::error(*path, "must be between 1 and 65535"));
+            } else if let Some((other, _)) = ports[..i].iter().find(|(_, other)| other == port) {
+                problems.push(ConfigProblem::error(*path, format!("is the same as {other}")));
+            }
+        }
+    }
+
+    fn validate_pipelines(&self, problems: &mut Vec<ConfigProblem>) {
+        if self.pipelines.contains_key(DEFAULT_PIPELINE) {
+            problems.push(ConfigProblem::error(
+                format!("pipelines.{DEFAULT_PIPELINE}"),
+                "is the top level detection_config and camera_controls, use another name",
+            ));
+        }
+        let active = self.active_pipeline();
+        if active != DEFAULT_PIPELINE && !self.pipelines.contains_key(active) {
+            problems.push(ConfigProblem::error("active_pipeline", format!("there is no pipeline named `{active}`")));
+        }
+
+        for (name, pipeline) in self.pipelines.iter() {
+            let section = format!("pipelines.{name}");
+            pipeline.detection_config.validate(&value_path(&section, "detection_config"), problems);
+
+            if let Some(index) = pipeline.index {
+                let taken = self
+                    .pipelines
+                    .iter()
+                    .take_while(|(other_name, _)| *other_name != name)
+                    .any(|(_, other)| other.index == Some(index));
+                if taken {
+                    problems.push(ConfigProblem::error(
+                        value_path(&section, "index"),
+                        format!("{index} is used by another pipeline"),
+                    ));
+                }
+            }
+            for (i, kind) in pipeline.outputs.iter().flatten().enumerate() {
+                if !self.interface.outputs.contains(kind) {
+                    problems.push(ConfigProblem::warning(
+                        format!("{section}.outputs[{i}]"),
+                        format!("{kind:?} isn't in interface.outputs, so it isn't running"),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Model that turns how good a detection looks into standard deviations for its pose.
+///
+/// A single tag at 1 meter with a perfect detection gets the base standard deviations, which then grow with the
+/// square (by default) of the distance and with every other sign of a bad detection.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UncertaintyConfig {
+    /// Translation standard deviation of a perfect single tag detection at 1 meter, in meters
+    #[serde(default = "default_translation_std")]
+    pub translation_std: f64,
+    /// Rotation standard deviation of a perfect single tag detection at 1 meter, in radians
+    #[serde(default = "default_rotation_std")]
+    pub rotation_std: f64,
+    /// Power of the tag distance the standard deviations grow with
+    #[serde(default = "default_distance_exponent")]
+    pub distance_exponent: f64,
+    /// Decision margin at or above which a detection isn't penalized, lower margins scale up linearly
+    #[serde(default = "default_reference_margin")]
+    pub reference_margin: f64,
+    /// How much each pixel of mean reprojection error scales up the standard deviations
+    #[serde(default = "default_reprojection_weight")]
+    pub reprojection_weight: f64,
+    /// How much the pose ambiguity (0 to 1) scales up the standard deviations
+    #[serde(default = "default_ambiguity_weight")]
+    pub ambiguity_weight: f64,
+    /// The robot pose standard deviations are divided by the number of field tags in view to this power
+    #[serde(default = "default_tag_count_exponent")]
+    pub tag_count_exponent: f64,
+}
+
+impl Default for UncertaintyConfig {
+    fn default() -> Self {
+        Self {
+            translation_std: default_translation_std(),
+            rotation_std: default_rotation_std(),
+            distance_exponent: default_distance_exponent(),
+            reference_margin: default_reference_margin(),
+            reprojection_weight: default_reprojection_weight(),
+            ambiguity_weight: default_ambiguity_weight(),
+            tag_count_exponent: default_tag_count_exponent(),
+        }
+    }
+}
+
+impl UncertaintyConfig {
+    fn validate(&self, section: &str, problems: &mut Vec<ConfigProblem>) {
+        let positive = [
+            ("translation_std", self.translation_std),
+            ("rotation_std", self.rotation_std),
+        ];
+        let non_negative = [
+            ("distance_exponent", self.distance_exponent),
+            ("reference_margin", self.reference_margin),
+            ("reprojection_weight", self.reprojection_weight),
+            ("ambiguity_weight", self.ambiguity_weight),
+            ("tag_count_exponent", self.tag_count_exponent),
+        ];
+        check_positive(section, &positive, problems);
+        check_non_negative(section, &non_negative, problems);
+    }
+}
+
+fn default_translation_std() -> f64 {
+    0.02
+}
+
+fn default_rotation_std() -> f64 {
+    0.05
+}
+
+fn default_distance_exponent() -> f64 {
+    2.0
+}
+
+fn default_reference_margin() -> f64 {
+    150.0
+}
+
+fn default_reprojection_weight() -> f64 {
+    0.5
+}
+
+fn default_ambiguity_weight() -> f64 {
+    4.0
+}
+
+fn default_tag_count_exponent() -> f64 {
+    0.5
+}
+
+/// Settings for the pose tracking stage that smooths poses across frames
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TrackingConfig {
+    /// Run the tracking stage
+    #[serde(default)]
+    pub enabled: bool,
+    /// How hard we expect translations to accelerate, in m/s²
+    #[serde(default = "default_translation_process_noise")]
+    pub translation_process_noise: f64,
+    /// How hard we expect rotations to accelerate, in rad/s²
+    #[serde(default = "default_rotation_process_noise")]
+    pub rotation_process_noise: f64,
+    /// Standard deviation of a translation measurement, in meters
+    #[serde(default = "default_translation_measurement_noise")]
+    pub translation_measurement_noise: f64,
+    /// Standard deviation of a rotation measurement, in radians
+    #[serde(default = "default_rotation_measurement_noise")]
+    pub rotation_measurement_noise: f64,
+    /// Squared Mahalanobis distance above which a measurement is rejected.
+    /// The default is the 99% point of a chi-squared distribution with 3 degrees of freedom.
+    #[serde(default = "default_gate")]
+    pub gate: f64,
+    /// Restart a track after this many rejected measurements in a row
+    #[serde(default = "default_max_rejects")]
+    pub max_rejects: u32,
+    /// Drop a track that hasn't been updated for this long, in milliseconds
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Default for TrackingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            translation_process_noise: default_translation_process_noise(),
+            rotation_process_noise: default_rotation_process_noise(),
+            translation_measurement_noise: default_translation_measurement_noise(),
+            rotation_measurement_noise: default_rotation_measurement_noise(),
+            gate: default_gate(),
+            max_rejects: default_max_rejects(),
+            timeout_ms: default_timeout_ms(),
+        }
+    }
+}
+
+impl TrackingConfig {
+    fn validate(&self, problems: &mut Vec<ConfigProblem>) {
+        let positive = [
+            ("translation_process_noise", self.translation_process_noise),
+            ("rotation_process_noise", self.rotation_process_noise),
+            ("translation_measurement_noise", self.translation_measurement_noise),
+            ("rotation_measurement_noise", self.rotation_measurement_noise),
+            ("gate", self.gate),
+            ("timeout_ms", self.timeout_ms as f64),
+        ];
+        check_positive("tracking", &positive, problems);
+    }
+}
+
+fn value_path(section: &str, name: &str) -> String {
+    if section.is_empty() {
+        name.to_string()
+    } else {
+        format!("{section}.{name}")
+    }
+}
+
+/// Adds an error for every value that isn't a finite number above zero
+fn check_positive(section: &str, values: &[(&str, f64)], problems: &mut Vec<ConfigProblem>) {
+    for (name, value) in values {
+        if !(value.is_finite() && *value > 0.0) {
+            problems.push(ConfigProblem::error(value_path(section, name), "must be above zero"));
+        }
+    }
+}
+
+/// Adds an error for every value that isn't a finite number of at least zero
+fn check_non_negative(section: &str, values: &[(&str, f64)], problems: &mut Vec<ConfigProblem>) {
+    for (name, value) in values {
+        if !(value.is_finite() && *value >= 0.0) {
+            problems.push(ConfigProblem::error(value_path(section, name), "can't be negative"));
+        }
+    }
+}
+
+fn default_translation_process_noise() -> f64 {
+    4.0
+}
+
+fn default_rotation_process_noise() -> f64 {
+    8.0
+}
+
+fn default_translation_measurement_noise() -> f64 {
+    0.03
+}
+
+fn default_rotation_measurement_noise() -> f64 {
+    0.05
+}
+
+fn default_gate() -> f64 {
+    11.34
+}
+
+fn default_max_rejects() -> u32 {
+    5
+}
+
+fn default_timeout_ms() -> u64 {
+    500
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct InterfaceConfig {
+    #[serde(default = "default_nt_ip")]
     pub nt_ip: [u8; 4],
+    #[serde(default = "default_nt_port")]
     pub nt_port: u16,
+    #[serde(default = "default_server_port")]
     pub server_port: u16,
-    pub serial_port: String
+    #[serde(default = "default_serial_port")]
+    pub serial_port: String,
+    /// Every output the results get sent to, all of them run at the same time
+    #[serde(default = "default_outputs")]
+    pub outputs: Vec<OutputKind>,
+    /// Address (`ip:port`) the UDP output sends packets to
+    #[serde(default)]
+    pub udp_target: String,
+    /// Path of the file the file log output appends results to
+    #[serde(default)]
+    pub log_path: String,
+    /// Only log results while the robot is enabled, needs the match state from the robot
+    #[serde(default)]
+    pub log_only_enabled: bool,
+    /// Table the NetworkTables output publishes its topics under
+    #[serde(default = "default_nt_table")]
+    pub nt_table: String,
+    /// Topic layout the NetworkTables output publishes
+    #[serde(default)]
+    pub nt_mode: NtMode,
+    /// Camera name to publish under in `PhotonVision` mode, must match the name given to `PhotonCamera`
+    #[serde(default = "default_photon_camera")]
+    pub photon_camera: String,
+    /// Table to publish under in `Limelight` mode, must match the name given to `LimelightHelpers`
+    #[serde(default = "default_limelight_table")]
+    pub limelight_table: String,
+    /// NetworkTables topics to read robot values from, read whatever the outputs are, clear them all to not connect
+    #[serde(default)]
+    pub robot_topics: RobotTopics,
+    /// Seconds between health statuses sent on every output
+    #[serde(default = "default_health_interval")]
+    pub health_interval: f64,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+impl Default for InterfaceConfig {
+    fn default() -> Self {
+        Self {
+            nt_ip: default_nt_ip(),
+            nt_port: default_nt_port(),
+            server_port: default_server_port(),
+            serial_port: default_serial_port(),
+            outputs: default_outputs(),
+            udp_target: String::new(),
+            log_path: String::new(),
+            log_only_enabled: false,
+            nt_table: default_nt_table(),
+            nt_mode: NtMode::default(),
+            photon_camera: default_photon_camera(),
+            limelight_table: default_limelight_table(),
+            robot_topics: RobotTopics::default(),
+            health_interval: default_health_interval(),
+        }
+    }
+}
+
+impl InterfaceConfig {
+    fn validate(&self, problems: &mut Vec<ConfigProblem>) {
+        if self.outputs.is_empty() {
+            problems.push(ConfigProblem::warning("interface.outputs", "no outputs, results won't go anywhere"));
+        }
+        for (i, kind) in self.outputs.iter().enumerate() {
+            if self.outputs[..i].contains(kind) {
+                problems.push(ConfigProblem::warning(
+                    format!("interface.outputs[{i}]"),
+                    format!("{kind:?} is listed more than once"),
+                ));
+            }
+        }
+
+        if self.outputs.contains(&OutputKind::Serial) {
+            if self.serial_port.is_empty() {
+                problems.push(ConfigProblem::error("interface.serial_port", "needed by the Serial output"));
+            } else if !Path::new(&self.serial_port).exists() {
+                // It may just not be plugged in yet, the output keeps trying to open it
+                problems.push(ConfigProblem::warning(
+                    "interface.serial_port",
+                    format!("{} doesn't exist", self.serial_port),
+                ));
+            }
+        }
+
+        if !(self.health_interval.is_finite() && self.health_interval > 0.0) {
+            problems.push(ConfigProblem::error("interface.health_interval", "must be more than 0 seconds"));
+        }
+
+        if self.outputs.contains(&OutputKind::Server) && self.server_port == 0 {
+            problems.push(ConfigProblem::error("interface.server_port", "must be between 1 and 65535"));
+        }
+
+        if self.outputs.contains(&OutputKind::Udp) && self.udp_target.parse::<SocketAddr>().is_err() {
+            problems.push(ConfigProblem::error(
+                "interface.udp_target",
+                format!("`{}` isn't an `ip:port` address", self.udp_target),
+            ));
+        }
+
+        if self.outputs.contains(&OutputKind::NetworkTables) {
+            if !cfg!(feature = "nt") {
+                problems.push(ConfigProblem::error("interface.outputs", "NetworkTables needs the `nt` feature"));
+            }
+            let ip = Ipv4Addr::from(self.nt_ip);
+            if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+                problems.push(ConfigProblem::error("interface.nt_ip", format!("{ip} isn't a server address")));
+            }
+            if self.nt_port == 0 {
+                problems.push(ConfigProblem::error("interface.nt_port", "must be between 1 and 65535"));
+            }
+            let (path, name) = match self.nt_mode {
+                NtMode::Vision => ("interface.nt_table", &self.nt_table),
+                NtMode::PhotonVision => ("interface.photon_camera", &self.photon_camera),
+                NtMode::Limelight => ("interface.limelight_table", &self.limelight_table),
+            };
+            if name.trim_matches('/').is_empty() {
+                problems.push(ConfigProblem::error(path, format!("needed in {:?} mode", self.nt_mode)));
+            }
+        }
+
+        if self.outputs.contains(&OutputKind::FileLog) {
+            if self.log_path.is_empty() {
+                problems.push(ConfigProblem::error("interface.log_path", "needed by the FileLog output"));
+            } else {
+                let parent = Path::new(&self.log_path).parent().filter(|p| !p.as_os_str().is_empty());
+                if let Some(parent) = parent.filter(|p| !p.is_dir()) {
+                    problems.push(ConfigProblem::error(
+                        "interface.log_path",
+                        format!("directory {} doesn't exist", parent.display()),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// NetworkTables topics the robot values are read from, leave a topic empty to not subscribe to it
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RobotTopics {
+    /// Gyro heading in degrees, counter-clockwise positive (double)
+    #[serde(default)]
+    pub heading: String,
+    /// Whether the robot is on the red alliance (boolean)
+    #[serde(default = "default_alliance_topic")]
+    pub alliance: String,
+    /// Driver station control word (int)
+    #[serde(default = "default_match_state_topic")]
+    pub match_state: String,
+    /// Pipeline to run, by index (int) or by name (string)
+    #[serde(default)]
+    pub pipeline: String,
+}
+
+impl RobotTopics {
+    /// Whether no topic is set, so there is nothing to subscribe to
+    pub fn is_empty(&self) -> bool {
+        [&self.heading, &self.alliance, &self.match_state, &self.pipeline].iter().all(|topic| topic.is_empty())
+    }
+}
+
+impl Default for RobotTopics {
+    fn default() -> Self {
+        Self {
+            heading: String::new(),
+            alliance: default_alliance_topic(),
+            match_state: default_match_state_topic(),
+            pipeline: String::new(),
+        }
+    }
+}
+
+fn default_nt_ip() -> [u8; 4] {
+    [10, 31, 89, 2]
+}
+
+fn default_nt_port() -> u16 {
+    5810
+}
+
+fn default_server_port() -> u16 {
+    8010
+}
+
+fn default_serial_port() -> String {
+    "/dev/ttyS3".to_string()
+}
+
+fn default_alliance_topic() -> String {
+    "/FMSInfo/IsRedAlliance".to_string()
+}
+
+fn default_match_state_topic() -> String {
+    "/FMSInfo/FMSControlData".to_string()
+}
+
+fn default_nt_table() -> String {
+    "Vision".to_string()
+}
+
+fn default_photon_camera() -> String {
+    "vision".to_string()
+}
+
+fn default_limelight_table() -> String {
+    "limelight".to_string()
+}
+
+fn default_health_interval() -> f64 {
+    1.0
+}
+
+fn default_metrics_port() -> u16 {
+    5801
+}
+
+fn default_stream_port() -> u16 {
+    5802
+}
+
+fn default_stream_fps() -> f64 {
+    10.0
+}
+
+fn default_stream_quality() -> u8 {
+    70
+}
+
+fn default_stream_width() -> u32 {
+    640
+}
+
+fn default_dashboard_port() -> u16 {
+    5803
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_max_log_files() -> usize {
+    7
+}
+
+/// The topic layouts the NetworkTables output can publish
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NtMode {
+    /// Our own topics under `nt_table`
+    #[default]
+    Vision,
+    /// PhotonVision topics and packed results under `/photonvision/<photon_camera>`, readable by PhotonLib
+    PhotonVision,
+    /// Limelight entries under `limelight_table`
+    Limelight,
+}
+
+fn default_outputs() -> Vec<OutputKind> {
+    vec![OutputKind::Serial]
+}
+
+/// The kinds of outputs the results can be sent over
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    /// `VisionData` packets over the serial port in `serial_port`
+    Serial,
+    /// `VisionData` packets to a client connected on `server_port`
+    Server,
+    /// `VisionData` packets as UDP datagrams to `udp_target`
+    Udp,
+    /// NetworkTables 4 topics on the server at `nt_ip`:`nt_port`
+    NetworkTables,
+    /// JSON lines appended to the file at `log_path`
+    FileLog,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
 pub enum AprilTagFamily {
     #[default]
     Tag16H5,
@@ -229,6 +1370,21 @@ pub enum AprilTagFamily {
     TagCustom48h12,
 }
 
+impl AprilTagFamily {
+    /// Every family, for picking one from a list
+    #[cfg_attr(not(feature = "gui"), allow(dead_code))]
+    pub const ALL: [AprilTagFamily; 8] = [
+        AprilTagFamily::Tag16H5,
+        AprilTagFamily::Tag25H9,
+        AprilTagFamily::Tag36H11,
+        AprilTagFamily::TagCircle21H7,
+        AprilTagFamily::TagCircle49h12,
+        AprilTagFamily::TagStandard41h12,
+        AprilTagFamily::TagStandard52h13,
+        AprilTagFamily::TagCustom48h12,
+    ];
+}
+
 impl From<&AprilTagFamily> for Family {
     fn from(value: &AprilTagFamily) -> Self {
         match value {
@@ -243,3 +1399,72 @@ impl From<&AprilTagFamily> for Family {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn port_problems(config: Value) -> Vec<(String, String)> {
+        let config: Config = serde_json::from_value(config).unwrap();
+        let mut problems = vec![];
+        config.validate_ports(&mut problems);
+        problems.into_iter().map(|problem| (problem.path, problem.message)).collect()
+    }
+
+    #[test]
+    fn default_ports_are_fine() {
+        let config = json!({
+            "interface": { "outputs": ["Server"] },
+            "metrics": { "enabled": true },
+            "stream": { "enabled": true },
+            "dashboard": { "enabled": true },
+        });
+        assert_eq!(port_problems(config), vec![]);
+    }
+
+    #[test]
+    fn shared_ports_are_an_error() {
+        let config = json!({
+            "interface": { "outputs": ["Server"], "server_port": 5801 },
+            "metrics": { "enabled": true },
+            "stream": { "enabled": true, "port": 5801 },
+        });
+        assert_eq!(
+            port_problems(config),
+            vec![
+                ("metrics.port".to_string(), "is the same as interface.server_port".to_string()),
+                ("stream.port".to_string(), "is the same as interface.server_port".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn servers_that_are_off_dont_count() {
+        let config = json!({
+            "metrics": { "enabled": true, "port": 5803 },
+            "dashboard": { "port": 5803 },
+        });
+        assert_eq!(port_problems(config), vec![]);
+    }
+
+    #[test]
+    fn port_zero_is_an_error() {
+        let config = json!({ "dashboard": { "enabled": true, "port": 0 } });
+        assert_eq!(
+            port_problems(config),
+            vec![("dashboard.port".to_string(), "must be between 1 and 65535".to_string())]
+        );
+    }
+
+    #[test]
+    fn nominal_calibration() {
+        let calibration = CameraCalibration::nominal(1920, 1080);
+        let params = calibration.tag_params();
+        let focal = 960.0 / (NOMINAL_FOV_DEGREES / 2.0).to_radians().tan();
+        assert!((params.fx - focal).abs() < 1e-9 && (params.fy - focal).abs() < 1e-9);
+        assert_eq!((params.cx, params.cy), (960.0, 540.0));
+        assert!(calibration.validate().iter().all(|problem| problem.severity != Severity::Error));
+    }
+}
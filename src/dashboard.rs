@@ -0,0 +1,250 @@
+//! # Web dashboard
+//!
+//! A page served at `/` on `dashboard.port` when `dashboard.enabled` is set, for watching and tuning the pipeline
+//! from a laptop on the robot network without SSH. It shows the annotated stream (when `stream.enabled` is set), the
+//! latest targets, the health status, the Prometheus metrics and the config, and edits the camera controls, detector
+//! settings, tag filters and active pipeline.
+//!
+//! Edits aren't applied to the pipeline directly: they are merged into the config file, checked like any other
+//! config, and saved with `reload::save_edit`. Hot reload then picks the file up the same way it would a hand edit,
+//! so what is running is always what is in `config.json`. An edit that doesn't check out is sent back with its
+//! problems and the file is left alone.
+//!
+//! Edits have to be sent as `application/json`, and from the dashboard's own page when a browser sends them: a page on
+//! any other site can't send either without the browser stopping it, so it can't change the config behind the back
+//! of someone who has the dashboard open.
+//!
+//! | Path | Method | |
+//! |---|---|---|
+//! | `/` | `GET` | The page |
+//! | `/api/status` | `GET` | Latest results, health status and where the stream is |
+//! | `/api/metrics` | `GET` | The same text `/metrics` serves |
+//! | `/api/config` | `GET` | The config with every default filled in, and the pipeline names |
+//! | `/api/config` | `POST` | A JSON merge patch (RFC 7386) of the sections in `EDITABLE_SECTIONS` |
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::cli::Overrides;
+use crate::config::{Config, ConfigError, ConfigProblem, Severity, StreamConfig};
+use crate::health::HealthStatus;
+use crate::http::{self, Request, Response};
+use crate::interface::DataError;
+use crate::metrics::{Metrics, PROMETHEUS_CONTENT_TYPE};
+use crate::process::VisionFrame;
+use crate::reload::save_edit;
+use crate::stream::STREAM_PATH;
+
+/// The page, scripts and styles included
+pub const DASHBOARD_PAGE: &str = include_str!("dashboard.html");
+
+/// Top level config sections the dashboard may change, everything else needs the file edited
+pub const EDITABLE_SECTIONS: [&str; 4] = ["camera_controls", "detection_config", "pipelines", "active_pipeline"];
+
+/// Everything the dashboard shows and where its edits go
+pub struct Dashboard {
+    config_path: PathBuf,
+    /// Applied before an edit is checked, the same as on reload
+    overrides: Overrides,
+    stream: StreamConfig,
+    metrics: Arc<Metrics>,
+    results: watch::Receiver<Option<Arc<VisionFrame>>>,
+    health: watch::Receiver<Option<HealthStatus>>,
+}
+
+impl Dashboard {
+    pub fn new(
+        config_path: PathBuf,
+        overrides: Overrides,
+        stream: StreamConfig,
+        metrics: Arc<Metrics>,
+        results: watch::Receiver<Option<Arc<VisionFrame>>>,
+        health: watch::Receiver<Option<HealthStatus>>,
+    ) -> Arc<Self> {
+        Arc::new(Dashboard {
+            config_path,
+            overrides,
+            stream,
+            metrics,
+            results,
+            health,
+        })
+    }
+
+    async fn respond(&self, request: Request) -> Response {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/") => Response::ok("text/html; charset=utf-8", DASHBOARD_PAGE),
+            ("GET", "/api/status") => self.status(),
+            ("GET", "/api/metrics") => Response::ok(PROMETHEUS_CONTENT_TYPE, self.metrics.render()),
+            ("GET", "/api/config") => self.config().await,
+            ("POST", "/api/config") => match cross_site(&request) {
+                Some(err) => error_response(err.0, err.1),
+                None => self.edit(&request.body).await,
+            },
+            (_, "/" | "/api/status" | "/api/metrics" | "/api/config") => Response::method_not_allowed(),
+            _ => Response::not_found(),
+        }
+    }
+
+    fn status(&self) -> Response {
+        let results = self.results.borrow().clone();
+        let health = self.health.borrow().clone();
+        Response::json(
+            200,
+            &json!({
+                "results": results.as_deref(),
+                "health": health,
+                "stream": {
+                    "enabled": self.stream.enabled,
+                    "port": self.stream.port,
+                    "path": STREAM_PATH,
+                },
+            }),
+        )
+    }
+
+    async fn config(&self) -> Response {
+        let file = self.config_path.clone();
+        let config = match tokio::task::spawn_blocking(move || Config::load_from_file(file)).await {
+            Ok(Ok(config)) => config,
+            Ok(Err(err)) => return error_response(500, err.to_string()),
+            Err(err) => return error_response(500, err.to_string()),
+        };
+        let pipelines: Vec<String> = config.pipelines().into_keys().collect();
+        Response::json(
+            200,
+            &json!({
+                "config": config,
+                "active_pipeline": config.active_pipeline(),
+                "pipelines": pipelines,
+                "editable": EDITABLE_SECTIONS,
+            }),
+        )
+    }
+
+    /// Merges the patch into the config file and saves it if the result checks out
+    async fn edit(&self, body: &[u8]) -> Response {
+        let patch = match serde_json::from_slice(body) {
+            Ok(Value::Object(patch)) => patch,
+            _ => return error_response(400, "the edit must be a JSON object"),
+        };
+        if let Some(section) = patch.keys().find(|section| !EDITABLE_SECTIONS.contains(&section.as_str())) {
+            return error_response(400, format!("{section} can't be changed from the dashboard"));
+        }
+
+        let config_path = self.config_path.clone();
+        let overrides = self.overrides.clone();
+        let saved = tokio::task::spawn_blocking(move || save_edit(&config_path, &overrides, patch)).await;
+        match saved {
+            Ok(Ok(warnings)) => {
+                info!("Dashboard saved an edit to {}", self.config_path.display());
+                Response::json(200, &json!({ "problems": problems_json(&warnings) }))
+            }
+            Ok(Err(ConfigError::Invalid(problems))) => {
+                Response::json(400, &json!({ "problems": problems_json(&problems) }))
+            }
+            Ok(Err(err @ ConfigError::Parse { .. })) => error_response(400, err.to_string()),
+            Ok(Err(err)) => {
+                warn!("Dashboard failed to save an edit [{}]", err);
+                error_response(500, err.to_string())
+            }
+            Err(err) => error_response(500, err.to_string()),
+        }
+    }
+}
+
+/// Serves the dashboard on `port` until shutdown
+pub async fn serve(port: u16, dashboard: Arc<Dashboard>, shutdown: CancellationToken) -> Result<(), DataError> {
+    http::serve(
+        "Dashboard",
+        port,
+        move |request: Request, mut stream: TcpStream| {
+            let dashboard = dashboard.clone();
+            async move { dashboard.respond(request).await.write(&mut stream).await }
+        },
+        shutdown,
+    )
+    .await
+}
+
+/// Why an edit can't be taken, when it wasn't sent as JSON or came from another site's page
+fn cross_site(request: &Request) -> Option<(u16, &'static str)> {
+    let json = request.content_type.as_deref().is_some_and(|content_type| {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        essence.eq_ignore_ascii_case("application/json")
+    });
+    if !json {
+        return Some((415, "edits have to be sent as application/json"));
+    }
+    // Browsers always send where the page came from, tools like curl send nothing
+    if let Some(origin) = &request.origin {
+        let same_origin = origin
+            .strip_prefix("http://")
+            .is_some_and(|origin| request.host.as_deref().is_some_and(|host| host.eq_ignore_ascii_case(origin)));
+        if !same_origin {
+            return Some((403, "edits can only come from the dashboard's own page"));
+        }
+    }
+    None
+}
+
+fn problems_json(problems: &[ConfigProblem]) -> Vec<Value> {
+    problems
+        .iter()
+        .map(|problem| {
+            json!({
+                "severity": match problem.severity {
+                    Severity::Warning => "warning",
+                    Severity::Error => "error",
+                },
+                "path": problem.path,
+                "message": problem.message,
+            })
+        })
+        .collect()
+}
+
+fn error_response(status: u16, message: impl Into<String>) -> Response {
+    Response::json(status, &json!({ "error": message.into() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(content_type: Option<&str>, origin: Option<&str>) -> Request {
+        Request {
+            method: "POST".to_string(),
+            path: "/api/config".to_string(),
+            content_type: content_type.map(str::to_string),
+            origin: origin.map(str::to_string),
+            host: Some("10.0.0.5:5803".to_string()),
+            body: b"{}".to_vec(),
+        }
+    }
+
+    #[test]
+    fn takes_json_from_its_own_page() {
+        assert_eq!(cross_site(&edit(Some("application/json"), Some("http://10.0.0.5:5803"))), None);
+        assert_eq!(cross_site(&edit(Some("Application/JSON; charset=utf-8"), None)), None);
+    }
+
+    #[test]
+    fn turns_away_other_content_types() {
+        for content_type in [None, Some("text/plain"), Some("application/x-www-form-urlencoded")] {
+            assert_eq!(cross_site(&edit(content_type, None)).map(|err| err.0), Some(415));
+        }
+    }
+
+    #[test]
+    fn turns_away_other_origins() {
+        for origin in ["http://evil.example", "http://10.0.0.5:5802", "https://10.0.0.5:5803", "null"] {
+            assert_eq!(cross_site(&edit(Some("application/json"), Some(origin))).map(|err| err.0), Some(403));
+        }
+    }
+}
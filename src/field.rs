@@ -0,0 +1,125 @@
+//! # Field layout
+//!
+//! Loads the AprilTag field layout JSON that WPILib ships for each game (e.g. `2024-crescendo.json`) and uses it
+//! to turn a tag seen by the camera into a field relative robot pose.
+//!
+//! Field poses use the WPILib conventions: the origin is the blue alliance corner, x points towards the red alliance
+//! wall, y to the left and z up, and a tag's x axis points straight out of its face.
+use std::collections::HashMap;
+use std::path::Path;
+
+use nalgebra::{Isometry3, Quaternion, Translation3, UnitQuaternion};
+use serde::*;
+
+use crate::config::CalibrationError;
+use crate::geometry::camera_to_tag;
+use crate::process::{TagPose, TagTarget};
+
+#[derive(Debug, Clone, Deserialize)]
+struct LayoutJson {
+    tags: Vec<TagJson>,
+    field: FieldJson,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TagJson {
+    #[serde(rename = "ID")]
+    id: u32,
+    pose: PoseJson,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PoseJson {
+    translation: TranslationJson,
+    rotation: RotationJson,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TranslationJson {
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RotationJson {
+    quaternion: QuaternionJson,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+struct QuaternionJson {
+    w: f64,
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FieldJson {
+    length: f64,
+    width: f64,
+}
+
+/// Where every tag is on the field
+#[derive(Debug, Clone)]
+pub struct FieldLayout {
+    /// Pose of each tag on the field by id
+    pub tags: HashMap<u32, Isometry3<f64>>,
+    /// Length of the field along x in meters
+    pub length: f64,
+    /// Width of the field along y in meters
+    pub width: f64,
+}
+
+impl FieldLayout {
+    /// Loads a WPILib AprilTag field layout JSON file from the given path
+    pub fn load_from_file<T: AsRef<Path>>(path: T) -> Result<Self, CalibrationError> {
+        let json_text = std::fs::read_to_string(path)?;
+        let layout: LayoutJson = match serde_json::from_str(&json_text) {
+            Ok(v) => v,
+            Err(e) => return Err(CalibrationError::LoadError(format!("{e}"))),
+        };
+
+        let tags = layout
+            .tags
+            .iter()
+            .map(|tag| {
+                let t = &tag.pose.translation;
+                let q = &tag.pose.rotation.quaternion;
+                let pose = Isometry3::from_parts(
+                    Translation3::new(t.x, t.y, t.z),
+                    UnitQuaternion::from_quaternion(Quaternion::new(q.w, q.x, q.y, q.z)),
+                );
+                (tag.id, pose)
+            })
+            .collect();
+
+        Ok(FieldLayout {
+            tags,
+            length: layout.field.length,
+            width: layout.field.width,
+        })
+    }
+
+    /// Field relative pose of the robot from the best pose of a single tag, `None` if the tag isn't on the field
+    pub fn robot_pose(
+        &self,
+        target: &TagTarget,
+        robot_to_camera: &Isometry3<f64>,
+    ) -> Option<Isometry3<f64>> {
+        self.robot_pose_from(target.id, &target.best, robot_to_camera)
+    }
+
+    /// Field relative pose of the robot if the given tag is at the given pose
+    pub fn robot_pose_from(
+        &self,
+        id: u32,
+        pose: &TagPose,
+        robot_to_camera: &Isometry3<f64>,
+    ) -> Option<Isometry3<f64>> {
+        let field_to_tag = self.tags.get(&id)?;
+        let field_to_camera = field_to_tag * camera_to_tag(pose).inverse();
+        Some(field_to_camera * robot_to_camera.inverse())
+    }
+}
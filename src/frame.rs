@@ -0,0 +1,349 @@
+//! # Camera frames
+//!
+//! The detector only ever looks at grayscale, so the capture thread decodes every frame straight to luma instead
+//! of to RGBA: MJPEG is decompressed with grayscale output (which skips the color conversion and the chroma planes)
+//! and YUYV and NV12 already carry a luma plane that only needs picking out. The luma goes into a buffer from a
+//! `BufferPool`, which it returns to once every reference to the frame is gone, so the luma planes are reused
+//! instead of allocated for every frame (the camera's own buffer still is, nokhwa hands out a new one each time).
+//!
+//! The camera's own buffer stays with the frame, and RGB is only decoded from it when something wants to show the
+//! frame (the GUI), so frames nobody looks at never pay for it.
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use image::RgbImage;
+use nokhwa::pixel_format::RgbFormat;
+use nokhwa::utils::FrameFormat;
+use nokhwa::Buffer;
+use thiserror::Error;
+
+/// How many spare buffers a pool keeps, enough for the frame in the mailbox, the one being processed and the one
+/// being captured
+pub const POOL_SIZE: usize = 4;
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("Failed to decode {format} frame: {message}")]
+    Decode { format: FrameFormat, message: String },
+    #[error("Frame is {actual} bytes, expected {expected}")]
+    Size { actual: usize, expected: usize },
+}
+
+type Free = Mutex<Vec<Vec<u8>>>;
+
+/// Reusable byte buffers for luma frames
+#[derive(Clone)]
+pub struct BufferPool {
+    free: Arc<Free>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        BufferPool {
+            free: Arc::new(Mutex::new(Vec::with_capacity(POOL_SIZE))),
+        }
+    }
+
+    /// A buffer of exactly `len` bytes, reused if there is a spare one. The contents are whatever was left in it.
+    pub fn take(&self, len: usize) -> PooledBuffer {
+        let mut data = self.free.lock().unwrap().pop().unwrap_or_default();
+        data.resize(len, 0);
+        PooledBuffer {
+            data,
+            pool: Arc::downgrade(&self.free),
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A buffer that goes back to its pool when dropped
+pub struct PooledBuffer {
+    data: Vec<u8>,
+    pool: Weak<Free>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(free) = self.pool.upgrade() {
+            let mut free = free.lock().unwrap();
+            if free.len() < POOL_SIZE {
+                free.push(std::mem::take(&mut self.data));
+            }
+        }
+    }
+}
+
+/// A single grayscale frame
+pub struct Frame {
+    width: u32,
+    height: u32,
+    /// When the camera handed the frame over, in seconds since the Unix epoch
+    timestamp: f64,
+    /// Row after row of `width` pixels, with no padding
+    luma: PooledBuffer,
+    /// What the camera sent, kept around for decoding color on demand
+    source: Buffer,
+}
+
+impl Frame {
+    /// Decodes the luma of a camera frame into a buffer from the pool, stamping it with the time it was called.
+    ///
+    /// Call it as soon as the camera returns the frame, the stamp is what the robot lines the pose up with its
+    /// odometry by, and it should count the time the frame then spends waiting to be processed.
+    pub fn decode(buffer: Buffer, pool: &BufferPool) -> Result<Frame, FrameError> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |since| since.as_secs_f64());
+        let resolution = buffer.resolution();
+        let (width, height) = (resolution.width(), resolution.height());
+        let format = buffer.source_frame_format();
+        let data = buffer.buffer();
+        let len = width as usize * height as usize;
+        let mut luma = pool.take(len);
+
+        match format {
+            FrameFormat::MJPEG => decode_mjpeg_luma(data, width, height, &mut luma)?,
+            FrameFormat::YUYV => {
+                // Y0 U Y1 V, every other byte is luma
+                check_size(data.len(), len * 2)?;
+                for (dest, src) in luma.iter_mut().zip(data.iter().step_by(2)) {
+                    *dest = *src;
+                }
+            }
+            // The full size Y plane comes first
+            FrameFormat::NV12 | FrameFormat::GRAY => {
+                check_size(data.len(), len)?;
+                luma.copy_from_slice(&data[..len]);
+            }
+            FrameFormat::RAWRGB => {
+                check_size(data.len(), len * 3)?;
+                for (dest, rgb) in luma.iter_mut().zip(data.chunks_exact(3)) {
+                    *dest = ((rgb[0] as u16 + rgb[1] as u16 + rgb[2] as u16) / 3) as u8;
+                }
+            }
+        }
+
+        Ok(Frame {
+            width,
+            height,
+            timestamp,
+            luma,
+            source: buffer,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// When the camera handed the frame over, in seconds since the Unix epoch
+    pub fn timestamp(&self) -> f64 {
+        self.timestamp
+    }
+
+    /// Row after row of `width` pixels
+    pub fn luma(&self) -> &[u8] {
+        &self.luma
+    }
+
+    /// Decodes the frame in color, this is as slow as decoding always used to be so only do it when showing it
+    pub fn to_rgb(&self) -> Option<RgbImage> {
+        self.source.decode_image::<RgbFormat>().ok()
+    }
+}
+
+fn check_size(actual: usize, expected: usize) -> Result<(), FrameError> {
+    if actual < expected {
+        return Err(FrameError::Size { actual, expected });
+    }
+    Ok(())
+}
+
+/// Decompresses just the luma of a JPEG into `dest`
+fn decode_mjpeg_luma(data: &[u8], width: u32, height: u32, dest: &mut [u8]) -> Result<(), FrameError> {
+    let error = |message: String| FrameError::Decode {
+        format: FrameFormat::MJPEG,
+        message,
+    };
+
+    // mozjpeg fails by unwinding out of an `extern "C"` callback, which aborts the process instead of panicking
+    // (no `catch_unwind` can stop it), so frames that are cut short or garbled have to be turned away first
+    check_jpeg(data, width, height).map_err(|message| error(message.to_string()))?;
+
+    let decompress = mozjpeg::Decompress::new_mem(data).map_err(|err| error(err.to_string()))?;
+    if decompress.size() != (width as usize, height as usize) {
+        return Err(error(format!(
+            "JPEG is {:?}, camera said {}x{}",
+            decompress.size(),
+            width,
+            height
+        )));
+    }
+    let mut decompress = decompress.grayscale().map_err(|err| error(err.to_string()))?;
+    if !decompress.read_scanlines_flat_into(dest) {
+        return Err(error("ran out of data".to_string()));
+    }
+    if !decompress.finish_decompress() {
+        return Err(error("didn't finish".to_string()));
+    }
+    Ok(())
+}
+
+/// Walks the JPEG's headers up to the scan the way libjpeg reads them, and checks the frame wasn't cut short.
+///
+/// This covers how camera frames actually go bad, a transfer that stopped early or a buffer of garbage. A frame
+/// that is cut off in the middle of the scan would decode without an error (libjpeg fills in the rest with gray),
+/// so it is caught by the missing end of image marker instead.
+fn check_jpeg(data: &[u8], width: u32, height: u32) -> Result<(), &'static str> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err("not a JPEG");
+    }
+    // Some cameras pad the frame out with zeros after the end of image
+    let end = data.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+    if !data[..end].ends_with(&[0xFF, 0xD9]) {
+        return Err("frame was cut short");
+    }
+
+    let mut pos = 2;
+    let mut found_frame = false;
+    loop {
+        if data.get(pos) != Some(&0xFF) {
+            return Err("expected a marker");
+        }
+        // Markers can be preceded by any number of fill bytes
+        while data.get(pos) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = *data.get(pos).ok_or("ran out of data in the headers")?;
+        pos += 1;
+        match marker {
+            0xD9 => return Err("no image before the end"),
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+        let length = match data.get(pos..pos + 2) {
+            Some(length) => u16::from_be_bytes([length[0], length[1]]) as usize,
+            None => return Err("ran out of data in the headers"),
+        };
+        if length < 2 {
+            return Err("bad header length");
+        }
+        let segment = data.get(pos + 2..pos + length).ok_or("header runs past the end of the frame")?;
+        match marker {
+            // Start of frame, except for the tables and extensions that share its range
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                let components = *segment.get(5).ok_or("start of frame is too short")? as usize;
+                if segment[0] != 8 || !(1..=4).contains(&components) || segment.len() != 6 + 3 * components {
+                    return Err("unsupported frame header");
+                }
+                let frame_height = u16::from_be_bytes([segment[1], segment[2]]) as u32;
+                let frame_width = u16::from_be_bytes([segment[3], segment[4]]) as u32;
+                if (frame_width, frame_height) != (width, height) {
+                    return Err("JPEG size doesn't match the camera");
+                }
+                found_frame = true;
+            }
+            // Start of scan, the headers are done
+            0xDA if found_frame => return Ok(()),
+            0xDA => return Err("scan before the frame header"),
+            _ => {}
+        }
+        pos += length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{GrayImage, Luma};
+    use nokhwa::utils::Resolution;
+
+    use super::*;
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let image = GrayImage::from_fn(width, height, |x, y| Luma([((x + y) * 4) as u8]));
+        let mut jpeg = Cursor::new(Vec::new());
+        JpegEncoder::new_with_quality(&mut jpeg, 90).encode_image(&image).unwrap();
+        jpeg.into_inner()
+    }
+
+    #[test]
+    fn stamps_when_it_was_handed_over() {
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        let buffer = Buffer::new(Resolution::new(32, 16), &jpeg(32, 16), FrameFormat::MJPEG);
+        let frame = Frame::decode(buffer, &BufferPool::new()).unwrap();
+        let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        assert!((before..=after).contains(&frame.timestamp()));
+    }
+
+    #[test]
+    fn decodes_luma() {
+        let data = jpeg(32, 16);
+        let mut dest = vec![0; 32 * 16];
+        decode_mjpeg_luma(&data, 32, 16, &mut dest).unwrap();
+        // Lossy, but close to the gradient that went in
+        let (x, y) = (17, 15);
+        assert!((dest[y * 32 + x] as i32 - ((x + y) * 4) as i32).abs() < 8);
+    }
+
+    #[test]
+    fn truncated_jpeg_is_an_error() {
+        let data = jpeg(32, 16);
+        let mut dest = vec![0; 32 * 16];
+        for len in [0, 1, 40, data.len() / 2, data.len() - 1] {
+            let result = decode_mjpeg_luma(&data[..len], 32, 16, &mut dest);
+            assert!(matches!(result, Err(FrameError::Decode { .. })), "cut to {len} bytes");
+        }
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        let mut data = jpeg(32, 16);
+        // Break the length of the first segment after the start of image
+        data[4] = 0xFF;
+        let mut dest = vec![0; 32 * 16];
+        assert!(decode_mjpeg_luma(&data, 32, 16, &mut dest).is_err());
+        assert!(decode_mjpeg_luma(&[0x42; 100], 32, 16, &mut dest).is_err());
+    }
+
+    #[test]
+    fn zero_padding_is_fine() {
+        let mut data = jpeg(32, 16);
+        data.extend([0; 64]);
+        let mut dest = vec![0; 32 * 16];
+        decode_mjpeg_luma(&data, 32, 16, &mut dest).unwrap();
+    }
+
+    #[test]
+    fn wrong_size_is_an_error() {
+        let data = jpeg(32, 16);
+        let mut dest = vec![0; 16 * 16];
+        assert!(decode_mjpeg_luma(&data, 16, 16, &mut dest).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//! # Coordinate conversions
+//!
+//! The AprilTag library gives poses in the camera convention OpenCV uses: x right, y down and z forward out of the lens,
+//! with the tag frame x right, y down and z pointing into the tag.
+//!
+//! WPILib (and so all of the robot code) uses NWU instead: x forward, y left and z up,
+//! with a tag's x axis pointing straight out of its printed face.
+//!
+//! The functions in here convert between the two so the outputs can hand the robot poses it can use as is.
+use apriltag::TagParams;
+use nalgebra::{Isometry3, Matrix3, Rotation3, Translation3, UnitQuaternion, Vector3};
+
+use crate::process::TagPose;
+
+/// Change of basis from the camera frame (x right, y down, z forward) to NWU (x forward, y left, z up)
+pub fn camera_to_nwu() -> Matrix3<f64> {
+    Matrix3::new(
+        0.0, 0.0, 1.0, //
+        -1.0, 0.0, 0.0, //
+        0.0, -1.0, 0.0,
+    )
+}
+
+/// Rotation taking a WPILib tag frame (x out of the face, y left, z up) into the AprilTag tag frame
+pub fn wpilib_tag_to_apriltag() -> Matrix3<f64> {
+    Matrix3::new(
+        0.0, 1.0, 0.0, //
+        0.0, 0.0, -1.0, //
+        -1.0, 0.0, 0.0,
+    )
+}
+
+/// Transform from the camera to the tag in WPILib conventions, this is what PhotonLib calls `cameraToTarget`
+pub fn camera_to_tag(pose: &TagPose) -> Isometry3<f64> {
+    let nwu = camera_to_nwu();
+    let translation = nwu * Vector3::from(pose.translation);
+    let rotation = nwu * pose.rotation.matrix() * wpilib_tag_to_apriltag();
+    let rotation = Rotation3::from_matrix(&rotation);
+
+    Isometry3::from_parts(
+        Translation3::from(translation),
+        UnitQuaternion::from_rotation_matrix(&rotation),
+    )
+}
+
+/// Mean distance in pixels between the detected tag corners and the corners projected back through the pose.
+///
+/// The corners are in the order the AprilTag library gives them, which matches the object points its pose
+/// estimation uses.
+pub fn reprojection_error(pose: &TagPose, corners: &[[f64; 2]; 4], params: &TagParams) -> f64 {
+    let half = params.tagsize / 2.0;
+    let object_points = [[-half, half], [half, half], [half, -half], [-half, -half]];
+    let translation = Vector3::from(pose.translation);
+
+    let mut total = 0.0;
+    for ([x, y], [u, v]) in object_points.iter().zip(corners.iter()) {
+        // Behind the camera, this pose can't be right
+        let Some([projected_u, projected_v]) = project(&(pose.rotation * Vector3::new(*x, *y, 0.0) + translation), params)
+        else {
+            return f64::INFINITY;
+        };
+        total += ((projected_u - u).powi(2) + (projected_v - v).powi(2)).sqrt();
+    }
+    total / 4.0
+}
+
+/// Pixel a point in the camera frame lands on, `None` if it is behind the camera
+pub fn project(point: &Vector3<f64>, params: &TagParams) -> Option<[f64; 2]> {
+    if point.z <= 0.0 {
+        return None;
+    }
+    Some([
+        params.fx * point.x / point.z + params.cx,
+        params.fy * point.y / point.z + params.cy,
+    ])
+}
@@ -0,0 +1,151 @@
+//! Top-down map of the field with the tags, the estimated robot pose, its uncertainty and where it has been, for
+//! checking localization while walking the robot around.
+//!
+//! Drawn in field coordinates with the blue alliance wall on the left and +y up the screen, the way the field layout
+//! and WPILib's field widgets lay it out.
+use std::collections::VecDeque;
+
+use eframe::egui;
+use egui::{Align2, Color32, FontId, Pos2, Rect, Sense, Shape, Stroke, Vec2};
+use nalgebra::{Isometry3, Vector3};
+
+use crate::field::FieldLayout;
+use crate::process::VisionFrame;
+use crate::uncertainty::StdDevs;
+
+/// Most robot poses kept for the trail
+pub const TRAIL_LENGTH: usize = 300;
+
+/// How many standard deviations the uncertainty ellipse spans, 2 holds the pose about 86% of the time
+pub const ELLIPSE_SIGMAS: f64 = 2.0;
+
+const FIELD_COLOR: Color32 = Color32::from_gray(40);
+const LINE_COLOR: Color32 = Color32::from_gray(140);
+const TAG_COLOR: Color32 = Color32::from_rgb(255, 200, 0);
+const SEEN_TAG_COLOR: Color32 = Color32::from_rgb(0, 255, 0);
+const ROBOT_COLOR: Color32 = Color32::from_rgb(0, 160, 255);
+const TRAIL_COLOR: Color32 = Color32::from_rgba_premultiplied(0, 100, 160, 160);
+const ELLIPSE_COLOR: Color32 = Color32::from_rgb(255, 90, 90);
+
+pub struct FieldView {
+    layout: FieldLayout,
+    /// Recent robot positions on the field, oldest first
+    trail: VecDeque<[f64; 2]>,
+    /// Timestamp of the last result recorded, the same result is seen every frame until a new one comes in
+    last_timestamp: f64,
+}
+
+impl FieldView {
+    pub fn new(layout: FieldLayout) -> Self {
+        FieldView {
+            layout,
+            trail: VecDeque::with_capacity(TRAIL_LENGTH),
+            last_timestamp: f64::NAN,
+        }
+    }
+
+    /// Adds the robot pose of a new result to the trail
+    pub fn record(&mut self, results: &VisionFrame) {
+        if results.timestamp == self.last_timestamp {
+            return;
+        }
+        self.last_timestamp = results.timestamp;
+        let Some(pose) = &results.robot_pose else {
+            return;
+        };
+        if self.trail.len() == TRAIL_LENGTH {
+            self.trail.pop_front();
+        }
+        self.trail.push_back([pose.translation.x, pose.translation.y]);
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui, results: Option<&VisionFrame>) {
+        let robot = results.and_then(|results| Some((results.robot_pose?, results.robot_std_devs)));
+        ui.horizontal(|ui| {
+            ui.heading("Field");
+            match robot {
+                Some((pose, _)) => {
+                    let (_, _, heading) = pose.rotation.euler_angles();
+                    ui.label(format!(
+                        "x {:.2} m, y {:.2} m, heading {:.1}°",
+                        pose.translation.x,
+                        pose.translation.y,
+                        heading.to_degrees()
+                    ));
+                }
+                None => {
+                    ui.label("No robot pose");
+                }
+            }
+            if ui.button("Clear trail").clicked() {
+                self.trail.clear();
+            }
+        });
+
+        // Keep the field's shape in whatever space the panel has
+        let available = ui.available_size();
+        let scale = (available.x as f64 / self.layout.length).min(available.y as f64 / self.layout.width) as f32;
+        let size = Vec2::new(self.layout.length as f32, self.layout.width as f32) * scale;
+        let (response, painter) = ui.allocate_painter(size, Sense::hover());
+        let rect = response.rect;
+        let point = |x: f64, y: f64| Pos2::new(rect.left() + x as f32 * scale, rect.bottom() - y as f32 * scale);
+
+        painter.rect_filled(rect, 0.0, FIELD_COLOR);
+        painter.rect_stroke(rect, 0.0, Stroke::new(1.0, LINE_COLOR));
+        let middle = self.layout.length / 2.0;
+        painter.line_segment([point(middle, 0.0), point(middle, self.layout.width)], Stroke::new(1.0, LINE_COLOR));
+
+        let seen: Vec<u32> = results
+            .map(|results| results.targets.iter().map(|target| target.id).collect())
+            .unwrap_or_default();
+        for (id, tag) in self.layout.tags.iter() {
+            let color = if seen.contains(id) { SEEN_TAG_COLOR } else { TAG_COLOR };
+            let center = point(tag.translation.x, tag.translation.y);
+            // A tag's x axis points out of its face
+            let facing = tag.rotation * Vector3::x() * 0.4;
+            let face = point(tag.translation.x + facing.x, tag.translation.y + facing.y);
+            painter.rect_filled(Rect::from_center_size(center, Vec2::splat(6.0)), 0.0, color);
+            painter.line_segment([center, face], Stroke::new(1.5, color));
+            painter.text(center, Align2::CENTER_BOTTOM, id.to_string(), FontId::proportional(11.0), color);
+        }
+
+        let trail: Vec<Pos2> = self.trail.iter().map(|[x, y]| point(*x, *y)).collect();
+        if trail.len() > 1 {
+            painter.add(Shape::line(trail, Stroke::new(1.5, TRAIL_COLOR)));
+        }
+
+        if let Some((pose, std_devs)) = robot {
+            if let Some(std_devs) = std_devs {
+                painter.add(Shape::closed_line(
+                    ellipse(&pose, &std_devs).into_iter().map(|[x, y]| point(x, y)).collect(),
+                    Stroke::new(1.5, ELLIPSE_COLOR),
+                ));
+            }
+            draw_robot(&painter, &pose, &point);
+        }
+    }
+}
+
+/// Robot as a dot with a line out of its front
+fn draw_robot(painter: &egui::Painter, pose: &Isometry3<f64>, point: &impl Fn(f64, f64) -> Pos2) {
+    let center = point(pose.translation.x, pose.translation.y);
+    let front = pose.rotation * Vector3::x() * 0.5;
+    painter.circle_filled(center, 5.0, ROBOT_COLOR);
+    painter.line_segment(
+        [center, point(pose.translation.x + front.x, pose.translation.y + front.y)],
+        Stroke::new(2.0, ROBOT_COLOR),
+    );
+}
+
+/// Outline of the position uncertainty, the standard deviations are along the field axes
+fn ellipse(pose: &Isometry3<f64>, std_devs: &StdDevs) -> Vec<[f64; 2]> {
+    (0..32)
+        .map(|i| {
+            let angle = i as f64 / 32.0 * std::f64::consts::TAU;
+            [
+                pose.translation.x + ELLIPSE_SIGMAS * std_devs.x * angle.cos(),
+                pose.translation.y + ELLIPSE_SIGMAS * std_devs.y * angle.sin(),
+            ]
+        })
+        .collect()
+}
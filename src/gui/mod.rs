@@ -0,0 +1,222 @@
+use std::sync::Arc;
+
+use apriltag::TagParams;
+use eframe::egui;
+use egui::{Align2, Color32, ColorImage, FontId, Pos2, Rect, Stroke, TextureHandle, Vec2};
+use image::Rgb;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::field::FieldLayout;
+use crate::frame::Frame;
+use crate::geometry::camera_to_tag;
+use crate::mailbox::FrameReceiver;
+use crate::overlay;
+use crate::process::{TagTarget, VisionFrame};
+use crate::reload::LiveConfig;
+
+mod field;
+mod tuning;
+
+pub use field::FieldView;
+pub use tuning::Tuning;
+
+const QUAD_COLOR: Color32 = Color32::from_rgb(0, 255, 0);
+const CUBE_COLOR: Color32 = Color32::from_rgb(255, 0, 255);
+const ROI_COLOR: Color32 = Color32::from_rgb(255, 200, 0);
+
+pub struct VisionApp {
+    image: Option<ColorImage>,
+    texture: Option<TextureHandle>,
+    /// Peeks at the frames, so the process thread still gets every one of them
+    image_receiver: FrameReceiver<Frame>,
+    /// Latest results from the process thread
+    results: watch::Receiver<Option<Arc<VisionFrame>>>,
+    /// For the calibration the overlays are projected with
+    live_rx: watch::Receiver<LiveConfig>,
+    tuning: Tuning,
+    /// Only with a field layout to draw
+    field: Option<FieldView>,
+    shutdown: CancellationToken,
+}
+
+impl VisionApp {
+    pub fn new(
+        image_receiver: FrameReceiver<Frame>,
+        results: watch::Receiver<Option<Arc<VisionFrame>>>,
+        live_rx: watch::Receiver<LiveConfig>,
+        tuning: Tuning,
+        field: Option<FieldLayout>,
+        shutdown: CancellationToken,
+    ) -> VisionApp {
+        VisionApp {
+            image: None,
+            texture: None,
+            image_receiver,
+            results,
+            live_rx,
+            tuning,
+            field: field.map(FieldView::new),
+            shutdown,
+        }
+    }
+}
+
+impl eframe::App for VisionApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // Got a signal, close the window so main can shut everything down
+        if self.shutdown.is_cancelled() {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+
+        let results = self.results.borrow().clone();
+        let params = self.live_rx.borrow().calibration.tag_params();
+
+        egui::SidePanel::left("tuning").min_width(260.0).show(ctx, |ui| {
+            let running = results.as_deref().map(|results| results.pipeline_name.as_str());
+            self.tuning.show(ui, running);
+        });
+
+        if let Some(field) = self.field.as_mut() {
+            if let Some(results) = results.as_deref() {
+                field.record(results);
+            }
+            egui::TopBottomPanel::bottom("field").resizable(true).default_height(260.0).show(ctx, |ui| {
+                field.show(ui, results.as_deref());
+            });
+        }
+
+        egui::SidePanel::right("targets").min_width(220.0).show(ctx, |ui| {
+            target_list(ui, results.as_deref());
+        });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            // Only look at the latest frame, taking it would starve the process thread
+            // Color is only decoded here, the pipeline itself only needs the luma
+            if let Some(buffer) = self.image_receiver.peek().and_then(|frame| frame.to_rgb()) {
+                let size = [buffer.width() as _, buffer.height() as _];
+                let image = ColorImage::from_rgb(size, buffer.as_raw());
+                self.image = Some(image);
+            }
+
+            if let Some(frame) = self.image.take() {
+                self.texture = Some(ui.ctx().load_texture("frame", frame, Default::default()))
+            }
+
+            if let Some(texture) = self.texture.as_ref() {
+                // Keep the frame's shape so the overlays land on the tags
+                let available = ui.available_size();
+                let frame_size = texture.size_vec2();
+                let scale = (available.x / frame_size.x).min(available.y / frame_size.y);
+                let rect = ui.image((texture.id(), frame_size * scale)).rect;
+                if let Some(results) = results.as_deref() {
+                    draw_overlays(&ui.painter_at(rect), rect, results, &params);
+                }
+            } else {
+                ui.spinner();
+            }
+
+            ctx.request_repaint();
+        });
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Draws the region of interest and every target's quad, cube, axes, id and decision margin over the frame in `rect`
+fn draw_overlays(painter: &egui::Painter, rect: Rect, results: &VisionFrame, params: &TagParams) {
+    if results.width == 0 || results.height == 0 {
+        return;
+    }
+    let scale = Vec2::new(rect.width() / results.width as f32, rect.height() / results.height as f32);
+    let point = |[x, y]: [f64; 2]| rect.min + Vec2::new(x as f32, y as f32) * scale;
+
+    if let Some(roi) = results.roi {
+        let min = point([roi.x as f64, roi.y as f64]);
+        let max = point([(roi.x + roi.width) as f64, (roi.y + roi.height) as f64]);
+        painter.rect_stroke(Rect::from_min_max(min, max), 0.0, Stroke::new(1.5, ROI_COLOR));
+    }
+
+    for target in results.targets.iter() {
+        draw_target(painter, target, params, &point);
+    }
+}
+
+fn draw_target(painter: &egui::Painter, target: &TagTarget, params: &TagParams, point: &impl Fn([f64; 2]) -> Pos2) {
+    for (start, end) in overlay::cube_lines(target, params) {
+        painter.line_segment([point(start), point(end)], Stroke::new(1.0, CUBE_COLOR));
+    }
+    for i in 0..4 {
+        let edge = [point(target.corners[i]), point(target.corners[(i + 1) % 4])];
+        painter.line_segment(edge, Stroke::new(2.0, QUAD_COLOR));
+    }
+    for (start, end, color) in overlay::axis_lines(target, params) {
+        painter.line_segment([point(start), point(end)], Stroke::new(2.5, color32(color)));
+    }
+
+    let center = point(target.center);
+    painter.text(
+        center,
+        Align2::CENTER_BOTTOM,
+        format!("#{}", target.id),
+        FontId::proportional(16.0),
+        Color32::WHITE,
+    );
+    painter.text(
+        center,
+        Align2::CENTER_TOP,
+        format!("margin {:.0}", target.decision_margin),
+        FontId::proportional(12.0),
+        Color32::WHITE,
+    );
+}
+
+/// Every target's pose from the camera, in WPILib axes
+fn target_list(ui: &mut egui::Ui, results: Option<&VisionFrame>) {
+    ui.heading("Targets");
+    let Some(results) = results else {
+        ui.label("Waiting for results");
+        return;
+    };
+    ui.label(format!("Pipeline: {}", results.pipeline_name));
+    ui.label(format!("Latency: {:.1} ms", results.pipeline_latency));
+    if results.targets.is_empty() {
+        ui.label("No targets");
+    }
+
+    egui::ScrollArea::vertical().show(ui, |ui| {
+        for target in results.targets.iter() {
+            ui.separator();
+            ui.strong(format!("Tag {}", target.id));
+            let pose = camera_to_tag(&target.best);
+            let translation = pose.translation.vector;
+            let (roll, pitch, yaw) = pose.rotation.euler_angles();
+            egui::Grid::new(("target", target.id)).num_columns(2).show(ui, |ui| {
+                ui.label("Translation (m)");
+                ui.label(format!("{:.3}, {:.3}, {:.3}", translation.x, translation.y, translation.z));
+                ui.end_row();
+                ui.label("Roll, pitch, yaw (°)");
+                ui.label(format!("{:.1}, {:.1}, {:.1}", roll.to_degrees(), pitch.to_degrees(), yaw.to_degrees()));
+                ui.end_row();
+                ui.label("Distance (m)");
+                ui.label(format!("{:.3}", translation.norm()));
+                ui.end_row();
+                ui.label("Decision margin");
+                ui.label(format!("{:.1}", target.decision_margin));
+                ui.end_row();
+                ui.label("Ambiguity");
+                ui.label(format!("{:.3}", target.ambiguity));
+                ui.end_row();
+                ui.label("Reprojection (px)");
+                ui.label(format!("{:.2}", target.reprojection_error));
+                ui.end_row();
+            });
+        }
+    });
+}
+
+fn color32(Rgb([r, g, b]): Rgb<u8>) -> Color32 {
+    Color32::from_rgb(r, g, b)
+}
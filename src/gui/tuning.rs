@@ -0,0 +1,227 @@
+//! Sliders for the camera controls and detector settings of one pipeline.
+//!
+//! Changes go straight into the live config the capture and process threads already follow, so their effect shows
+//! up on the next frame. They only last until the next reload unless they are saved, which writes them into the
+//! config file through `reload::save_edit` like the dashboard does. Saving happens on its own thread so the window
+//! doesn't freeze on the file or on a reload saving at the same time.
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use eframe::egui;
+use egui::{Color32, ComboBox, Slider};
+use serde_json::{json, Value};
+use tokio::sync::watch;
+
+use crate::cli::Overrides;
+use crate::config::{AprilTagFamily, PipelineConfig, DEFAULT_PIPELINE};
+use crate::reload::{save_edit, LiveConfig};
+
+/// Shortest time between live config changes while a slider is dragged, every change restarts the tracker
+pub const TUNING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Most detector threads the slider goes to
+pub const MAX_THREADS: u8 = 16;
+
+pub struct Tuning {
+    live_tx: Arc<watch::Sender<LiveConfig>>,
+    config_path: PathBuf,
+    /// Applied before a save is checked, the same as on reload
+    overrides: Overrides,
+    /// Pipeline being tuned, the running one until another is picked
+    pipeline: Option<String>,
+    /// Change waiting for `TUNING_INTERVAL` to pass
+    pending: Option<(String, PipelineConfig)>,
+    last_sent: Instant,
+    unsaved: bool,
+    /// Outcome of the save running in the background
+    saving: Option<mpsc::Receiver<(bool, String)>>,
+    /// Outcome of the last save and whether it worked
+    status: Option<(bool, String)>,
+}
+
+impl Tuning {
+    pub fn new(live_tx: Arc<watch::Sender<LiveConfig>>, config_path: PathBuf, overrides: Overrides) -> Self {
+        Tuning {
+            live_tx,
+            config_path,
+            overrides,
+            pipeline: None,
+            pending: None,
+            last_sent: Instant::now(),
+            unsaved: false,
+            saving: None,
+            status: None,
+        }
+    }
+
+    /// Draws the panel, `running` is the pipeline the last result came from
+    pub fn show(&mut self, ui: &mut egui::Ui, running: Option<&str>) {
+        ui.heading("Tuning");
+
+        let (names, mut name, mut pipeline) = {
+            let live = self.live_tx.borrow();
+            let names: Vec<String> = live.pipelines.keys().cloned().collect();
+            let name = [self.pipeline.as_deref(), running, Some(live.active_pipeline.as_str())]
+                .into_iter()
+                .flatten()
+                .find(|name| live.pipelines.contains_key(*name))
+                .unwrap_or(DEFAULT_PIPELINE)
+                .to_string();
+            let pipeline = match &self.pending {
+                Some((pending, pipeline)) if *pending == name => pipeline.clone(),
+                _ => live.pipelines.get(&name).cloned().unwrap_or_default(),
+            };
+            (names, name, pipeline)
+        };
+
+        let selected = name.clone();
+        ComboBox::from_label("Pipeline").selected_text(name.clone()).show_ui(ui, |ui| {
+            for option in names {
+                ui.selectable_value(&mut name, option.clone(), option);
+            }
+        });
+        if name != selected {
+            self.flush();
+            self.pipeline = Some(name);
+            return;
+        }
+
+        let mut changed = false;
+        ui.separator();
+        ui.strong("Camera");
+        let controls = &mut pipeline.camera_controls;
+        changed |= ui.add(Slider::new(&mut controls.exposure, 0..=10000).logarithmic(true).text("Exposure")).changed();
+        changed |= ui.add(Slider::new(&mut controls.gain, 0..=255).text("Gain")).changed();
+        changed |= ui.add(Slider::new(&mut controls.brightness, 0..=255).text("Brightness")).changed();
+
+        ui.separator();
+        ui.strong("Detector");
+        let detection = &mut pipeline.detection_config;
+        let family = detection.families;
+        ComboBox::from_label("Family")
+            .selected_text(format!("{:?}", detection.families))
+            .show_ui(ui, |ui| {
+                for option in AprilTagFamily::ALL {
+                    ui.selectable_value(&mut detection.families, option, format!("{option:?}"));
+                }
+            });
+        changed |= detection.families != family;
+        let detector = &mut detection.detector;
+        changed |= ui.add(Slider::new(&mut detector.decimation, 1.0..=8.0).step_by(0.5).text("Decimation")).changed();
+        changed |= ui.add(Slider::new(&mut detector.sigma, 0.0..=4.0).text("Blur sigma")).changed();
+        changed |= ui.add(Slider::new(&mut detector.threads, 1..=MAX_THREADS).text("Threads")).changed();
+        changed |= ui
+            .add(Slider::new(&mut detector.min_decision_margin, 0.0..=200.0).text("Min decision margin"))
+            .changed();
+
+        if changed {
+            self.pending = Some((name.clone(), pipeline));
+            self.unsaved = true;
+            self.status = None;
+        }
+        if self.last_sent.elapsed() >= TUNING_INTERVAL {
+            self.flush();
+        }
+
+        self.poll_save();
+        ui.separator();
+        ui.horizontal(|ui| {
+            let can_save = self.unsaved && self.saving.is_none();
+            if ui.add_enabled(can_save, egui::Button::new("Save to config")).clicked() {
+                self.flush();
+                self.save(&name, ui.ctx().clone());
+            }
+            if self.saving.is_some() {
+                ui.spinner();
+            } else if self.unsaved {
+                ui.label("Unsaved, a reload will undo it");
+            }
+        });
+        if let Some((ok, message)) = &self.status {
+            let color = if *ok { Color32::LIGHT_GREEN } else { Color32::LIGHT_RED };
+            ui.colored_label(color, message);
+        }
+    }
+
+    /// Sends the pending change to the live config
+    fn flush(&mut self) {
+        let Some((name, pipeline)) = self.pending.take() else {
+            return;
+        };
+        self.live_tx.send_modify(|live| {
+            if let Some(live_pipeline) = live.pipelines.get_mut(&name) {
+                *live_pipeline = pipeline;
+            }
+        });
+        self.last_sent = Instant::now();
+    }
+
+    /// Writes the tuned settings of the pipeline into the config file on a thread of its own, `poll_save` picks up
+    /// how it went
+    fn save(&mut self, name: &str, ctx: egui::Context) {
+        let Some(pipeline) = self.live_tx.borrow().pipelines.get(name).cloned() else {
+            return;
+        };
+        let section = json!({
+            "camera_controls": pipeline.camera_controls,
+            "detection_config": {
+                "families": pipeline.detection_config.families,
+                "detector": pipeline.detection_config.detector,
+            },
+        });
+        // The default pipeline is the top of the config
+        let edit = match name {
+            DEFAULT_PIPELINE => section,
+            name => json!({ "pipelines": { name: section } }),
+        };
+        let Value::Object(edit) = edit else {
+            return;
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let config_path = self.config_path.clone();
+        let overrides = self.overrides.clone();
+        let spawned = thread::Builder::new().name("tuning-save".to_string()).spawn(move || {
+            let outcome = match save_edit(&config_path, &overrides, edit) {
+                Ok(warnings) => {
+                    let message = match warnings.len() {
+                        0 => format!("Saved to {}", config_path.display()),
+                        n => format!("Saved to {} with {} warning(s)", config_path.display(), n),
+                    };
+                    (true, message)
+                }
+                Err(err) => (false, err.to_string()),
+            };
+            let _ = tx.send(outcome);
+            ctx.request_repaint();
+        });
+        match spawned {
+            Ok(_) => {
+                // Changes made while it saves set this again
+                self.unsaved = false;
+                self.saving = Some(rx);
+                self.status = None;
+            }
+            Err(err) => self.status = Some((false, err.to_string())),
+        }
+    }
+
+    /// Takes the outcome of the background save once it is done
+    fn poll_save(&mut self) {
+        let Some(saving) = &self.saving else {
+            return;
+        };
+        let outcome = match saving.try_recv() {
+            Ok(outcome) => outcome,
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => (false, "Saving stopped before it finished".to_string()),
+        };
+        self.saving = None;
+        if !outcome.0 {
+            self.unsaved = true;
+        }
+        self.status = Some(outcome);
+    }
+}
@@ -0,0 +1,360 @@
+//! # Runtime health
+//!
+//! Keeps track of how well the pipeline is running so the driver station can tell 30 fps from 3 without a monitor
+//! plugged into the coprocessor.
+//!
+//! `HealthCounters` is shared with the threads that see failures happen (camera reconnects, frames that don't
+//! decode, outputs that fail to open or write). `HealthMonitor` sits on the comms task, sees every result on its way
+//! to the outputs and every `interface.health_interval` seconds sums them up into a `HealthStatus`, which goes out on
+//! every output alongside the results.
+//!
+//! The binary outputs (serial, TCP server and UDP) send it as a `HealthData` packet after the same sync bytes as
+//! `VisionData`. Its first byte is `HEALTH_DATA_VERSION`, which is how the robot tells the two apart.
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bondrewd::Bitfields;
+use serde::{Deserialize, Serialize};
+
+use crate::config::OutputKind;
+use crate::mailbox::FrameCounts;
+use crate::process::{StageLatency, VisionFrame};
+
+/// Where Linux lists its temperature sensors
+pub const THERMAL_PATH: &str = "/sys/class/thermal";
+
+/// Failures counted by the threads they happen on
+#[derive(Debug, Default)]
+pub struct HealthCounters {
+    camera_reconnects: AtomicU64,
+    decode_errors: AtomicU64,
+    link_errors: AtomicU64,
+    /// Link errors split up by output
+    output_errors: Mutex<Vec<(OutputKind, u64)>>,
+}
+
+impl HealthCounters {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// The camera stopped sending frames and was opened again
+    pub fn camera_reconnected(&self) {
+        self.camera_reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A frame from the camera couldn't be decoded
+    pub fn decode_failed(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An output failed to open or to write
+    pub fn link_failed(&self, kind: OutputKind) {
+        self.link_errors.fetch_add(1, Ordering::Relaxed);
+        let mut outputs = self.output_errors.lock().unwrap();
+        match outputs.iter_mut().find(|(output, _)| *output == kind) {
+            Some((_, count)) => *count += 1,
+            None => outputs.push((kind, 1)),
+        }
+    }
+
+    pub fn camera_reconnects(&self) -> u64 {
+        self.camera_reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn decode_errors(&self) -> u64 {
+        self.decode_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn link_errors(&self) -> u64 {
+        self.link_errors.load(Ordering::Relaxed)
+    }
+
+    /// Link errors of every output that has had any
+    pub fn output_errors(&self) -> Vec<(OutputKind, u64)> {
+        self.output_errors.lock().unwrap().clone()
+    }
+}
+
+/// How the pipeline has been running since the last status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthStatus {
+    /// When the status was made, in seconds since the Unix epoch
+    pub timestamp: f64,
+    /// Seconds since the pipeline started
+    pub uptime: f64,
+    /// Frames processed per second
+    pub fps: f64,
+    /// Frames the camera captured that were replaced before the process thread got to them
+    pub dropped_frames: u64,
+    /// Fraction of processed frames with at least one target
+    pub detection_rate: f64,
+    /// Mean milliseconds each stage took
+    pub stages: StageLatency,
+    /// Mean milliseconds from the process thread getting a frame to its result being ready
+    pub pipeline_latency: f64,
+    /// Hottest CPU temperature in degrees Celsius, `None` where there is no sensor to read
+    pub cpu_temperature: Option<f64>,
+    /// Times the camera had to be opened again since the pipeline started
+    pub camera_reconnects: u64,
+    /// Camera frames that couldn't be decoded since the pipeline started
+    pub decode_errors: u64,
+    /// Output opens and writes that failed since the pipeline started
+    pub link_errors: u64,
+    /// Pipeline that processed the last frame
+    pub pipeline_name: String,
+}
+
+impl HealthStatus {
+    /// Packs the status into the `HealthData` packet the robot reads
+    pub fn health_data(&self) -> HealthData {
+        HealthData {
+            version: HEALTH_DATA_VERSION,
+            timestamp: self.timestamp,
+            fps: self.fps,
+            detection_rate: self.detection_rate,
+            pipeline_latency: self.pipeline_latency,
+            cpu_temperature: self.cpu_temperature.unwrap_or(f64::NAN),
+            uptime: self.uptime,
+            dropped_frames: self.dropped_frames.min(u32::MAX as u64) as u32,
+            camera_reconnects: self.camera_reconnects.min(u16::MAX as u64) as u16,
+            link_errors: self.link_errors.min(u16::MAX as u64) as u16,
+        }
+    }
+}
+
+/// First byte of every `HealthData`, the high bit marks a health packet (`VISION_DATA_VERSION` stays below it) and
+/// the rest is the layout version, bumped whenever the fields below change
+pub const HEALTH_DATA_VERSION: u8 = 0x81;
+
+/// `HealthStatus` as sent over the binary outputs, after the sync bytes.
+///
+/// 57 bytes, little endian:
+///
+/// | Offset | Field | Type | |
+/// |---|---|---|---|
+/// | 0 | `version` | u8 | `HEALTH_DATA_VERSION` |
+/// | 1 | `timestamp` | f64 | Seconds since the Unix epoch on the coprocessor |
+/// | 9 | `fps` | f64 | |
+/// | 17 | `detection_rate` | f64 | Share of frames with a target, 0 to 1 |
+/// | 25 | `pipeline_latency` | f64 | Milliseconds |
+/// | 33 | `cpu_temperature` | f64 | Celsius |
+/// | 41 | `uptime` | f64 | Seconds |
+/// | 49 | `dropped_frames` | u32 | |
+/// | 53 | `camera_reconnects` | u16 | |
+/// | 55 | `link_errors` | u16 | |
+#[derive(Debug, Clone, Bitfields, Serialize)]
+#[bondrewd(default_endianness = "le", enforce_bytes = 57)]
+pub struct HealthData {
+    pub version: u8,
+    pub timestamp: f64,
+    pub fps: f64,
+    pub detection_rate: f64,
+    pub pipeline_latency: f64,
+    /// NaN where there is no sensor to read
+    pub cpu_temperature: f64,
+    pub uptime: f64,
+    pub dropped_frames: u32,
+    pub camera_reconnects: u16,
+    pub link_errors: u16,
+}
+
+/// Sums up every result between statuses
+pub struct HealthMonitor {
+    counters: Arc<HealthCounters>,
+    started: Instant,
+    window_start: Instant,
+    /// Mailbox counts at the start of the window
+    window_counts: Option<FrameCounts>,
+    last_counts: Option<FrameCounts>,
+    frames: u32,
+    frames_with_targets: u32,
+    stages: StageLatency,
+    pipeline_latency: f64,
+    pipeline_name: String,
+}
+
+impl HealthMonitor {
+    pub fn new(counters: Arc<HealthCounters>) -> Self {
+        let now = Instant::now();
+        HealthMonitor {
+            counters,
+            started: now,
+            window_start: now,
+            window_counts: None,
+            last_counts: None,
+            frames: 0,
+            frames_with_targets: 0,
+            stages: StageLatency::default(),
+            pipeline_latency: 0.0,
+            pipeline_name: String::new(),
+        }
+    }
+
+    /// Adds a result to the current window
+    pub fn record(&mut self, frame: &VisionFrame) {
+        if self.window_counts.is_none() {
+            self.window_counts = self.last_counts.or(Some(frame.frames));
+        }
+        self.last_counts = Some(frame.frames);
+        self.frames += 1;
+        if !frame.targets.is_empty() {
+            self.frames_with_targets += 1;
+        }
+        self.stages.preprocess += frame.stages.preprocess;
+        self.stages.detection += frame.stages.detection;
+        self.stages.pose += frame.stages.pose;
+        self.pipeline_latency += frame.pipeline_latency;
+        if self.pipeline_name != frame.pipeline_name {
+            self.pipeline_name = frame.pipeline_name.clone();
+        }
+    }
+
+    /// Sums up the window and starts the next one
+    pub fn report(&mut self) -> HealthStatus {
+        let now = Instant::now();
+        let elapsed = (now - self.window_start).as_secs_f64();
+        let frames = self.frames.max(1) as f64;
+        let dropped_frames = match (self.window_counts, self.last_counts) {
+            (Some(start), Some(end)) => end.dropped.saturating_sub(start.dropped),
+            _ => 0,
+        };
+
+        let status = HealthStatus {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::ZERO)
+                .as_secs_f64(),
+            uptime: (now - self.started).as_secs_f64(),
+            fps: if elapsed > 0.0 { self.frames as f64 / elapsed } else { 0.0 },
+            dropped_frames,
+            detection_rate: self.frames_with_targets as f64 / frames,
+            stages: StageLatency {
+                preprocess: self.stages.preprocess / frames,
+                detection: self.stages.detection / frames,
+                pose: self.stages.pose / frames,
+            },
+            pipeline_latency: self.pipeline_latency / frames,
+            cpu_temperature: cpu_temperature(),
+            camera_reconnects: self.counters.camera_reconnects(),
+            decode_errors: self.counters.decode_errors(),
+            link_errors: self.counters.link_errors(),
+            pipeline_name: self.pipeline_name.clone(),
+        };
+
+        self.window_start = now;
+        self.window_counts = None;
+        self.frames = 0;
+        self.frames_with_targets = 0;
+        self.stages = StageLatency::default();
+        self.pipeline_latency = 0.0;
+        status
+    }
+}
+
+/// Hottest CPU sensor, or the hottest sensor of any kind when none of them say they are for the CPU
+pub fn cpu_temperature() -> Option<f64> {
+    let mut cpu = None;
+    let mut any = None;
+    for entry in std::fs::read_dir(THERMAL_PATH).ok()?.flatten() {
+        let path = entry.path();
+        let Some(celsius) = read_millidegrees(&path.join("temp")) else {
+            continue;
+        };
+        let kind = std::fs::read_to_string(path.join("type")).unwrap_or_default().to_lowercase();
+        // `cpu-thermal` on a Pi, `x86_pkg_temp` on Intel, `soc-thermal` on most ARM boards
+        if ["cpu", "pkg", "soc"].iter().any(|name| kind.contains(name)) {
+            cpu = Some(f64::max(cpu.unwrap_or(f64::MIN), celsius));
+        }
+        any = Some(f64::max(any.unwrap_or(f64::MIN), celsius));
+    }
+    cpu.or(any)
+}
+
+fn read_millidegrees(path: &Path) -> Option<f64> {
+    let millidegrees: f64 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    Some(millidegrees / 1000.0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::process::tests::sample_frame;
+
+    /// A status with every field set to something different
+    pub(crate) fn sample_status() -> HealthStatus {
+        HealthStatus {
+            timestamp: 1_700_000_000.5,
+            uptime: 120.0,
+            fps: 30.0,
+            dropped_frames: 12,
+            detection_rate: 0.75,
+            stages: StageLatency::default(),
+            pipeline_latency: 8.5,
+            cpu_temperature: Some(55.0),
+            camera_reconnects: 1,
+            decode_errors: 2,
+            link_errors: 3,
+            pipeline_name: "default".to_string(),
+        }
+    }
+
+    #[test]
+    fn packs_health_data() {
+        let bytes = sample_status().health_data().into_bytes();
+        assert_eq!(bytes.len(), 57);
+        assert_ne!(bytes[0], crate::process::VISION_DATA_VERSION);
+        let mut expected = vec![HEALTH_DATA_VERSION];
+        for value in [1_700_000_000.5, 30.0, 0.75, 8.5, 55.0, 120.0] {
+            expected.extend_from_slice(&f64::to_le_bytes(value));
+        }
+        expected.extend_from_slice(&12u32.to_le_bytes());
+        expected.extend_from_slice(&1u16.to_le_bytes());
+        expected.extend_from_slice(&3u16.to_le_bytes());
+        assert_eq!(bytes[..], expected[..]);
+    }
+
+    #[test]
+    fn health_data_saturates() {
+        let status = HealthStatus {
+            cpu_temperature: None,
+            dropped_frames: u64::MAX,
+            camera_reconnects: 70_000,
+            link_errors: 70_000,
+            ..sample_status()
+        };
+        let data = status.health_data();
+        assert!(data.cpu_temperature.is_nan());
+        assert_eq!(data.dropped_frames, u32::MAX);
+        assert_eq!(data.camera_reconnects, u16::MAX);
+        assert_eq!(data.link_errors, u16::MAX);
+    }
+
+    #[test]
+    fn report_sums_up_the_window() {
+        let counters = HealthCounters::new();
+        let mut monitor = HealthMonitor::new(counters.clone());
+        let mut frame = sample_frame();
+        frame.frames = FrameCounts { captured: 10, processed: 8, dropped: 2 };
+        monitor.record(&frame);
+        frame.targets.clear();
+        frame.pipeline_latency = 4.0;
+        frame.frames = FrameCounts { captured: 20, processed: 14, dropped: 6 };
+        monitor.record(&frame);
+        counters.link_failed(OutputKind::Udp);
+
+        let status = monitor.report();
+        assert_eq!(status.detection_rate, 0.5);
+        assert_eq!(status.pipeline_latency, 6.0);
+        assert_eq!(status.dropped_frames, 4);
+        assert_eq!(status.link_errors, 1);
+        assert_eq!(status.pipeline_name, "default");
+
+        // The next window starts from where this one ended
+        frame.frames = FrameCounts { captured: 25, processed: 18, dropped: 7 };
+        monitor.record(&frame);
+        assert_eq!(monitor.report().dropped_frames, 1);
+    }
+}
@@ -0,0 +1,261 @@
+//! # Tiny HTTP server
+//!
+//! Just enough HTTP/1.1 for the few pages the coprocessor serves to a browser or a scraper on the robot network:
+//! every connection gets one request and is closed once it has been answered. Pulling in a full web framework for that
+//! isn't worth the build time on the coprocessor.
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::interface::DataError;
+
+/// How long a client gets to send its request before it is hung up on
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest request head read, anything bigger isn't one of ours
+pub const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// Longest request body read, a whole config fits many times over
+pub const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Wait after a connection couldn't be accepted, they fail when the process is out of file descriptors and trying
+/// again straight away would only spin
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The parts of a request the handlers look at
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query string
+    pub path: String,
+    /// Headers handlers that take edits check where they came from with
+    pub content_type: Option<String>,
+    /// Sent by browsers with the site of the page that made the request
+    pub origin: Option<String>,
+    pub host: Option<String>,
+    /// Empty unless the request had a `Content-Length`
+    pub body: Vec<u8>,
+}
+
+/// A complete response
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status: 200,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    /// A JSON body
+    pub fn json(status: u16, body: &serde_json::Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Response {
+            status: 404,
+            content_type: "text/plain; charset=utf-8",
+            body: b"Not Found\n".to_vec(),
+        }
+    }
+
+    pub fn method_not_allowed() -> Self {
+        Response {
+            status: 405,
+            content_type: "text/plain; charset=utf-8",
+            body: b"Method Not Allowed\n".to_vec(),
+        }
+    }
+
+    /// Writes the status line, headers and body
+    pub async fn write(&self, stream: &mut TcpStream) -> std::io::Result<()> {
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&self.body).await?;
+        stream.flush().await
+    }
+}
+
+/// Accepts connections on `port` until shutdown, handing every request to `handler` on its own task.
+///
+/// The handler gets the stream so it can keep writing (like a video stream does), it is closed once the handler
+/// returns. Handlers answer methods they don't take with `Response::method_not_allowed`.
+pub async fn serve<H, F>(name: &'static str, port: u16, handler: H, shutdown: CancellationToken) -> Result<(), DataError>
+where
+    H: Fn(Request, TcpStream) -> F + Clone + Send + 'static,
+    F: Future<Output = std::io::Result<()>> + Send + 'static,
+{
+    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port)))
+        .await
+        .map_err(DataError::ServerCreationFailed)?;
+    info!("{} Server Listening on Port {}!", name, port);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        let mut stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("{} Server failed to accept a connection [{}]", name, err);
+                tokio::select! {
+                    _ = tokio::time::sleep(ACCEPT_BACKOFF) => continue,
+                    _ = shutdown.cancelled() => return Ok(()),
+                }
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+                Ok(Some(request)) => request,
+                _ => return,
+            };
+            // Most of these are the browser going away mid stream, which is nothing to report
+            let _ = handler(request, stream).await;
+        });
+    }
+}
+
+/// Reads the request line, the headers in `Request` and the body, `None` if it isn't HTTP
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> Option<Request> {
+    // Nothing past the biggest head and body we'd take is read, however long a line runs
+    let mut reader = BufReader::new(stream.take((MAX_REQUEST_BYTES + MAX_BODY_BYTES) as u64));
+    let mut line = String::new();
+    reader.read_line(&mut line).await.ok()?;
+    if line.len() > MAX_REQUEST_BYTES {
+        return None;
+    }
+
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?;
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    let path = target.split('?').next().unwrap_or(target);
+
+    let mut read = line.len();
+    let mut content_length = 0;
+    let (mut content_type, mut origin, mut host) = (None, None, None);
+    loop {
+        let mut header = String::new();
+        let n = reader.read_line(&mut header).await.ok()?;
+        read += n;
+        if read > MAX_REQUEST_BYTES {
+            return None;
+        }
+        if n == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse().ok()?,
+                "content-type" => content_type = Some(value.to_string()),
+                "origin" => origin = Some(value.to_string()),
+                "host" => host = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        return None;
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await.ok()?;
+
+    Some(Request {
+        method,
+        path: path.to_string(),
+        content_type,
+        origin,
+        host,
+        body,
+    })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(request: &str) -> Option<Request> {
+        read_request(&mut request.as_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn reads_a_request() {
+        let request = parse(
+            "POST /api/config?x=1 HTTP/1.1\r\nHost: 10.0.0.5:5803\r\nContent-Type: application/json\r\n\
+             origin: http://10.0.0.5:5803\r\nContent-Length: 2\r\n\r\n{}",
+        )
+        .await
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/config");
+        assert_eq!(request.content_type.as_deref(), Some("application/json"));
+        assert_eq!(request.origin.as_deref(), Some("http://10.0.0.5:5803"));
+        assert_eq!(request.host.as_deref(), Some("10.0.0.5:5803"));
+        assert_eq!(request.body, b"{}");
+    }
+
+    #[tokio::test]
+    async fn turns_away_what_isnt_http() {
+        assert!(parse("hello\r\n\r\n").await.is_none());
+        assert!(parse("GET / SSH-2.0\r\n\r\n").await.is_none());
+        let too_long = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_BYTES + 1);
+        assert!(parse(&too_long).await.is_none());
+        // Shorter than it said
+        assert!(parse("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stops_reading_long_lines() {
+        let long_path = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_REQUEST_BYTES));
+        assert!(parse(&long_path).await.is_none());
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_REQUEST_BYTES));
+        assert!(parse(&long_header).await.is_none());
+
+        // A line that never ends is only read up to the limit
+        let endless = "a".repeat(1024 * 1024);
+        let mut rest = endless.as_bytes();
+        assert!(read_request(&mut rest).await.is_none());
+        assert_eq!(endless.len() - rest.len(), MAX_REQUEST_BYTES + MAX_BODY_BYTES);
+    }
+}
@@ -32,7 +32,8 @@
 //!                             tag.id() as u64,
 //!                             0.0,
 //!                             [rotation.0, rotation.1, rotation.2],
-//!                             [transform.x, transform.y, transform.z]
+//!                             [transform.x, transform.y, transform.z],
+//!                             [0.02, 0.02, 0.05]
 //!    )).await?;
 //!   // Read a response from the serial port
 //!   let response = data.read_frame().await?;
@@ -52,8 +53,10 @@ use tokio_serial::{DataBits, FlowControl, Parity, SerialPortBuilderExt, StopBits
 use tokio_util::bytes::{Buf, Bytes, BytesMut};
 
 use thiserror::Error;
+use tracing::{info, trace};
 
 use futures::{future, SinkExt, StreamExt, TryStreamExt};
+use crate::health::HealthData;
 use crate::process::VisionData;
 use crate::InterfaceConfig;
 
@@ -79,6 +82,19 @@ pub enum DataError {
     /// No response error
     #[error("No response")]
     NoResponse,
+    /// Invalid address error
+    #[error("Invalid Address: {0}")]
+    AddrParse(#[from] std::net::AddrParseError),
+    /// JSON encoding error
+    #[error("JSON Error: {0}")]
+    Json(#[from] serde_json::Error),
+    /// Output not available in this build
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+    /// NetworkTables error
+    #[cfg(feature = "nt")]
+    #[error("NetworkTables Error: {0}")]
+    NetworkTables(#[from] network_tables::Error),
 }
 
 /// The synchronization bytes to append to the beginning of every `VisionData` packet.
@@ -146,10 +162,9 @@ pub async fn start_tcp_server(config: &InterfaceConfig) -> Result<DataInterface,
     let ip = SocketAddr::from(([0,0,0,0], config.server_port));
     match TcpListener::bind(&ip).await {
         Ok(listener) => {
-            println!("Listener Started!");
-            let (stream, addr) = listener.accept().await.unwrap();
-            println!("{}", addr.to_string());
-            println!("Found Connection to Server!");
+            info!("Listener Started!");
+            let (stream, addr) = listener.accept().await?;
+            info!("Found Connection to Server! [{}]", addr);
             Ok(DataInterface::new(Box::new(stream)))
         }
 
@@ -266,8 +281,18 @@ impl DataInterface {
     /// Writes a VisionData packet to the data interface.
     pub async fn write_vision_data(&mut self, data: VisionData) -> Result<(), DataError> {
         let bytes = data.into_bytes();
-        println!("Bytes: {:X?}", bytes);
+        trace!("Bytes: {:X?}", bytes);
         self.write_bytes(&bytes).await
     }
+
+    /// Writes a HealthData packet to the data interface.
+    pub async fn write_health_data(&mut self, data: HealthData) -> Result<(), DataError> {
+        self.write_bytes(&data.into_bytes()).await
+    }
+
+    /// Flushes anything left to write and shuts down the underlying stream.
+    pub async fn close(&mut self) -> Result<(), DataError> {
+        SinkExt::<Bytes>::close(&mut self.framed).await
+    }
 }
 // --- Implementation of DataInterface ---
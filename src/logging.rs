@@ -0,0 +1,147 @@
+//! # Logging
+//!
+//! Everything logs through `tracing` with a level, so the console only shows what matters while running and more can
+//! be turned on for a single module when chasing something down. The filter is built from `logging.level` and
+//! `logging.modules` in the config, with `RUST_LOG` taking over completely when it is set.
+//!
+//! Logging starts at `info` before the config is loaded so loading problems show up, then `apply` switches it to
+//! the config. Hot reload calls `apply` again, so levels can be changed while the pipeline runs.
+//!
+//! With `logging.directory` set the log also goes to `vision.<date>.log` files there, written from a background
+//! thread so a slow SD card never holds up a frame, and rotated every `logging.rotation`.
+//!
+//! The process thread wraps each frame in a `frame` span with a span for each stage inside it, which show up at
+//! `trace` along with the packets the binary outputs send.
+//!
+//! The one-off commands (`bench`, `check-config` and the like) still print their results straight to the console,
+//! that is their output rather than a log.
+use std::sync::Mutex;
+
+use tracing::warn;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::format::{DefaultFields, Format};
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+use crate::config::{LogRotation, LoggingConfig};
+
+/// Log file names are `<prefix>.<date>.<suffix>`
+pub const LOG_FILE_PREFIX: &str = "vision";
+pub const LOG_FILE_SUFFIX: &str = "log";
+
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+type FileLayer = fmt::Layer<Filtered, DefaultFields, Format, NonBlocking>;
+
+/// Lets the filter and log file be changed after startup
+pub struct Logging {
+    filter: reload::Handle<EnvFilter, Registry>,
+    file: reload::Handle<Option<FileLayer>, Filtered>,
+    /// Flushes the file when dropped, so this has to live as long as the file is logged to
+    guard: Mutex<Option<WorkerGuard>>,
+    /// Config that was last applied, to skip rebuilding the file when nothing about it changed
+    applied: Mutex<Option<LoggingConfig>>,
+}
+
+/// Starts logging to the console at `level`, or what `RUST_LOG` says
+pub fn init(level: Option<&str>) -> Logging {
+    let (filter, filter_handle) = reload::Layer::new(env_filter(&LoggingConfig {
+        level: level.unwrap_or("info").to_string(),
+        ..LoggingConfig::default()
+    }));
+    let (file, file_handle) = reload::Layer::new(None);
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(file)
+        .with(fmt::layer().with_target(true))
+        .init();
+
+    Logging {
+        filter: filter_handle,
+        file: file_handle,
+        guard: Mutex::new(None),
+        applied: Mutex::new(None),
+    }
+}
+
+impl Logging {
+    /// Switches to the levels and log file in the config
+    pub fn apply(&self, config: &LoggingConfig) {
+        let mut applied = self.applied.lock().unwrap();
+        if applied.as_ref() == Some(config) {
+            return;
+        }
+
+        if let Err(err) = self.filter.reload(env_filter(config)) {
+            warn!("Failed to change log levels: {}", err);
+        }
+
+        let file_changed = !applied.as_ref().is_some_and(|applied| {
+            (&applied.directory, applied.rotation, applied.max_files)
+                == (&config.directory, config.rotation, config.max_files)
+        });
+        if file_changed {
+            let (layer, guard) = match file_layer(config) {
+                Some((layer, guard)) => (Some(layer), Some(guard)),
+                None => (None, None),
+            };
+            if let Err(err) = self.file.reload(layer) {
+                warn!("Failed to change the log file: {}", err);
+            }
+            // The old writer flushes as its guard drops
+            *self.guard.lock().unwrap() = guard;
+        }
+
+        *applied = Some(config.clone());
+    }
+
+    /// Writes out anything still waiting to go to the log file, for when the program can't exit normally
+    pub fn flush(&self) {
+        self.guard.lock().unwrap().take();
+    }
+}
+
+/// The filter for the config, unless `RUST_LOG` is set
+fn env_filter(config: &LoggingConfig) -> EnvFilter {
+    if let Ok(filter) = EnvFilter::try_from_default_env() {
+        return filter;
+    }
+    let mut directives = config.level.to_lowercase();
+    for (module, level) in config.modules.iter() {
+        directives.push_str(&format!(",{}={}", module, level.to_lowercase()));
+    }
+    EnvFilter::try_new(&directives).unwrap_or_else(|err| {
+        warn!("Bad log levels `{}` [{}], using info", directives, err);
+        EnvFilter::new("info")
+    })
+}
+
+/// A layer writing to rotating files in the configured directory, `None` without one
+fn file_layer(config: &LoggingConfig) -> Option<(FileLayer, WorkerGuard)> {
+    let directory = config.directory.as_ref()?;
+    let rotation = match config.rotation {
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix(LOG_FILE_SUFFIX);
+    if config.max_files > 0 {
+        builder = builder.max_log_files(config.max_files);
+    }
+    let appender = match builder.build(directory) {
+        Ok(appender) => appender,
+        Err(err) => {
+            warn!("Can't log to {} [{}]", directory, err);
+            return None;
+        }
+    };
+
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+    let layer = fmt::layer().with_ansi(false).with_writer(writer);
+    Some((layer, guard))
+}
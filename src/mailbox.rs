@@ -0,0 +1,228 @@
+//! # Latest frame mailbox
+//!
+//! Hands frames from the camera to the process thread so that the process thread always gets the freshest one.
+//!
+//! The mailbox holds a single frame. Putting a frame in never blocks, it replaces whatever is there, and if the
+//! frame it replaces was never taken it gets counted as dropped. Taking a frame waits for one newer than the last
+//! frame taken. Anything else that wants to look at the frames (the GUI, the stream) can `peek` at the latest one
+//! without taking it, so it doesn't steal frames from the process thread.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+/// How many frames went through the mailbox
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct FrameCounts {
+    /// Frames the camera put in
+    pub captured: u64,
+    /// Frames the process thread took out
+    pub processed: u64,
+    /// Frames replaced by a newer one before anything took them
+    pub dropped: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    captured: AtomicU64,
+    processed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+struct Slot<T> {
+    frame: Option<Arc<T>>,
+    /// Sequence number of the frame in the slot, 0 before the first one
+    seq: u64,
+    /// Whether the frame in the slot has been taken
+    taken: bool,
+    /// Set once the sender is gone
+    closed: bool,
+}
+
+struct Shared<T> {
+    slot: Mutex<Slot<T>>,
+    ready: Condvar,
+    counters: Counters,
+}
+
+/// Creates an empty mailbox
+pub fn mailbox<T>() -> (FrameSender<T>, FrameReceiver<T>) {
+    let shared = Arc::new(Shared {
+        slot: Mutex::new(Slot {
+            frame: None,
+            seq: 0,
+            taken: true,
+            closed: false,
+        }),
+        ready: Condvar::new(),
+        counters: Counters::default(),
+    });
+    (
+        FrameSender {
+            shared: shared.clone(),
+        },
+        FrameReceiver { shared, last_seq: 0 },
+    )
+}
+
+// --- Implementation of FrameSender ---
+/// The camera side of the mailbox, closes the mailbox when dropped
+pub struct FrameSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> FrameSender<T> {
+    /// Puts a frame in the mailbox, replacing the one there
+    pub fn send(&self, frame: T) {
+        let counters = &self.shared.counters;
+        counters.captured.fetch_add(1, Ordering::Relaxed);
+
+        let mut slot = self.shared.slot.lock().unwrap();
+        if !slot.taken {
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        slot.frame = Some(Arc::new(frame));
+        slot.seq += 1;
+        slot.taken = false;
+        drop(slot);
+        self.shared.ready.notify_all();
+    }
+}
+
+impl<T> Drop for FrameSender<T> {
+    fn drop(&mut self) {
+        self.shared.slot.lock().unwrap().closed = true;
+        self.shared.ready.notify_all();
+    }
+}
+// --- Implementation of FrameSender ---
+
+// --- Implementation of FrameReceiver ---
+/// The reading side of the mailbox, every clone keeps track of the last frame it saw on its own
+pub struct FrameReceiver<T> {
+    shared: Arc<Shared<T>>,
+    last_seq: u64,
+}
+
+impl<T> Clone for FrameReceiver<T> {
+    fn clone(&self) -> Self {
+        FrameReceiver {
+            shared: self.shared.clone(),
+            last_seq: self.last_seq,
+        }
+    }
+}
+
+impl<T> FrameReceiver<T> {
+    /// Takes the latest frame, waiting for one newer than the last one this receiver got.
+    ///
+    /// Returns `None` once the sender is gone and there is nothing new left.
+    pub fn recv(&mut self) -> Option<Arc<T>> {
+        let shared = &self.shared;
+        let mut slot = shared.slot.lock().unwrap();
+        while slot.seq == self.last_seq && !slot.closed {
+            slot = shared.ready.wait(slot).unwrap();
+        }
+        if slot.seq == self.last_seq {
+            return None;
+        }
+        self.last_seq = slot.seq;
+        if !slot.taken {
+            slot.taken = true;
+            shared.counters.processed.fetch_add(1, Ordering::Relaxed);
+        }
+        slot.frame.clone()
+    }
+
+    /// The latest frame if it is newer than the last one this receiver saw, without taking it.
+    ///
+    /// Frames seen this way still count as dropped if nothing takes them.
+    pub fn peek(&mut self) -> Option<Arc<T>> {
+        let slot = self.shared.slot.lock().unwrap();
+        if slot.seq == self.last_seq {
+            return None;
+        }
+        self.last_seq = slot.seq;
+        slot.frame.clone()
+    }
+
+    /// Frame counts so far
+    pub fn counts(&self) -> FrameCounts {
+        let counters = &self.shared.counters;
+        FrameCounts {
+            captured: counters.captured.load(Ordering::Relaxed),
+            processed: counters.processed.load(Ordering::Relaxed),
+            dropped: counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+// --- Implementation of FrameReceiver ---
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn replaced_frame_counts_as_dropped() {
+        let (tx, mut rx) = mailbox();
+        tx.send(1);
+        tx.send(2);
+        assert_eq!(rx.recv().as_deref(), Some(&2));
+        tx.send(3);
+        assert_eq!(rx.recv().as_deref(), Some(&3));
+        let counts = rx.counts();
+        assert_eq!((counts.captured, counts.processed, counts.dropped), (3, 2, 1));
+    }
+
+    #[test]
+    fn recv_waits_for_a_newer_frame() {
+        let (tx, mut rx) = mailbox();
+        tx.send(1);
+        assert_eq!(rx.recv().as_deref(), Some(&1));
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            tx.send(2);
+            tx
+        });
+        // Doesn't get the same frame twice
+        assert_eq!(rx.recv().as_deref(), Some(&2));
+        drop(sender.join().unwrap());
+    }
+
+    #[test]
+    fn peek_doesnt_take() {
+        let (tx, mut rx) = mailbox();
+        let mut viewer = rx.clone();
+        tx.send(1);
+        assert_eq!(viewer.peek().as_deref(), Some(&1));
+        // Already seen by this receiver
+        assert_eq!(viewer.peek(), None);
+        assert_eq!(rx.recv().as_deref(), Some(&1));
+        assert_eq!(rx.counts().processed, 1);
+        assert_eq!(rx.counts().dropped, 0);
+    }
+
+    #[test]
+    fn recv_ends_once_the_sender_is_gone() {
+        let (tx, mut rx) = mailbox();
+        tx.send(1);
+        drop(tx);
+        // What was left in the slot still comes out
+        assert_eq!(rx.recv().as_deref(), Some(&1));
+        assert_eq!(rx.recv(), None);
+    }
+
+    #[test]
+    fn recv_wakes_when_the_sender_goes() {
+        let (tx, mut rx) = mailbox::<u32>();
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(tx);
+        });
+        assert_eq!(rx.recv(), None);
+        sender.join().unwrap();
+    }
+}
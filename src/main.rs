@@ -1,17 +1,59 @@
 use crate::camera::Camera;
-use crate::interface::*;
-use crate::process::VisionData;
+use crate::cli::{Cli, Command, Overrides};
+use crate::dashboard::Dashboard;
+use crate::health::{HealthCounters, HealthMonitor, HealthStatus};
+use crate::logging::Logging;
+use crate::metrics::Metrics;
+use crate::process::VisionFrame;
+use crate::sink::Sinks;
 use config::*;
-use image::DynamicImage;
+use field::FieldLayout;
+use frame::Frame;
+use mailbox::mailbox;
 // use nokhwa::query;
 use process::Process;
+use reload::{live_config_channel, LiveConfig};
+use robot::robot_state_channel;
+use clap::Parser;
 use std::env;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use tokio::runtime::Handle;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
 mod camera;
+mod cli;
+mod commands;
 mod config;
+mod dashboard;
+mod field;
+mod frame;
+mod geometry;
+mod health;
+mod http;
 mod process;
 mod interface;
+mod logging;
+mod mailbox;
+mod metrics;
+mod overlay;
+mod reload;
+mod robot;
+mod roi;
+mod shutdown;
+mod sink;
+mod stream;
+mod synthetic;
+mod tracking;
+mod uncertainty;
+
+#[cfg(feature = "nt")]
+mod nt;
 
 #[cfg(feature = "gui")]
 mod gui;
@@ -19,134 +61,423 @@ mod gui;
 pub const CAL_FILE_NAME: &str = "configs/cam-cal.json";
 pub const CONFIG_FILE_NAME: &str = "configs/config.json";
 
+/// How many results can wait between the process thread and the outputs
+pub const DATA_QUEUE_DEPTH: usize = 4;
+
+/// Wait after the first failed try at opening the camera, doubled after every failure up to the max
+pub const CAMERA_RETRY_DELAY: Duration = Duration::from_millis(100);
+pub const CAMERA_RETRY_MAX_DELAY: Duration = Duration::from_secs(2);
+
 #[tokio::main]
-async fn main() {
-    let runtime = Handle::current();
+async fn main() -> ExitCode {
+    let mut cli = Cli::parse();
     let env_path = env::current_dir().unwrap();
+    let logging = Arc::new(logging::init(cli.overrides.log_level.as_deref()));
 
-    // Calibration & Config Files
-    let calibration = CameraCalibration::load_from_file(env_path.join(CAL_FILE_NAME)).unwrap();
-    let config = Config::load_from_file(env_path.join(CONFIG_FILE_NAME)).unwrap();
+    // These don't need the config loaded, or load it themselves
+    match &cli.command {
+        Some(Command::ListCameras) => return commands::list_cameras(),
+        Some(Command::CheckConfig) => {
+            return commands::check_config(
+                &env_path.join(&cli.config),
+                &env_path.join(&cli.calibration),
+                &cli.overrides,
+            )
+        }
+        _ => {}
+    }
+
+    // Config Files
+    let Some((config, field)) = load(&cli, &env_path) else {
+        return ExitCode::FAILURE;
+    };
+    logging.apply(&config.logging);
+    if let Some(field) = &field {
+        info!("Loaded Field Layout! [{} tags, {}m x {}m]", field.tags.len(), field.length, field.width);
+    }
 
-    println!("Loaded Configs!");
+    info!("Loaded Configs!");
+
+    match cli.command.take().unwrap_or(Command::Run) {
+        Command::Run => {
+            let Some(calibration) = load_calibration(&cli, &env_path) else {
+                return ExitCode::FAILURE;
+            };
+            let config_path = env_path.join(&cli.config);
+            let calibration_path = env_path.join(&cli.calibration);
+            run(config, calibration, field, config_path, calibration_path, cli.overrides, logging).await
+        }
+        // Making the calibration is what this is for, there isn't one yet
+        Command::Calibrate {
+            output,
+            count,
+            interval_ms,
+        } => commands::calibrate(&config, &output, count, Duration::from_millis(interval_ms)),
+        Command::Replay { log, speed } => commands::replay(&config, field, &log, speed).await,
+        Command::Bench(args) => {
+            // Rendered frames are drawn through whatever calibration they get, so a missing file only matters for
+            // images from a real camera
+            let calibration = if args.images.is_none() && !env_path.join(&cli.calibration).exists() {
+                warn!(
+                    "No calibration at {}, rendering through a nominal {}° camera",
+                    cli.calibration.display(),
+                    NOMINAL_FOV_DEGREES
+                );
+                CameraCalibration::nominal(synthetic::SYNTHETIC_WIDTH, synthetic::SYNTHETIC_HEIGHT)
+            } else {
+                let Some(calibration) = load_calibration(&cli, &env_path) else {
+                    return ExitCode::FAILURE;
+                };
+                calibration
+            };
+            commands::bench(&config, &calibration, field, &args)
+        }
+        Command::ListCameras | Command::CheckConfig => unreachable!(),
+    }
+}
+
+/// Loads and checks the config and field layout, printing what is wrong if either of them can't be used
+fn load(cli: &Cli, env_path: &Path) -> Option<(Config, Option<FieldLayout>)> {
+    let mut config = match Config::load_from_file(env_path.join(&cli.config)) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            return None;
+        }
+    };
+    cli.overrides.apply(&mut config);
+    match config.check() {
+        Ok(warnings) => {
+            for warning in warnings {
+                warn!("{}: {}", cli.config.display(), warning);
+            }
+        }
+        Err(err) => {
+            error!("{}: {}", cli.config.display(), err);
+            return None;
+        }
+    }
+
+    // Already checked that it loads
+    let field = match &config.field_layout {
+        Some(path) => Some(FieldLayout::load_from_file(env_path.join(path)).ok()?),
+        None => None,
+    };
+    Some((config, field))
+}
+
+/// Loads and checks the camera calibration, printing what is wrong if it can't be used
+fn load_calibration(cli: &Cli, env_path: &Path) -> Option<CameraCalibration> {
+    let calibration = match CameraCalibration::load_from_file(env_path.join(&cli.calibration)) {
+        Ok(calibration) => calibration,
+        Err(err) => {
+            error!("Failed to load {}: {}", cli.calibration.display(), err);
+            return None;
+        }
+    };
+    let problems = calibration.validate();
+    for problem in problems.iter() {
+        error!("{}: {}", cli.calibration.display(), problem);
+    }
+    if !problems.is_empty() {
+        return None;
+    }
+    Some(calibration)
+}
+
+/// Runs the pipeline until shutdown, reloading the config and calibration files whenever they change
+async fn run(
+    config: Config,
+    calibration: CameraCalibration,
+    field: Option<FieldLayout>,
+    config_path: PathBuf,
+    calibration_path: PathBuf,
+    overrides: Overrides,
+    logging: Arc<Logging>,
+) -> ExitCode {
+    let runtime = Handle::current();
 
     // Creating Channels
-    let (image_tx, image_rx) = crossbeam_channel::bounded::<DynamicImage>(1);
-    let (data_tx, data_rx) = crossbeam_channel::bounded::<VisionData>(1);
-    println!("Created Channels!");
+    // The camera never waits on the process thread, the process thread always gets the newest frame
+    let (image_tx, image_rx) = mailbox::<Frame>();
+    let (data_tx, mut data_rx) = tokio::sync::mpsc::channel::<VisionFrame>(DATA_QUEUE_DEPTH);
+    let (robot_tx, robot_rx) = robot_state_channel();
+    let (live_tx, live_rx) = live_config_channel(LiveConfig::new(&config, &calibration));
+    // Shared so the GUI can tune the pipeline live too
+    let live_tx = Arc::new(live_tx);
+    // Newest results, for anything drawing them over the camera
+    let (results_tx, results_rx) = watch::channel::<Option<Arc<VisionFrame>>>(None);
+    let (health_tx, health_rx) = watch::channel::<Option<HealthStatus>>(None);
+    let shutdown = CancellationToken::new();
+    runtime.spawn(shutdown::cancel_on_signal(shutdown.clone()));
+    let health = HealthCounters::new();
+    let metrics = Metrics::new(health.clone());
+    info!("Created Channels!");
+
+    #[cfg(feature = "gui")]
+    let gui_tuning = gui::Tuning::new(live_tx.clone(), config_path.clone(), overrides.clone());
+    let dashboard = Dashboard::new(
+        config_path.clone(),
+        overrides.clone(),
+        config.stream.clone(),
+        metrics.clone(),
+        results_rx.clone(),
+        health_rx,
+    );
+    runtime.spawn(reload::watch_files(
+        config_path,
+        calibration_path,
+        overrides,
+        config.clone(),
+        live_tx,
+        logging.clone(),
+        shutdown.clone(),
+    ));
+    info!("Watching Configs for Changes!");
 
     // ------------------- Server Thread -------------------------------
 
-    runtime.spawn(async move {
-        let mut data_interface = None;
+    let sinks = Sinks::spawn(&runtime, &config.interface, field.clone(), health.clone(), shutdown.clone());
+    info!("Outputs Started! {:?}", &config.interface.outputs);
 
-        #[cfg(feature = "serial")] {
-            println!("Serial Initialzied... [Port: {}]", &config.interface.serial_port);
-            data_interface = Some(open_serial_port(&config.interface).await.unwrap());
-        }
+    if config.metrics.enabled {
+        let server = metrics::serve(config.metrics.port, metrics.clone(), shutdown.clone());
+        runtime.spawn(async move {
+            if let Err(err) = server.await {
+                error!("Metrics Server Failed: {}", err);
+            }
+        });
+    }
 
-        #[cfg(feature = "server")] {
-            println!("Server Initializing...");
-            data_interface = Some(start_tcp_server(&config.interface).await.unwrap());
-        }
+    if config.stream.enabled {
+        let server = stream::serve(
+            config.stream.clone(),
+            image_rx.clone(),
+            results_rx.clone(),
+            live_rx.clone(),
+            shutdown.clone(),
+        );
+        runtime.spawn(async move {
+            if let Err(err) = server.await {
+                error!("Stream Server Failed: {}", err);
+            }
+        });
+    }
 
-        let mut data_interface = data_interface.unwrap();
-        println!("Connected to interface!");
+    if config.dashboard.enabled {
+        let server = dashboard::serve(config.dashboard.port, dashboard, shutdown.clone());
+        runtime.spawn(async move {
+            if let Err(err) = server.await {
+                error!("Dashboard Server Failed: {}", err);
+            }
+        });
+    }
 
-        // #[cfg(feature = "nt")]
-        // let mut net = NT::new(network_config).await;
-        
+    // Ends once the process thread stops and everything it sent has gone out
+    let mut monitor = HealthMonitor::new(health.clone());
+    let health_interval = Duration::from_secs_f64(config.interface.health_interval);
+    let comms = runtime.spawn(async move {
+        let mut health_timer = tokio::time::interval(health_interval);
+        // The first tick is right away, there is nothing to report yet
+        health_timer.tick().await;
         loop {
-            match data_rx.recv() {
-                Ok(data) => {
-                    let _ = data_interface.write_vision_data(data).await;
-                },
-                Err(_) => {},
+            tokio::select! {
+                data = data_rx.recv() => {
+                    let Some(data) = data else {
+                        break;
+                    };
+                    monitor.record(&data);
+                    metrics.record(&data);
+                    sinks.publish(&data);
+                    results_tx.send_replace(Some(Arc::new(data)));
+                }
+                _ = health_timer.tick() => {
+                    let status = monitor.report();
+                    sinks.publish_health(&status);
+                    health_tx.send_replace(Some(status));
+                }
             }
         }
+        sinks.close().await;
     });
 
-    println!("Comms Thread Started!");
+    info!("Comms Task Started!");
+
+    #[cfg(feature = "nt")]
+    if !config.interface.robot_topics.is_empty() {
+        runtime.spawn(nt::subscriber::run_robot_subscriber(
+            config.interface.clone(),
+            robot_tx,
+            shutdown.clone(),
+        ));
+        info!("Robot Subscriber Started!");
+    }
+    #[cfg(not(feature = "nt"))]
+    drop(robot_tx);
 
     // ----------------------------------------------------------------
 
     // --------------------- Process Camera ---------------------------
+    // Capture and detection block, so they each get their own OS thread instead of a runtime worker
 
-    println!("Finding Camera...");
-    let mut proc_camera;
-    let mut cam_id = config.camera_index;
-
-    loop {
-        // match query(nokhwa::utils::ApiBackend::Auto) {
-        //     Ok(cameras) =>  {
-        //         println!("Found a list of Cameras...");
-        //         match cameras[0].index().as_index() {
-        //             Ok(index) => {
-        //                 println!("Found camera id...");
-        //                 cam_id = index;
-        //                 println!("ID: {}", cam_id);
-        //             },
-        //             Err(err) => {
-        //                 println!("Couldn't find camera [{}]", err);
-        //             }
-        //         }
-        //     },
-        //     Err(err) => {
-        //         println!("Couldn't obtain backend to find camera [{}]", err);
-        //     }
-        // }
-        
-        println!("Getting Camera...");
-        if let Ok(cam) = Camera::new(cam_id) {
-            proc_camera = cam;
-            break;
-        }
-    }
+    let camera_index = config.camera_index;
+    let mut capture_live_rx = live_rx.clone();
+    let mut capture_robot_rx = robot_rx.clone();
+    let capture_health = health.clone();
+    let capture_shutdown = shutdown.clone();
+    let capture = thread::Builder::new()
+        .name("capture".to_string())
+        .spawn(move || {
+            let mut reconnecting = false;
+            loop {
+                info!("Finding Camera...");
+                let mut proc_camera;
+                let mut cam_id = camera_index;
+                let mut retry_delay = CAMERA_RETRY_DELAY;
 
-    // -----------------------------------------------------------------
+                loop {
+                    if capture_shutdown.is_cancelled() {
+                        return;
+                    }
 
-    proc_camera.start_stream();
+                    // match query(nokhwa::utils::ApiBackend::Auto) {
+                    //     Ok(cameras) =>  {
+                    //         println!("Found a list of Cameras...");
+                    //         match cameras[0].index().as_index() {
+                    //             Ok(index) => {
+                    //                 println!("Found camera id...");
+                    //                 cam_id = index;
+                    //                 println!("ID: {}", cam_id);
+                    //             },
+                    //             Err(err) => {
+                    //                 println!("Couldn't find camera [{}]", err);
+                    //             }
+                    //         }
+                    //     },
+                    //     Err(err) => {
+                    //         println!("Couldn't obtain backend to find camera [{}]", err);
+                    //     }
+                    // }
 
-    // Camera Callback Thread
-    runtime.spawn(async move {
-        proc_camera.callback_thread(image_tx);
-    });
-    println!("Found Camera! & Stated Callback Thread!");
+                    debug!("Getting Camera...");
+                    let robot = capture_robot_rx.borrow().clone();
+                    let controls = capture_live_rx.borrow().select(&robot).1.camera_controls.clone();
+                    if let Ok(cam) = Camera::new(cam_id, &controls) {
+                        proc_camera = cam;
+                        break;
+                    }
+                    thread::sleep(retry_delay);
+                    retry_delay = (retry_delay * 2).min(CAMERA_RETRY_MAX_DELAY);
+                }
 
-    // ------------------- Process Thread ------------------------------
-    println!("Starting Process Thread!");
+                proc_camera.start_stream();
+                if reconnecting {
+                    capture_health.camera_reconnected();
+                    info!("Reconnected to Camera!");
+                } else {
+                    info!("Found Camera! & Started Capture Thread!");
+                }
+                let lost = proc_camera.callback_thread(
+                    &image_tx,
+                    &mut capture_live_rx,
+                    &mut capture_robot_rx,
+                    &capture_health,
+                    &capture_shutdown,
+                );
+                if !lost {
+                    return;
+                }
+                warn!("Lost Camera! Reconnecting...");
+                reconnecting = true;
+            }
+        })
+        .expect("Failed to start capture thread");
 
-    let mut proc_thread = Process::new(
-        image_rx.clone(),
-        data_tx,
-        calibration,
-        config.detection_config,
-    );
+    // -----------------------------------------------------------------
 
-    // Process Thread
-    runtime.spawn(async move {
-        loop {
-            proc_thread.update();
-        }
-    });
+    // ------------------- Process Thread ------------------------------
+    info!("Starting Process Thread!");
 
-    println!("Started Process Thread!");
+    let proc_rx = image_rx.clone();
+    #[cfg(feature = "gui")]
+    let gui_live_rx = live_rx.clone();
+    #[cfg(feature = "gui")]
+    let gui_field = field.clone();
+    let process = thread::Builder::new()
+        .name("process".to_string())
+        .spawn(move || {
+            // The detector isn't Send, so the process has to be built on the thread that runs it
+            let mut proc_thread = Process::new(proc_rx, data_tx, field, robot_rx, live_rx);
+            proc_thread.run();
+        })
+        .expect("Failed to start process thread");
+
+    info!("Started Process Thread!");
     // -----------------------------------------------------------------
 
-    // GUI
+    // GUI, closing the window shuts everything down
     #[cfg(feature = "gui")]
-    let _ = eframe::run_native(
-        "Vision-App",
-        eframe::NativeOptions::default(),
-        Box::new(|_c| Box::new(gui::VisionApp::new(image_rx))),
-    );
+    {
+        let gui_shutdown = shutdown.clone();
+        if let Err(err) = eframe::run_native(
+            "Vision-App",
+            eframe::NativeOptions::default(),
+            Box::new(|_c| {
+                Box::new(gui::VisionApp::new(
+                    image_rx,
+                    results_rx,
+                    gui_live_rx,
+                    gui_tuning,
+                    gui_field,
+                    gui_shutdown,
+                ))
+            }),
+        ) {
+            error!("GUI Failed: {}", err);
+        }
+        shutdown.cancel();
+    }
 
+    // Without a GUI there is nothing for the main thread to do but wait to be stopped
     #[cfg(not(feature = "gui"))]
-    loop {
-        if false {
-            break;
+    shutdown.cancelled().await;
+
+    // ------------------- Shutdown ------------------------------------
+    info!("Shutting Down!");
+
+    // Capture stops first, which closes the mailbox, which stops the process thread, which ends the comms task.
+    // The threads are joined on a thread of their own rather than the blocking pool, the runtime waits for blocking
+    // tasks as it drops so one stuck in there would hang the exit the timeout is meant to bound.
+    let (joined_tx, joined_rx) = tokio::sync::oneshot::channel();
+    thread::Builder::new()
+        .name("shutdown".to_string())
+        .spawn(move || {
+            let capture_ok = capture.join().is_ok();
+            let process_ok = process.join().is_ok();
+            let _ = joined_tx.send(capture_ok && process_ok);
+        })
+        .expect("Failed to start shutdown thread");
+    let stopped = tokio::time::timeout(shutdown::SHUTDOWN_TIMEOUT, async {
+        let threads_ok = joined_rx.await.unwrap_or(false);
+        let comms_ok = comms.await.is_ok();
+        threads_ok && comms_ok
+    })
+    .await;
+
+    match stopped {
+        Ok(true) => {
+            info!("Shut Down Cleanly!");
+            ExitCode::SUCCESS
+        }
+        Ok(false) => {
+            error!("A thread panicked while shutting down!");
+            ExitCode::FAILURE
+        }
+        Err(_) => {
+            error!("Timed out shutting down!");
+            // Whatever is stuck could still hold up dropping the runtime, don't wait on it
+            logging.flush();
+            std::process::exit(1);
         }
     }
-
-    // proc_camera.stop_stream();
 }
@@ -0,0 +1,250 @@
+//! # Prometheus metrics
+//!
+//! Counters and latency histograms for the whole run, served in the Prometheus text exposition format at
+//! `/metrics` on `metrics.port` when `metrics.enabled` is set, so a laptop in the pits can scrape the coprocessor.
+//!
+//! `Metrics` sits on the comms task next to the health monitor and sees every result on its way to the outputs.
+//! Camera, decode and output failures come from the `HealthCounters` the other threads already count into.
+//!
+//! | Metric | Type | Labels |
+//! |---|---|---|
+//! | `vision_frames_captured_total`, `vision_frames_processed_total`, `vision_frames_dropped_total` | counter | |
+//! | `vision_frames_with_targets_total` | counter | |
+//! | `vision_tag_detections_total` | counter | `tag` |
+//! | `vision_stage_latency_seconds` | histogram | `stage`: `preprocess`, `detection`, `pose` |
+//! | `vision_pipeline_latency_seconds` | histogram | |
+//! | `vision_output_errors_total` | counter | `output` |
+//! | `vision_camera_reconnects_total`, `vision_decode_errors_total` | counter | |
+//! | `vision_cpu_temperature_celsius` | gauge | |
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::health::{cpu_temperature, HealthCounters};
+use crate::http::{self, Request, Response};
+use crate::interface::DataError;
+use crate::mailbox::FrameCounts;
+use crate::process::VisionFrame;
+
+/// Upper bounds of the latency histogram buckets in seconds, from a fast crop to a slow full frame on a Pi
+pub const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.002, 0.005, 0.01, 0.02, 0.03, 0.05, 0.075, 0.1, 0.15, 0.25, 0.5];
+
+/// Content type of the text exposition format
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Cumulative histogram over `LATENCY_BUCKETS`
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Observations at or under each bucket's bound, the `+Inf` bucket is `count`
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (count, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {}", self.count);
+        let braces = |labels: &str| if labels.is_empty() { String::new() } else { format!("{{{labels}}}") };
+        let _ = writeln!(out, "{name}_sum{} {}", braces(labels), self.sum);
+        let _ = writeln!(out, "{name}_count{} {}", braces(labels), self.count);
+    }
+}
+
+#[derive(Default)]
+struct Recorded {
+    frames: FrameCounts,
+    frames_with_targets: u64,
+    tag_detections: BTreeMap<u32, u64>,
+    preprocess: Histogram,
+    detection: Histogram,
+    pose: Histogram,
+    pipeline: Histogram,
+}
+
+/// Everything scraped from `/metrics`
+pub struct Metrics {
+    recorded: Mutex<Recorded>,
+    health: Arc<HealthCounters>,
+}
+
+impl Metrics {
+    pub fn new(health: Arc<HealthCounters>) -> Arc<Self> {
+        Arc::new(Metrics {
+            recorded: Mutex::new(Recorded::default()),
+            health,
+        })
+    }
+
+    /// Counts a result
+    pub fn record(&self, frame: &VisionFrame) {
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.frames = frame.frames;
+        if !frame.targets.is_empty() {
+            recorded.frames_with_targets += 1;
+        }
+        for target in frame.targets.iter() {
+            *recorded.tag_detections.entry(target.id).or_default() += 1;
+        }
+        recorded.preprocess.observe(frame.stages.preprocess / 1000.0);
+        recorded.detection.observe(frame.stages.detection / 1000.0);
+        recorded.pose.observe(frame.stages.pose / 1000.0);
+        recorded.pipeline.observe(frame.pipeline_latency / 1000.0);
+    }
+
+    /// Every metric in the text exposition format
+    pub fn render(&self) -> String {
+        let recorded = self.recorded.lock().unwrap();
+        let mut out = String::new();
+
+        let mut counter = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {value}");
+        };
+        counter("vision_frames_captured_total", "Frames the camera captured", recorded.frames.captured);
+        counter("vision_frames_processed_total", "Frames the process thread ran detection on", recorded.frames.processed);
+        counter(
+            "vision_frames_dropped_total",
+            "Frames replaced by a newer one before they were processed",
+            recorded.frames.dropped,
+        );
+        counter(
+            "vision_frames_with_targets_total",
+            "Processed frames with at least one target",
+            recorded.frames_with_targets,
+        );
+        counter(
+            "vision_camera_reconnects_total",
+            "Times the camera had to be opened again",
+            self.health.camera_reconnects(),
+        );
+        counter(
+            "vision_decode_errors_total",
+            "Camera frames that couldn't be decoded",
+            self.health.decode_errors(),
+        );
+
+        let _ = writeln!(out, "# HELP vision_tag_detections_total Times each tag was a target");
+        let _ = writeln!(out, "# TYPE vision_tag_detections_total counter");
+        for (id, count) in recorded.tag_detections.iter() {
+            let _ = writeln!(out, "vision_tag_detections_total{{tag=\"{id}\"}} {count}");
+        }
+
+        let _ = writeln!(out, "# HELP vision_output_errors_total Times an output failed to open or write");
+        let _ = writeln!(out, "# TYPE vision_output_errors_total counter");
+        for (kind, count) in self.health.output_errors() {
+            let _ = writeln!(out, "vision_output_errors_total{{output=\"{kind:?}\"}} {count}");
+        }
+
+        let _ = writeln!(out, "# HELP vision_stage_latency_seconds Time spent on each stage of the pipeline");
+        let _ = writeln!(out, "# TYPE vision_stage_latency_seconds histogram");
+        recorded.preprocess.render(&mut out, "vision_stage_latency_seconds", "stage=\"preprocess\"");
+        recorded.detection.render(&mut out, "vision_stage_latency_seconds", "stage=\"detection\"");
+        recorded.pose.render(&mut out, "vision_stage_latency_seconds", "stage=\"pose\"");
+
+        let _ = writeln!(out, "# HELP vision_pipeline_latency_seconds Time from a frame reaching the process thread to its result");
+        let _ = writeln!(out, "# TYPE vision_pipeline_latency_seconds histogram");
+        recorded.pipeline.render(&mut out, "vision_pipeline_latency_seconds", "");
+
+        if let Some(temperature) = cpu_temperature() {
+            let _ = writeln!(out, "# HELP vision_cpu_temperature_celsius Hottest CPU temperature sensor");
+            let _ = writeln!(out, "# TYPE vision_cpu_temperature_celsius gauge");
+            let _ = writeln!(out, "vision_cpu_temperature_celsius {temperature}");
+        }
+        out
+    }
+}
+
+/// Serves `/metrics` on `port` until shutdown
+pub async fn serve(port: u16, metrics: Arc<Metrics>, shutdown: CancellationToken) -> Result<(), DataError> {
+    http::serve(
+        "Metrics",
+        port,
+        move |request: Request, mut stream: TcpStream| {
+            let metrics = metrics.clone();
+            async move {
+                let response = match request.path.as_str() {
+                    _ if request.method != "GET" => Response::method_not_allowed(),
+                    "/metrics" => Response::ok(PROMETHEUS_CONTENT_TYPE, metrics.render()),
+                    _ => Response::not_found(),
+                };
+                response.write(&mut stream).await
+            }
+        },
+        shutdown,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OutputKind;
+    use crate::process::tests::sample_frame;
+
+    #[test]
+    fn histogram_is_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.004);
+        histogram.observe(0.04);
+        histogram.observe(2.0);
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "");
+        assert!(out.contains("latency_bucket{le=\"0.001\"} 0\n"));
+        assert!(out.contains("latency_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("latency_bucket{le=\"0.05\"} 2\n"));
+        assert!(out.contains("latency_bucket{le=\"0.5\"} 2\n"));
+        assert!(out.contains("latency_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("latency_sum 2.044\n"));
+        assert!(out.contains("latency_count 3\n"));
+    }
+
+    #[test]
+    fn renders_what_was_recorded() {
+        let health = HealthCounters::new();
+        let metrics = Metrics::new(health.clone());
+        let mut frame = sample_frame();
+        frame.frames = FrameCounts { captured: 5, processed: 4, dropped: 1 };
+        metrics.record(&frame);
+        frame.targets.clear();
+        metrics.record(&frame);
+        health.link_failed(OutputKind::Udp);
+        health.camera_reconnected();
+
+        let out = metrics.render();
+        for line in [
+            "# TYPE vision_frames_captured_total counter",
+            "vision_frames_captured_total 5",
+            "vision_frames_processed_total 4",
+            "vision_frames_dropped_total 1",
+            "vision_frames_with_targets_total 1",
+            "vision_camera_reconnects_total 1",
+            "vision_decode_errors_total 0",
+            "vision_tag_detections_total{tag=\"7\"} 1",
+            "vision_output_errors_total{output=\"Udp\"} 1",
+            "vision_stage_latency_seconds_count{stage=\"pose\"} 2",
+            "vision_pipeline_latency_seconds_bucket{le=\"0.005\"} 0",
+            "vision_pipeline_latency_seconds_bucket{le=\"0.01\"} 2",
+            "vision_pipeline_latency_seconds_count 2",
+        ] {
+            assert!(out.lines().any(|rendered| rendered == line), "missing `{line}` in\n{out}");
+        }
+    }
+}
@@ -1,89 +1,224 @@
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::{config::*, process::VisionData};
+use crate::{config::InterfaceConfig, health::HealthStatus, interface::DataError, process::VisionFrame};
 use network_tables::v4::*;
 use network_tables::Value::*;
+use tracing::{info, warn};
+
+/// How long to wait on the NT server before giving up on connecting, in milliseconds
+pub const NT_CONNECT_TIMEOUT: u64 = 1000;
+/// How long to wait between reconnect attempts after losing the NT server, in milliseconds
+pub const NT_RETRY_INTERVAL: u64 = 1000;
+
+/// Connects to the NT server in the config.
+///
+/// The client reconnects on its own if the server goes away, the returned flag is cleared while it is
+/// reconnecting so the outputs can skip publishing instead of blocking on a dead socket.
+pub(crate) async fn connect(config: &InterfaceConfig) -> Result<(Client, Arc<AtomicBool>), DataError> {
+    let ip = SocketAddr::from((config.nt_ip, config.nt_port));
+    let connected = Arc::new(AtomicBool::new(true));
+
+    let on_disconnect = connected.clone();
+    let on_reconnect = connected.clone();
+    let nt_config = Config {
+        connect_timeout: NT_CONNECT_TIMEOUT,
+        disconnect_retry_interval: NT_RETRY_INTERVAL,
+        on_disconnect: Box::new(move || {
+            warn!("Lost connection to NetworkTables server!");
+            on_disconnect.store(false, Ordering::Relaxed);
+            Box::pin(async {})
+        }),
+        on_reconnect: Box::new(move || {
+            info!("Reconnected to NetworkTables server!");
+            on_reconnect.store(true, Ordering::Relaxed);
+            Box::pin(async {})
+        }),
+        ..Default::default()
+    };
+    let client = Client::try_new_w_config(ip, nt_config).await?;
+    Ok((client, connected))
+}
+
+/// Publishes a topic with the default properties
+pub(crate) async fn topic<S: AsRef<str>>(client: &Client, name: S, topic_type: Type) -> Result<PublishedTopic, DataError> {
+    Ok(client
+        .publish_topic(name, topic_type, Some(PublishProperties::default()))
+        .await?)
+}
+
+/// Milliseconds between the capture timestamp and now
+pub(crate) fn latency_ms(timestamp: f64) -> f64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs_f64();
+    ((now - timestamp) * 1000.0).max(0.0)
+}
+
+/// Health status topics under a table, every NetworkTables mode publishes these
+pub(crate) struct HealthTopics {
+    fps_topic: PublishedTopic,
+    dropped_topic: PublishedTopic,
+    detection_rate_topic: PublishedTopic,
+    latency_topic: PublishedTopic,
+    stages_topic: PublishedTopic,
+    temperature_topic: PublishedTopic,
+    reconnects_topic: PublishedTopic,
+    decode_errors_topic: PublishedTopic,
+    link_errors_topic: PublishedTopic,
+    uptime_topic: PublishedTopic,
+}
+
+impl HealthTopics {
+    pub(crate) async fn new(client: &Client, table: &str) -> Result<HealthTopics, DataError> {
+        Ok(HealthTopics {
+            fps_topic: topic(client, format!("{table}/FPS"), Type::Double).await?,
+            dropped_topic: topic(client, format!("{table}/DroppedFrames"), Type::Int).await?,
+            detection_rate_topic: topic(client, format!("{table}/DetectionRate"), Type::Double).await?,
+            latency_topic: topic(client, format!("{table}/Latency"), Type::Double).await?,
+            stages_topic: topic(client, format!("{table}/StageLatency"), Type::DoubleArray).await?,
+            temperature_topic: topic(client, format!("{table}/CpuTemperature"), Type::Double).await?,
+            reconnects_topic: topic(client, format!("{table}/CameraReconnects"), Type::Int).await?,
+            decode_errors_topic: topic(client, format!("{table}/DecodeErrors"), Type::Int).await?,
+            link_errors_topic: topic(client, format!("{table}/LinkErrors"), Type::Int).await?,
+            uptime_topic: topic(client, format!("{table}/Uptime"), Type::Double).await?,
+        })
+    }
+
+    /// Publishes every field of the status.
+    ///
+    /// `Latency` is the mean pipeline latency and `StageLatency` is the mean of preprocess, detection and pose, all
+    /// in milliseconds. `DroppedFrames` is since the last status, the error counts are since startup.
+    /// `CpuTemperature` is left alone where there is no sensor.
+    pub(crate) async fn publish(&self, client: &Client, status: &HealthStatus) -> Result<(), DataError> {
+        let count = |value: u64| Integer(value.min(i64::MAX as u64).into());
+
+        client.publish_value(&self.fps_topic, &F64(status.fps)).await?;
+        client
+            .publish_value(&self.dropped_topic, &count(status.dropped_frames))
+            .await?;
+        client
+            .publish_value(&self.detection_rate_topic, &F64(status.detection_rate))
+            .await?;
+        client
+            .publish_value(&self.latency_topic, &F64(status.pipeline_latency))
+            .await?;
+        client
+            .publish_value(
+                &self.stages_topic,
+                &Array(vec![
+                    F64(status.stages.preprocess),
+                    F64(status.stages.detection),
+                    F64(status.stages.pose),
+                ]),
+            )
+            .await?;
+        if let Some(temperature) = status.cpu_temperature {
+            client
+                .publish_value(&self.temperature_topic, &F64(temperature))
+                .await?;
+        }
+        client
+            .publish_value(&self.reconnects_topic, &count(status.camera_reconnects))
+            .await?;
+        client
+            .publish_value(&self.decode_errors_topic, &count(status.decode_errors))
+            .await?;
+        client
+            .publish_value(&self.link_errors_topic, &count(status.link_errors))
+            .await?;
+        client.publish_value(&self.uptime_topic, &F64(status.uptime)).await?;
+        Ok(())
+    }
+}
 
 pub(crate) struct NT {
     client: Client,
+    /// Cleared while the client is reconnecting so publishing doesn't block on a dead socket
+    connected: Arc<AtomicBool>,
     detected_topic: PublishedTopic,
     tag_id_topic: PublishedTopic,
     timestamp_topic: PublishedTopic,
+    latency_topic: PublishedTopic,
     rot_topic: PublishedTopic,
     transform_topic: PublishedTopic,
+    std_devs_topic: PublishedTopic,
+    filtered_rot_topic: PublishedTopic,
+    filtered_transform_topic: PublishedTopic,
+    pipeline_topic: PublishedTopic,
+    health_topics: HealthTopics,
 }
 
 impl NT {
-    pub(crate) async fn new(config: NetworkConfig) -> NT {
-        let ip = SocketAddr::from((config.nt_ip, config.nt_port));
-        let client = client::Client::new(ip).await;
-
-        let detected_topic = client
-            .publish_topic(
-                "Vision/Detected",
-                Type::Boolean,
-                Some(PublishProperties::default()),
-            )
-            .await
-            .unwrap();
-        let tag_id_topic = client
-            .publish_topic(
-                "Vision/TagID",
-                Type::Int,
-                Some(PublishProperties::default()),
-            )
-            .await
-            .unwrap();
-        let timestamp_topic = client
-            .publish_topic(
-                "Vision/Timestamp",
-                Type::Float,
-                Some(PublishProperties::default()),
-            )
-            .await
-            .unwrap();
-        let rot_topic = client
-            .publish_topic(
-                "Vision/Rotation",
-                Type::DoubleArray,
-                Some(PublishProperties::default()),
-            )
-            .await
-            .unwrap();
-        let transform_topic = client
-            .publish_topic(
-                "Vision/Translation",
-                Type::DoubleArray,
-                Some(PublishProperties::default()),
-            )
-            .await
-            .unwrap();
+    pub(crate) async fn new(config: &InterfaceConfig) -> Result<NT, DataError> {
+        let (client, connected) = connect(config).await?;
 
-        NT {
+        let table = config.nt_table.trim_matches('/');
+        let detected_topic = topic(&client, format!("/{table}/Detected"), Type::Boolean).await?;
+        let tag_id_topic = topic(&client, format!("/{table}/TagID"), Type::Int).await?;
+        let timestamp_topic = topic(&client, format!("/{table}/Timestamp"), Type::Double).await?;
+        let latency_topic = topic(&client, format!("/{table}/Latency"), Type::Double).await?;
+        let rot_topic = topic(&client, format!("/{table}/Rotation"), Type::DoubleArray).await?;
+        let transform_topic = topic(&client, format!("/{table}/Translation"), Type::DoubleArray).await?;
+        let std_devs_topic = topic(&client, format!("/{table}/StdDevs"), Type::DoubleArray).await?;
+        let filtered_rot_topic = topic(&client, format!("/{table}/FilteredRotation"), Type::DoubleArray).await?;
+        let filtered_transform_topic =
+            topic(&client, format!("/{table}/FilteredTranslation"), Type::DoubleArray).await?;
+        let pipeline_topic = topic(&client, format!("/{table}/Pipeline"), Type::String).await?;
+        let health_topics = HealthTopics::new(&client, &format!("/{table}/Health")).await?;
+
+        Ok(NT {
             client,
+            connected,
             detected_topic,
             tag_id_topic,
             timestamp_topic,
+            latency_topic,
             rot_topic,
             transform_topic,
-        }
+            std_devs_topic,
+            filtered_rot_topic,
+            filtered_transform_topic,
+            pipeline_topic,
+            health_topics,
+        })
     }
 
-    pub(crate) async fn publish(&mut self, data: VisionData) {
-        let _ = self
-            .client
+    /// Publishes every field of the data.
+    ///
+    /// `Timestamp` is when the frame was captured (seconds since the Unix epoch on the coprocessor) and
+    /// `Latency` is how long ago that was when the values got published (milliseconds), so the robot can
+    /// work out the capture time on its own clock with `now - latency`.
+    /// `StdDevs` is x, y (meters) and theta (radians), ready for `addVisionMeasurement`.
+    /// `Pipeline` is the name of the pipeline that processed the frame.
+    ///
+    /// `FilteredRotation` and `FilteredTranslation` are the same pose for the same tag smoothed by the tracking
+    /// stage, zeros when there is no target or tracking is off.
+    pub(crate) async fn publish(&mut self, frame: &VisionFrame) -> Result<(), DataError> {
+        if !self.connected.load(Ordering::Relaxed) {
+            // Stale by the time we reconnect, the client republishes the topics on its own
+            return Ok(());
+        }
+
+        let data = frame.vision_data();
+        let latency = latency_ms(data.timestamp);
+
+        self.client
             .publish_value(&self.detected_topic, &Boolean(data.detected))
-            .await;
-        let _ = self
-            .client
+            .await?;
+        self.client
             .publish_value(&self.tag_id_topic, &Integer(data.tag_id.into()))
-            .await;
-        let _ = self
-            .client
-            .publish_value(&self.timestamp_topic, &F32(0.0))
-            .await;
-        let _ = self
-            .client
+            .await?;
+        self.client
+            .publish_value(&self.timestamp_topic, &F64(data.timestamp))
+            .await?;
+        self.client
+            .publish_value(&self.latency_topic, &F64(latency))
+            .await?;
+        self.client
             .publish_value(
                 &self.rot_topic,
                 &Array(vec![
@@ -92,9 +227,8 @@ impl NT {
                     F64(data.rotation[2]),
                 ]),
             )
-            .await;
-        let _ = self
-            .client
+            .await?;
+        self.client
             .publish_value(
                 &self.transform_topic,
                 &Array(vec![
@@ -103,6 +237,181 @@ impl NT {
                     F64(data.translation[2]),
                 ]),
             )
-            .await;
+            .await?;
+        self.client
+            .publish_value(
+                &self.std_devs_topic,
+                &Array(data.std_devs.iter().copied().map(F64).collect()),
+            )
+            .await?;
+        self.client
+            .publish_value(&self.pipeline_topic, &String(frame.pipeline_name.as_str().into()))
+            .await?;
+
+        // Zeros rather than the last smoothed pose when nothing was tracked this frame
+        let (filtered_rotation, filtered_translation) = frame
+            .targets
+            .first()
+            .and_then(|target| target.filtered.as_ref())
+            .map_or(([0.0; 3], [0.0; 3]), |filtered| (filtered.rotation, filtered.translation));
+        self.client
+            .publish_value(
+                &self.filtered_rot_topic,
+                &Array(filtered_rotation.iter().copied().map(F64).collect()),
+            )
+            .await?;
+        self.client
+            .publish_value(
+                &self.filtered_transform_topic,
+                &Array(filtered_translation.iter().copied().map(F64).collect()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Publishes the status under `Health`, see `HealthTopics::publish`
+    pub(crate) async fn publish_health(&mut self, status: &HealthStatus) -> Result<(), DataError> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.health_topics.publish(&self.client, status).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use futures::StreamExt;
+    use network_tables::{rmpv, Value};
+    use serde_json::Value as Json;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::*;
+    use crate::health::tests::sample_status;
+    use crate::process::tests::sample_frame;
+
+    /// Answers with the NT4 subprotocol like a real server does
+    // The error type is tungstenite's
+    #[allow(clippy::result_large_err)]
+    fn accept_protocol(_: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+        let protocol = HeaderValue::from_static("networktables.first.wpi.edu");
+        response.headers_mut().insert("Sec-WebSocket-Protocol", protocol);
+        Ok(response)
+    }
+
+    /// Accepts one NT4 client on a local port and passes on everything it sends
+    async fn stand_in_server() -> (SocketAddr, mpsc::UnboundedReceiver<Message>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_hdr_async(stream, accept_protocol).await.unwrap();
+            while let Some(Ok(message)) = socket.next().await {
+                if tx.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        (addr, rx)
+    }
+
+    /// Topic names and types the client published and the last value sent on each, once every one of `names` has one
+    async fn published(
+        rx: &mut mpsc::UnboundedReceiver<Message>,
+        names: &[&str],
+    ) -> HashMap<std::string::String, (std::string::String, Value)> {
+        let mut topics: HashMap<u64, (std::string::String, std::string::String)> = HashMap::new();
+        let mut values = HashMap::new();
+        while !names.iter().all(|name| values.contains_key(*name)) {
+            match rx.recv().await.expect("the client hung up") {
+                Message::Text(text) => {
+                    let messages: Vec<Json> = serde_json::from_str(&text).unwrap();
+                    for message in messages.iter().filter(|message| message["method"] == "publish") {
+                        let params = &message["params"];
+                        let name = params["name"].as_str().unwrap().to_string();
+                        let topic_type = params["type"].as_str().unwrap().to_string();
+                        topics.insert(params["pubuid"].as_u64().unwrap(), (name, topic_type));
+                    }
+                }
+                Message::Binary(data) => {
+                    let mut data = &data[..];
+                    while !data.is_empty() {
+                        let Value::Array(update) = rmpv::decode::read_value(&mut data).unwrap() else {
+                            panic!("values are sent as arrays");
+                        };
+                        // The time sync is sent on -1, which isn't a topic
+                        if let Some((name, topic_type)) = update[0].as_u64().and_then(|id| topics.get(&id)) {
+                            values.insert(name.clone(), (topic_type.clone(), update[3].clone()));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        values
+    }
+
+    fn doubles(value: &Value) -> Vec<f64> {
+        value.as_array().unwrap().iter().map(|value| value.as_f64().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn publishes_to_an_nt4_server() {
+        let (addr, mut rx) = stand_in_server().await;
+        let config: InterfaceConfig = serde_json::from_value(serde_json::json!({
+            "nt_ip": [127, 0, 0, 1],
+            "nt_port": addr.port(),
+            "nt_table": "/Vision/",
+        }))
+        .unwrap();
+
+        let frame = sample_frame();
+        let status = sample_status();
+        let values = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut nt = NT::new(&config).await.unwrap();
+            nt.publish(&frame).await.unwrap();
+            nt.publish_health(&status).await.unwrap();
+            published(
+                &mut rx,
+                &[
+                    "/Vision/Detected",
+                    "/Vision/TagID",
+                    "/Vision/Timestamp",
+                    "/Vision/Translation",
+                    "/Vision/StdDevs",
+                    "/Vision/Pipeline",
+                    "/Vision/FilteredTranslation",
+                    "/Vision/FilteredRotation",
+                    "/Vision/Health/FPS",
+                    "/Vision/Health/DroppedFrames",
+                    "/Vision/Health/Uptime",
+                ],
+            )
+            .await
+        })
+        .await
+        .expect("the stand-in server didn't get every value");
+
+        let data = frame.vision_data();
+        let value = |name: &str| &values[name];
+        assert_eq!(value("/Vision/Detected"), &("boolean".into(), Boolean(true)));
+        assert_eq!(value("/Vision/TagID"), &("int".into(), Integer(7.into())));
+        assert_eq!(value("/Vision/Timestamp"), &("double".into(), F64(frame.timestamp)));
+        assert_eq!(value("/Vision/Translation").0, "double[]");
+        assert_eq!(doubles(&value("/Vision/Translation").1), data.translation);
+        assert_eq!(doubles(&value("/Vision/StdDevs").1), [0.02, 0.03, 0.01]);
+        assert_eq!(value("/Vision/Pipeline"), &("string".into(), String("default".into())));
+        // Not tracked
+        assert_eq!(doubles(&value("/Vision/FilteredTranslation").1), [0.0; 3]);
+        assert_eq!(doubles(&value("/Vision/FilteredRotation").1), [0.0; 3]);
+        assert_eq!(value("/Vision/Health/FPS"), &("double".into(), F64(status.fps)));
+        assert_eq!(value("/Vision/Health/DroppedFrames"), &("int".into(), Integer(12.into())));
+        assert_eq!(value("/Vision/Health/Uptime"), &("double".into(), F64(status.uptime)));
     }
 }
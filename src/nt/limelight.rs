@@ -0,0 +1,309 @@
+//! # Limelight style NetworkTables output
+//!
+//! Publishes results under `/<limelight_table>` with the same entries a Limelight does, so robot code written
+//! against `LimelightHelpers` or the raw Limelight entries can use this binary instead.
+//!
+//! | Entry | Value |
+//! |---|---|
+//! | `tv` | 1 if there is a target, 0 if not |
+//! | `tx`, `ty` | Horizontal (positive right) and vertical (positive up) offset to the target in degrees, 0 without one |
+//! | `ta` | Target area as a percent of the image, 0 without a target |
+//! | `tid` | Id of the target, -1 if there is none |
+//! | `tl` | Pipeline latency in milliseconds |
+//! | `cl` | Latency before and after the pipeline in milliseconds, `tl + cl` is the total latency |
+//! | `botpose` | Robot pose in field space with the origin at the center of the field, then total latency |
+//! | `botpose_wpiblue` | Robot pose in field space with the origin at the blue corner, then total latency |
+//! | `targetpose_cameraspace` | Target pose relative to the camera |
+//! | `camerapose_targetspace` | Camera pose relative to the target |
+//! | `stddevs` | Standard deviations of `botpose` as x, y, z, roll, pitch, yaw, then zeros where MegaTag2 would be. Only x, y and yaw are estimated, the rest are 0 |
+//! | `hw` | fps, CPU temperature, RAM usage (always 0) and temperature, once per health status |
+//!
+//! Poses are x, y, z in meters then roll, pitch, yaw in degrees.
+//! Camera space has x right, y down and z out of the lens, target space has x right, y down and z into the tag.
+//! The rest of the health status is published under `health`, which a Limelight doesn't have.
+//! The bot poses need a field layout, without one they are published as all zeros like a Limelight with no tags in view.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
+use network_tables::v4::*;
+use network_tables::Value::*;
+
+use super::client::{connect, latency_ms, topic, HealthTopics};
+use crate::config::InterfaceConfig;
+use crate::field::FieldLayout;
+use crate::health::HealthStatus;
+use crate::interface::DataError;
+use crate::process::VisionFrame;
+
+pub(crate) struct LimelightNT {
+    client: Client,
+    connected: Arc<AtomicBool>,
+    /// Field length and width, the `botpose` origin is the center of the field
+    field_size: Option<(f64, f64)>,
+    tv_topic: PublishedTopic,
+    tx_topic: PublishedTopic,
+    ty_topic: PublishedTopic,
+    ta_topic: PublishedTopic,
+    tid_topic: PublishedTopic,
+    tl_topic: PublishedTopic,
+    cl_topic: PublishedTopic,
+    botpose_topic: PublishedTopic,
+    botpose_blue_topic: PublishedTopic,
+    target_camera_topic: PublishedTopic,
+    camera_target_topic: PublishedTopic,
+    stddevs_topic: PublishedTopic,
+    hw_topic: PublishedTopic,
+    health_topics: HealthTopics,
+}
+
+impl LimelightNT {
+    pub(crate) async fn new(
+        config: &InterfaceConfig,
+        field: Option<&FieldLayout>,
+    ) -> Result<LimelightNT, DataError> {
+        let (client, connected) = connect(config).await?;
+
+        let table = config.limelight_table.trim_matches('/');
+        let tv_topic = topic(&client, format!("/{table}/tv"), Type::Double).await?;
+        let tx_topic = topic(&client, format!("/{table}/tx"), Type::Double).await?;
+        let ty_topic = topic(&client, format!("/{table}/ty"), Type::Double).await?;
+        let ta_topic = topic(&client, format!("/{table}/ta"), Type::Double).await?;
+        let tid_topic = topic(&client, format!("/{table}/tid"), Type::Double).await?;
+        let tl_topic = topic(&client, format!("/{table}/tl"), Type::Double).await?;
+        let cl_topic = topic(&client, format!("/{table}/cl"), Type::Double).await?;
+        let botpose_topic = topic(&client, format!("/{table}/botpose"), Type::DoubleArray).await?;
+        let botpose_blue_topic =
+            topic(&client, format!("/{table}/botpose_wpiblue"), Type::DoubleArray).await?;
+        let target_camera_topic =
+            topic(&client, format!("/{table}/targetpose_cameraspace"), Type::DoubleArray).await?;
+        let camera_target_topic =
+            topic(&client, format!("/{table}/camerapose_targetspace"), Type::DoubleArray).await?;
+        let stddevs_topic = topic(&client, format!("/{table}/stddevs"), Type::DoubleArray).await?;
+        let hw_topic = topic(&client, format!("/{table}/hw"), Type::DoubleArray).await?;
+        let health_topics = HealthTopics::new(&client, &format!("/{table}/health")).await?;
+
+        Ok(LimelightNT {
+            client,
+            connected,
+            field_size: field.map(|field| (field.length, field.width)),
+            tv_topic,
+            tx_topic,
+            ty_topic,
+            ta_topic,
+            tid_topic,
+            tl_topic,
+            cl_topic,
+            botpose_topic,
+            botpose_blue_topic,
+            target_camera_topic,
+            camera_target_topic,
+            stddevs_topic,
+            hw_topic,
+            health_topics,
+        })
+    }
+
+    pub(crate) async fn publish(&mut self, frame: &VisionFrame) -> Result<(), DataError> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let total_latency = latency_ms(frame.timestamp);
+        let entries = Entries::new(frame, self.field_size, total_latency);
+
+        self.publish_double(&self.tl_topic, entries.tl).await?;
+        self.publish_double(&self.cl_topic, entries.cl).await?;
+        self.publish_double(&self.tv_topic, entries.tv).await?;
+        self.publish_double(&self.tx_topic, entries.tx).await?;
+        self.publish_double(&self.ty_topic, entries.ty).await?;
+        self.publish_double(&self.ta_topic, entries.ta).await?;
+        self.publish_double(&self.tid_topic, entries.tid).await?;
+        self.publish_array(&self.target_camera_topic, &entries.target_camera)
+            .await?;
+        self.publish_array(&self.camera_target_topic, &entries.camera_target)
+            .await?;
+        self.publish_array(&self.botpose_topic, &entries.botpose).await?;
+        self.publish_array(&self.botpose_blue_topic, &entries.botpose_blue)
+            .await?;
+        self.publish_array(&self.stddevs_topic, &entries.stddevs).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn publish_health(&mut self, status: &HealthStatus) -> Result<(), DataError> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let temperature = status.cpu_temperature.unwrap_or(0.0);
+        self.publish_array(&self.hw_topic, &[status.fps, temperature, 0.0, temperature])
+            .await?;
+        self.health_topics.publish(&self.client, status).await
+    }
+
+    async fn publish_double(&self, topic: &PublishedTopic, value: f64) -> Result<(), DataError> {
+        Ok(self.client.publish_value(topic, &F64(value)).await?)
+    }
+
+    async fn publish_array(&self, topic: &PublishedTopic, values: &[f64]) -> Result<(), DataError> {
+        let values = values.iter().copied().map(F64).collect();
+        Ok(self.client.publish_value(topic, &Array(values)).await?)
+    }
+}
+
+/// Every entry published for a frame
+#[derive(Debug, PartialEq)]
+struct Entries {
+    tl: f64,
+    cl: f64,
+    tv: f64,
+    tx: f64,
+    ty: f64,
+    ta: f64,
+    tid: f64,
+    target_camera: [f64; 6],
+    camera_target: [f64; 6],
+    botpose: [f64; 7],
+    botpose_blue: [f64; 7],
+    stddevs: [f64; 12],
+}
+
+impl Entries {
+    /// Entries for the frame's best target, zeros (and -1 for `tid`) like a Limelight sends when there is none
+    fn new(frame: &VisionFrame, field_size: Option<(f64, f64)>, total_latency: f64) -> Entries {
+        let tl = frame.pipeline_latency;
+        let mut entries = Entries {
+            tl,
+            cl: (total_latency - tl).max(0.0),
+            tv: 0.0,
+            tx: 0.0,
+            ty: 0.0,
+            ta: 0.0,
+            tid: -1.0,
+            target_camera: [0.0; 6],
+            camera_target: [0.0; 6],
+            botpose: [0.0; 7],
+            botpose_blue: [0.0; 7],
+            stddevs: [0.0; 12],
+        };
+
+        if let Some(target) = frame.targets.first() {
+            let camera_to_target = Isometry3::from_parts(
+                Translation3::from(Vector3::from(target.best.translation)),
+                UnitQuaternion::from_rotation_matrix(&target.best.rotation),
+            );
+            entries.tv = 1.0;
+            // Our yaw is positive left
+            entries.tx = -target.yaw;
+            entries.ty = target.pitch;
+            entries.ta = target.area;
+            entries.tid = target.id as f64;
+            entries.target_camera = pose_array(&camera_to_target);
+            entries.camera_target = pose_array(&camera_to_target.inverse());
+        }
+
+        if let (Some(pose), Some((length, width))) = (&frame.robot_pose, field_size) {
+            let blue = pose_array(pose);
+            let mut center = blue;
+            center[0] -= length / 2.0;
+            center[1] -= width / 2.0;
+            entries.botpose[..6].copy_from_slice(&center);
+            entries.botpose_blue[..6].copy_from_slice(&blue);
+        }
+        entries.botpose[6] = total_latency;
+        entries.botpose_blue[6] = total_latency;
+
+        if let Some(std) = &frame.robot_std_devs {
+            // Only x, y and yaw are estimated, yaw in degrees like the poses
+            entries.stddevs[..6].copy_from_slice(&[std.x, std.y, 0.0, 0.0, 0.0, std.theta.to_degrees()]);
+        }
+        entries
+    }
+}
+
+/// Pose as x, y, z in meters then roll, pitch, yaw in degrees
+fn pose_array(pose: &Isometry3<f64>) -> [f64; 6] {
+    let (roll, pitch, yaw) = pose.rotation.euler_angles();
+    [
+        pose.translation.x,
+        pose.translation.y,
+        pose.translation.z,
+        roll.to_degrees(),
+        pitch.to_degrees(),
+        yaw.to_degrees(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Rotation3;
+
+    use super::*;
+    use crate::process::tests::sample_frame;
+    use crate::uncertainty::StdDevs;
+
+    const FIELD: (f64, f64) = (16.54, 8.21);
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-9, "{actual:?} isn't {expected:?}");
+        }
+    }
+
+    #[test]
+    fn target_entries() {
+        let entries = Entries::new(&sample_frame(), Some(FIELD), 20.0);
+        assert_eq!((entries.tv, entries.tid), (1.0, 7.0));
+        // Limelight's tx is positive right
+        assert_eq!((entries.tx, entries.ty, entries.ta), (-10.0, -5.0, 1.5));
+        assert_eq!((entries.tl, entries.cl), (8.0, 12.0));
+        assert_close(&entries.target_camera, &[0.5, -0.25, 2.0, 0.0, 0.0, 0.0]);
+        assert_close(&entries.camera_target, &[-0.5, 0.25, -2.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn camera_pose_in_target_space_turns_with_the_tag() {
+        let mut frame = sample_frame();
+        frame.targets[0].best.translation = [0.0, 0.0, 2.0];
+        frame.targets[0].best.rotation = Rotation3::from_euler_angles(0.0, 45f64.to_radians(), 0.0);
+        let entries = Entries::new(&frame, None, 0.0);
+        let side = 2.0 * 45f64.to_radians().sin();
+        assert_close(&entries.camera_target, &[side, 0.0, -side, 0.0, -45.0, 0.0]);
+    }
+
+    #[test]
+    fn botpose_is_from_the_field_center() {
+        let mut frame = sample_frame();
+        frame.robot_pose = Some(Isometry3::new(Vector3::new(2.0, 3.0, 0.0), Vector3::z() * 90f64.to_radians()));
+        frame.robot_std_devs = Some(StdDevs {
+            x: 0.1,
+            y: 0.2,
+            theta: 0.05,
+        });
+        let entries = Entries::new(&frame, Some(FIELD), 20.0);
+        assert_close(&entries.botpose_blue, &[2.0, 3.0, 0.0, 0.0, 0.0, 90.0, 20.0]);
+        assert_close(&entries.botpose, &[2.0 - 8.27, 3.0 - 4.105, 0.0, 0.0, 0.0, 90.0, 20.0]);
+        let mut stddevs = [0.0; 12];
+        stddevs[..6].copy_from_slice(&[0.1, 0.2, 0.0, 0.0, 0.0, 0.05f64.to_degrees()]);
+        assert_close(&entries.stddevs, &stddevs);
+    }
+
+    #[test]
+    fn botpose_needs_a_field() {
+        let mut frame = sample_frame();
+        frame.robot_pose = Some(Isometry3::translation(2.0, 3.0, 0.0));
+        let entries = Entries::new(&frame, None, 20.0);
+        assert_eq!(entries.botpose, [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 20.0]);
+    }
+
+    #[test]
+    fn no_target_is_zeros() {
+        let mut frame = sample_frame();
+        frame.targets.clear();
+        let entries = Entries::new(&frame, Some(FIELD), 20.0);
+        assert_eq!((entries.tv, entries.tx, entries.ty, entries.ta, entries.tid), (0.0, 0.0, 0.0, 0.0, -1.0));
+        assert_eq!(entries.target_camera, [0.0; 6]);
+        assert_eq!(entries.camera_target, [0.0; 6]);
+        assert_eq!(entries.stddevs, [0.0; 12]);
+    }
+}
@@ -1 +1,4 @@
 pub mod client;
+pub mod limelight;
+pub mod photon;
+pub mod subscriber;
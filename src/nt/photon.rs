@@ -0,0 +1,321 @@
+//! # PhotonVision compatible NetworkTables output
+//!
+//! Publishes results under `/photonvision/<camera>` the same way a PhotonVision coprocessor does, so
+//! `PhotonCamera` and `PhotonPoseEstimator` on the robot can read this binary without any changes.
+//!
+//! PhotonLib reads everything out of the packed `rawBytes` topic, the other topics are only there for dashboards.
+//! The packing follows the PhotonLib 2024 `Packet` format: big endian, with a result laid out as
+//!
+//! ```text
+//! latencyMillis: f64
+//! multiTagResult: isPresent: bool, (best, alt: Transform3d, bestReprojErr, altReprojErr, ambiguity: f64 if present),
+//!                 fiducialIDsUsed: [i16; 32] padded with -1
+//! targetCount: i8
+//! targets: [
+//!     yaw, pitch, area, skew: f64
+//!     fiducialId: i32
+//!     bestCameraToTarget, altCameraToTarget: Transform3d (x, y, z, qw, qx, qy, qz: f64)
+//!     poseAmbiguity: f64
+//!     minAreaRectCorners: [(x, y: f64); 4]
+//!     detectedCorners: count: i8, [(x, y: f64); count]
+//! ]
+//! ```
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use nalgebra::Isometry3;
+use network_tables::v4::*;
+use network_tables::Value::*;
+
+use super::client::{connect, latency_ms, topic, HealthTopics};
+use crate::config::InterfaceConfig;
+use crate::health::HealthStatus;
+use crate::geometry::camera_to_tag;
+use crate::interface::DataError;
+use crate::process::{TagTarget, VisionFrame};
+
+/// PhotonLib version we report, PhotonLib logs an error on the robot if this doesn't match its own
+pub const PHOTON_VERSION: &str = "v2024.3.1";
+
+/// Number of fiducial ids in the multi-tag result, unused slots are -1
+pub const MAX_MULTI_TAG_IDS: usize = 32;
+
+pub(crate) struct PhotonNT {
+    client: Client,
+    connected: Arc<AtomicBool>,
+    heartbeat: i64,
+    raw_bytes_topic: PublishedTopic,
+    latency_topic: PublishedTopic,
+    has_target_topic: PublishedTopic,
+    pitch_topic: PublishedTopic,
+    yaw_topic: PublishedTopic,
+    area_topic: PublishedTopic,
+    skew_topic: PublishedTopic,
+    pose_topic: PublishedTopic,
+    heartbeat_topic: PublishedTopic,
+    health_topics: HealthTopics,
+}
+
+impl PhotonNT {
+    pub(crate) async fn new(config: &InterfaceConfig) -> Result<PhotonNT, DataError> {
+        let (client, connected) = connect(config).await?;
+
+        let version_topic = topic(&client, "/photonvision/version", Type::String).await?;
+        client
+            .publish_value(&version_topic, &String(PHOTON_VERSION.into()))
+            .await?;
+
+        let table = format!("/photonvision/{}", config.photon_camera);
+        let raw_bytes_topic = topic(&client, format!("{table}/rawBytes"), Type::Raw).await?;
+        let latency_topic = topic(&client, format!("{table}/latencyMillis"), Type::Double).await?;
+        let has_target_topic = topic(&client, format!("{table}/hasTarget"), Type::Boolean).await?;
+        let pitch_topic = topic(&client, format!("{table}/targetPitch"), Type::Double).await?;
+        let yaw_topic = topic(&client, format!("{table}/targetYaw"), Type::Double).await?;
+        let area_topic = topic(&client, format!("{table}/targetArea"), Type::Double).await?;
+        let skew_topic = topic(&client, format!("{table}/targetSkew"), Type::Double).await?;
+        let pose_topic = topic(&client, format!("{table}/targetPose"), Type::DoubleArray).await?;
+        let heartbeat_topic = topic(&client, format!("{table}/heartbeat"), Type::Int).await?;
+        // Not something PhotonVision has, dashboards can still find it next to the camera
+        let health_topics = HealthTopics::new(&client, &format!("{table}/health")).await?;
+
+        Ok(PhotonNT {
+            client,
+            connected,
+            heartbeat: 0,
+            raw_bytes_topic,
+            latency_topic,
+            has_target_topic,
+            pitch_topic,
+            yaw_topic,
+            area_topic,
+            skew_topic,
+            pose_topic,
+            heartbeat_topic,
+            health_topics,
+        })
+    }
+
+    /// Publishes the frame as a PhotonVision pipeline result.
+    ///
+    /// PhotonLib takes the capture time as the NT timestamp of `rawBytes` minus `latencyMillis`,
+    /// so the latency covers everything from capture up to this publish.
+    pub(crate) async fn publish(&mut self, frame: &VisionFrame) -> Result<(), DataError> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let latency = latency_ms(frame.timestamp);
+        let packet = pack_result(frame, latency);
+
+        self.client
+            .publish_value(&self.raw_bytes_topic, &Binary(packet))
+            .await?;
+        self.client
+            .publish_value(&self.latency_topic, &F64(latency))
+            .await?;
+        self.client
+            .publish_value(&self.has_target_topic, &Boolean(!frame.targets.is_empty()))
+            .await?;
+
+        // PhotonVision zeros the summary topics when there is no target, instead of leaving the last one up
+        let summary = Summary::new(frame);
+        self.client
+            .publish_value(&self.pitch_topic, &F64(summary.pitch))
+            .await?;
+        self.client
+            .publish_value(&self.yaw_topic, &F64(summary.yaw))
+            .await?;
+        self.client
+            .publish_value(&self.area_topic, &F64(summary.area))
+            .await?;
+        self.client
+            .publish_value(&self.skew_topic, &F64(summary.skew))
+            .await?;
+        self.client
+            .publish_value(&self.pose_topic, &Array(summary.pose.into_iter().map(F64).collect()))
+            .await?;
+
+        self.heartbeat += 1;
+        self.client
+            .publish_value(&self.heartbeat_topic, &Integer(self.heartbeat.into()))
+            .await?;
+        Ok(())
+    }
+
+    /// Publishes the status under `health` next to the camera's topics
+    pub(crate) async fn publish_health(&mut self, status: &HealthStatus) -> Result<(), DataError> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.health_topics.publish(&self.client, status).await
+    }
+}
+
+/// The best target on the dashboard topics, all zeros without one
+#[derive(Debug, Default, PartialEq)]
+struct Summary {
+    yaw: f64,
+    pitch: f64,
+    area: f64,
+    skew: f64,
+    /// Camera to target as x, y, z, qw, qx, qy, qz
+    pose: [f64; 7],
+}
+
+impl Summary {
+    fn new(frame: &VisionFrame) -> Self {
+        let Some(target) = frame.targets.first() else {
+            return Summary::default();
+        };
+        let pose = camera_to_tag(&target.best);
+        let (qw, qx, qy, qz) = quaternion(&pose);
+        Summary {
+            yaw: target.yaw,
+            pitch: target.pitch,
+            area: target.area,
+            skew: target.skew,
+            pose: [pose.translation.x, pose.translation.y, pose.translation.z, qw, qx, qy, qz],
+        }
+    }
+}
+
+/// Packs the frame into a PhotonLib 2024 `PhotonPipelineResult`
+pub fn pack_result(frame: &VisionFrame, latency_ms: f64) -> Vec<u8> {
+    let mut packet = vec![];
+    packet.extend_from_slice(&latency_ms.to_be_bytes());
+
+    // Multi-tag needs a field layout, which PhotonLib can also do on its own from the targets
+    packet.push(false as u8);
+    for _ in 0..MAX_MULTI_TAG_IDS {
+        packet.extend_from_slice(&(-1i16).to_be_bytes());
+    }
+
+    let targets = &frame.targets[..frame.targets.len().min(i8::MAX as usize)];
+    packet.push(targets.len() as u8);
+    for target in targets {
+        pack_target(&mut packet, target);
+    }
+    packet
+}
+
+fn pack_target(packet: &mut Vec<u8>, target: &TagTarget) {
+    for value in [target.yaw, target.pitch, target.area, target.skew] {
+        packet.extend_from_slice(&value.to_be_bytes());
+    }
+    packet.extend_from_slice(&(target.id as i32).to_be_bytes());
+
+    let best = camera_to_tag(&target.best);
+    // With no alternate the best pose goes in both, same as PhotonVision does
+    let alt = target.alt.as_ref().map(camera_to_tag).unwrap_or(best);
+    pack_transform(packet, &best);
+    pack_transform(packet, &alt);
+    packet.extend_from_slice(&target.ambiguity.to_be_bytes());
+
+    for corner in target.corners.iter() {
+        packet.extend_from_slice(&corner[0].to_be_bytes());
+        packet.extend_from_slice(&corner[1].to_be_bytes());
+    }
+    packet.push(target.corners.len() as u8);
+    for corner in target.corners.iter() {
+        packet.extend_from_slice(&corner[0].to_be_bytes());
+        packet.extend_from_slice(&corner[1].to_be_bytes());
+    }
+}
+
+/// Packs a `Transform3d` as translation then quaternion (w, x, y, z)
+fn pack_transform(packet: &mut Vec<u8>, transform: &Isometry3<f64>) {
+    let (qw, qx, qy, qz) = quaternion(transform);
+    for value in [
+        transform.translation.x,
+        transform.translation.y,
+        transform.translation.z,
+        qw,
+        qx,
+        qy,
+        qz,
+    ] {
+        packet.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn quaternion(transform: &Isometry3<f64>) -> (f64, f64, f64, f64) {
+    let q = transform.rotation;
+    (q.w, q.i, q.j, q.k)
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Rotation3;
+
+    use super::*;
+    use crate::geometry::{camera_to_nwu, wpilib_tag_to_apriltag};
+    use crate::process::tests::sample_target;
+
+    /// Bytes written out by hand from PhotonLib's `Packet` encoding, whitespace is ignored
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(|byte| !byte.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn packs_a_photonlib_result() {
+        let mut frame = crate::process::tests::sample_frame();
+        let mut target = sample_target();
+        // Lined up with the field axes in WPILib terms, so the quaternion is the identity
+        target.best.rotation =
+            Rotation3::from_matrix(&(camera_to_nwu().transpose() * wpilib_tag_to_apriltag().transpose()));
+        frame.targets = vec![target];
+
+        let mut expected = hex("4029000000000000 00"); // latencyMillis 12.5, no multi-tag result
+        expected.extend(hex(&"ffff".repeat(MAX_MULTI_TAG_IDS))); // fiducialIDsUsed, all unused
+        expected.extend(hex("01")); // targetCount
+        expected.extend(hex(
+            "4024000000000000 c014000000000000 3ff8000000000000 0000000000000000 00000007", // yaw pitch area skew id
+        ));
+        let transform = "4000000000000000 bfe0000000000000 3fd0000000000000 \
+                         3ff0000000000000 0000000000000000 0000000000000000 0000000000000000";
+        expected.extend(hex(transform)); // bestCameraToTarget (2, -0.5, 0.25), no rotation
+        expected.extend(hex(transform)); // altCameraToTarget, the best again without an alternate
+        expected.extend(hex("3fc0000000000000")); // poseAmbiguity 0.125
+        let corners = "4059000000000000 4069000000000000 4061800000000000 4069000000000000 \
+                       4061800000000000 4064000000000000 4059000000000000 4064000000000000";
+        expected.extend(hex(corners)); // minAreaRectCorners
+        expected.extend(hex("04")); // detectedCorners count
+        expected.extend(hex(corners));
+
+        assert_eq!(pack_result(&frame, 12.5), expected);
+    }
+
+    #[test]
+    fn packs_an_empty_result() {
+        let mut frame = crate::process::tests::sample_frame();
+        frame.targets.clear();
+        let packet = pack_result(&frame, 0.0);
+        assert_eq!(packet.len(), 8 + 1 + 2 * MAX_MULTI_TAG_IDS + 1);
+        assert_eq!(packet[packet.len() - 1], 0);
+    }
+
+    #[test]
+    fn summarizes_the_best_target() {
+        let mut frame = crate::process::tests::sample_frame();
+        let mut target = sample_target();
+        target.best.rotation =
+            Rotation3::from_matrix(&(camera_to_nwu().transpose() * wpilib_tag_to_apriltag().transpose()));
+        frame.targets = vec![target];
+
+        let summary = Summary::new(&frame);
+        assert_eq!((summary.yaw, summary.pitch, summary.area, summary.skew), (10.0, -5.0, 1.5, 0.0));
+        assert_eq!(summary.pose, [2.0, -0.5, 0.25, 1.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn no_target_is_zeros() {
+        let mut frame = crate::process::tests::sample_frame();
+        frame.targets.clear();
+        assert_eq!(Summary::new(&frame), Summary::default());
+        assert_eq!(Summary::default().pose, [0.0; 7]);
+    }
+}
@@ -0,0 +1,206 @@
+//! # Robot values from NetworkTables
+//!
+//! Subscribes to the topics in `InterfaceConfig::robot_topics` and keeps the shared `RobotState` up to date with them.
+use std::time::{Duration, Instant};
+
+use network_tables::v4::MessageData;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use super::client::connect;
+use crate::config::{InterfaceConfig, RobotTopics};
+use crate::interface::DataError;
+use crate::robot::{Alliance, MatchState, RobotState};
+
+/// How long to wait before trying to reconnect after the subscriber fails
+pub const SUBSCRIBER_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Keeps the robot state updated from NetworkTables until shutdown or until the state has no more readers
+pub(crate) async fn run_robot_subscriber(
+    config: InterfaceConfig,
+    state_tx: watch::Sender<RobotState>,
+    shutdown: CancellationToken,
+) {
+    if config.robot_topics.is_empty() {
+        return;
+    }
+    let topics = topics(&config.robot_topics);
+
+    // Only the first failure in a row is a warning, the robot may just not be on yet
+    let mut failing = false;
+    while !state_tx.is_closed() {
+        tokio::select! {
+            result = subscribe(&config, &topics, &state_tx) => {
+                match &result {
+                    Err(err) if !failing => warn!("Failed to subscribe to robot values: {}", err),
+                    Err(err) => debug!("Failed to subscribe to robot values: {}", err),
+                    Ok(()) => {}
+                }
+                failing = result.is_err();
+            }
+            _ = shutdown.cancelled() => return,
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(SUBSCRIBER_RETRY_DELAY) => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+async fn subscribe(
+    config: &InterfaceConfig,
+    topics: &[String],
+    state_tx: &watch::Sender<RobotState>,
+) -> Result<(), DataError> {
+    // The client resubscribes on its own when it reconnects
+    let (client, _connected) = connect(config).await?;
+    let mut subscription = client.subscribe(topics).await?;
+    info!("Subscribed to robot values! {:?}", topics);
+
+    while let Some(message) = subscription.next().await {
+        state_tx.send_if_modified(|state| update(state, &config.robot_topics, &message));
+    }
+    Ok(())
+}
+
+/// Every topic that is set
+fn topics(topics: &RobotTopics) -> Vec<String> {
+    [&topics.heading, &topics.alliance, &topics.match_state, &topics.pipeline]
+        .into_iter()
+        .filter(|topic| !topic.is_empty())
+        .cloned()
+        .collect()
+}
+
+/// Decodes the `FMSControlData` bit field the driver station publishes
+fn match_state(word: i64) -> MatchState {
+    MatchState {
+        enabled: word & 0x01 != 0,
+        autonomous: word & 0x02 != 0,
+        test: word & 0x04 != 0,
+        emergency_stop: word & 0x08 != 0,
+        fms_attached: word & 0x10 != 0,
+        ds_attached: word & 0x20 != 0,
+    }
+}
+
+/// Applies a value to the state, returns whether anything changed
+fn update(state: &mut RobotState, topics: &RobotTopics, message: &MessageData) -> bool {
+    let name = message.topic_name.as_str();
+    if name == topics.heading {
+        if let Some(heading) = message.data.as_f64() {
+            state.heading = Some((heading, Instant::now()));
+            return true;
+        }
+    } else if name == topics.alliance {
+        if let Some(is_red) = message.data.as_bool() {
+            state.alliance = Some(if is_red { Alliance::Red } else { Alliance::Blue });
+            return true;
+        }
+    } else if name == topics.match_state {
+        if let Some(word) = message.data.as_i64() {
+            state.match_state = match_state(word);
+            return true;
+        }
+    } else if name == topics.pipeline {
+        if let Some(name) = message.data.as_str() {
+            state.pipeline_name = Some(name.to_string());
+            return true;
+        }
+        if let Some(pipeline) = message.data.as_i64().or(message.data.as_f64().map(|v| v as i64)) {
+            state.pipeline = Some(pipeline);
+            // The robot switched to asking by index
+            state.pipeline_name = None;
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use network_tables::v4::Type;
+    use network_tables::Value;
+
+    fn message(topic: &str, r#type: Type, data: Value) -> MessageData {
+        MessageData { topic_name: topic.to_string(), timestamp: 0, r#type, data }
+    }
+
+    fn robot_topics() -> RobotTopics {
+        RobotTopics { heading: "/Robot/Heading".to_string(), pipeline: "/Robot/Pipeline".to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn decodes_the_control_word() {
+        assert_eq!(match_state(0), MatchState::default());
+
+        let state = match_state(0x01 | 0x02 | 0x10 | 0x20);
+        assert!(state.enabled && state.autonomous && state.fms_attached && state.ds_attached);
+        assert!(!state.test && !state.emergency_stop);
+
+        let state = match_state(0x04 | 0x08);
+        assert!(state.test && state.emergency_stop);
+        assert!(!state.enabled && !state.autonomous);
+    }
+
+    #[test]
+    fn skips_unset_topics() {
+        let defaults = RobotTopics::default();
+        assert_eq!(topics(&defaults), vec![defaults.alliance.clone(), defaults.match_state.clone()]);
+        assert!(!defaults.is_empty());
+
+        let none = RobotTopics { alliance: String::new(), match_state: String::new(), ..Default::default() };
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn updates_heading_alliance_and_match_state() {
+        let topics = robot_topics();
+        let mut state = RobotState::default();
+
+        assert!(update(&mut state, &topics, &message("/Robot/Heading", Type::Double, Value::from(90.5))));
+        assert_eq!(state.heading.map(|(heading, _)| heading), Some(90.5));
+
+        assert!(update(&mut state, &topics, &message(&topics.alliance, Type::Boolean, Value::from(true))));
+        assert_eq!(state.alliance, Some(Alliance::Red));
+        assert!(update(&mut state, &topics, &message(&topics.alliance, Type::Boolean, Value::from(false))));
+        assert_eq!(state.alliance, Some(Alliance::Blue));
+
+        assert!(update(&mut state, &topics, &message(&topics.match_state, Type::Int, Value::from(0x21))));
+        assert!(state.match_state.enabled && state.match_state.ds_attached);
+        assert!(!state.match_state.fms_attached);
+    }
+
+    #[test]
+    fn pipeline_by_name_or_index() {
+        let topics = robot_topics();
+        let mut state = RobotState::default();
+
+        assert!(update(&mut state, &topics, &message("/Robot/Pipeline", Type::String, Value::from("speaker"))));
+        assert_eq!(state.pipeline_name.as_deref(), Some("speaker"));
+
+        assert!(update(&mut state, &topics, &message("/Robot/Pipeline", Type::Int, Value::from(2))));
+        assert_eq!(state.pipeline, Some(2));
+        assert_eq!(state.pipeline_name, None);
+
+        assert!(update(&mut state, &topics, &message("/Robot/Pipeline", Type::Double, Value::from(3.0))));
+        assert_eq!(state.pipeline, Some(3));
+    }
+
+    #[test]
+    fn ignores_unknown_topics_and_wrong_types() {
+        let topics = robot_topics();
+        let mut state = RobotState::default();
+
+        assert!(!update(&mut state, &topics, &message("/Robot/Other", Type::Double, Value::from(1.0))));
+        assert!(!update(&mut state, &topics, &message("/Robot/Heading", Type::String, Value::from("north"))));
+        assert!(!update(&mut state, &topics, &message(&topics.alliance, Type::Int, Value::from(1))));
+        assert!(!update(&mut state, &topics, &message(&topics.match_state, Type::Double, Value::from(1.5))));
+        assert!(!update(&mut state, &topics, &message("/Robot/Pipeline", Type::Boolean, Value::from(true))));
+        assert_eq!(state.heading, None);
+        assert_eq!(state.alliance, None);
+        assert_eq!(state.pipeline, None);
+    }
+}
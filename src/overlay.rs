@@ -0,0 +1,187 @@
+//! # Detection overlays
+//!
+//! Draws what the pipeline found onto a color copy of the frame: every target's outline, its axes, and its id and
+//! distance, plus the region of interest when only a crop was searched. The image can be smaller than the frame the
+//! results came from (the stream scales it down), everything is scaled to fit.
+//!
+//! Text is drawn with a tiny built in bitmap font so no font file has to be shipped to the coprocessor, it only has
+//! the characters the labels use.
+//!
+//! `axis_lines` and `cube_lines` project a target's pose into the frame, the GUI draws them with its own painter.
+use apriltag::TagParams;
+use image::{Rgb, RgbImage};
+use imageproc::drawing::{draw_filled_rect_mut, draw_hollow_rect_mut, draw_line_segment_mut};
+use imageproc::rect::Rect;
+use nalgebra::Vector3;
+
+use crate::geometry::project;
+use crate::process::{TagTarget, VisionFrame};
+
+pub const OUTLINE_COLOR: Rgb<u8> = Rgb([0, 255, 0]);
+pub const ROI_COLOR: Rgb<u8> = Rgb([255, 200, 0]);
+pub const TEXT_COLOR: Rgb<u8> = Rgb([255, 255, 255]);
+pub const TEXT_BACKGROUND: Rgb<u8> = Rgb([0, 0, 0]);
+/// Tag x, y and the axis out of the tag's face
+pub const AXIS_COLORS: [Rgb<u8>; 3] = [Rgb([255, 0, 0]), Rgb([0, 255, 0]), Rgb([0, 128, 255])];
+
+/// Glyphs are 3 pixels wide and 5 tall, one row per byte with the leftmost pixel in the highest of the 3 bits
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+
+/// Draws every target in the results, and the region of interest if there was one, onto the image
+pub fn annotate(image: &mut RgbImage, frame: &VisionFrame, params: &TagParams) {
+    if frame.width == 0 || frame.height == 0 {
+        return;
+    }
+    let scale = image.width() as f64 / frame.width as f64;
+    // One pixel of line or font for every 320 pixels across, so it stays readable at any size
+    let size = (image.width() / 320).max(1);
+
+    if let Some(roi) = frame.roi {
+        let x = (roi.x as f64 * scale) as i32;
+        let y = (roi.y as f64 * scale) as i32;
+        let width = ((roi.width as f64 * scale) as u32).max(1);
+        let height = ((roi.height as f64 * scale) as u32).max(1);
+        for offset in 0..size as i32 {
+            let shrink = 2 * offset as u32;
+            if width > shrink && height > shrink {
+                let rect = Rect::at(x + offset, y + offset).of_size(width - shrink, height - shrink);
+                draw_hollow_rect_mut(image, rect, ROI_COLOR);
+            }
+        }
+    }
+
+    for target in frame.targets.iter() {
+        annotate_target(image, target, params, scale, size);
+    }
+}
+
+fn annotate_target(image: &mut RgbImage, target: &TagTarget, params: &TagParams, scale: f64, size: u32) {
+    let point = |[x, y]: [f64; 2]| ((x * scale) as f32, (y * scale) as f32);
+
+    for i in 0..4 {
+        line(image, point(target.corners[i]), point(target.corners[(i + 1) % 4]), OUTLINE_COLOR, size);
+    }
+
+    for (start, end, color) in axis_lines(target, params) {
+        line(image, point(start), point(end), color, size);
+    }
+
+    let (x, y) = point(target.center);
+    let distance = Vector3::from(target.best.translation).norm();
+    let label_height = (GLYPH_HEIGHT + 2) * size;
+    text(image, &format!("#{}", target.id), x as i32, y as i32 - label_height as i32, size);
+    text(image, &format!("{:.2}m", distance), x as i32, y as i32 + size as i32, size);
+}
+
+/// The best pose's axes as lines in frame pixels, each in its color from `AXIS_COLORS`.
+///
+/// They are half a tag long, the third axis is drawn out of the face towards the camera where it can be seen. Axes
+/// that end up behind the camera are left out.
+pub fn axis_lines(target: &TagTarget, params: &TagParams) -> Vec<([f64; 2], [f64; 2], Rgb<u8>)> {
+    let length = params.tagsize / 2.0;
+    let axes = [
+        Vector3::new(length, 0.0, 0.0),
+        Vector3::new(0.0, length, 0.0),
+        Vector3::new(0.0, 0.0, -length),
+    ];
+    let Some(start) = project_tag_point(target, &Vector3::zeros(), params) else {
+        return vec![];
+    };
+    axes.iter()
+        .zip(AXIS_COLORS)
+        .filter_map(|(axis, color)| Some((start, project_tag_point(target, axis, params)?, color)))
+        .collect()
+}
+
+/// Edges of a cube standing on the tag's face, as lines in frame pixels, from the best pose
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+pub fn cube_lines(target: &TagTarget, params: &TagParams) -> Vec<([f64; 2], [f64; 2])> {
+    let half = params.tagsize / 2.0;
+    // The face on the tag then the face a tag's width out towards the camera
+    let corners: Vec<Option<[f64; 2]>> = [0.0, -params.tagsize]
+        .iter()
+        .flat_map(|z| [[-half, -half], [half, -half], [half, half], [-half, half]].map(|[x, y]| Vector3::new(x, y, *z)))
+        .map(|corner| project_tag_point(target, &corner, params))
+        .collect();
+
+    let mut edges = vec![];
+    for i in 0..4 {
+        edges.push((i, (i + 1) % 4));
+        edges.push((i + 4, (i + 1) % 4 + 4));
+        edges.push((i, i + 4));
+    }
+    edges
+        .into_iter()
+        .filter_map(|(a, b)| Some((corners[a]?, corners[b]?)))
+        .collect()
+}
+
+/// A point in the tag frame projected into the frame through the best pose
+fn project_tag_point(target: &TagTarget, point: &Vector3<f64>, params: &TagParams) -> Option<[f64; 2]> {
+    project(&(target.best.rotation * point + Vector3::from(target.best.translation)), params)
+}
+
+/// A line `size` pixels thick
+fn line(image: &mut RgbImage, start: (f32, f32), end: (f32, f32), color: Rgb<u8>, size: u32) {
+    let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+    let length = (dx * dx + dy * dy).sqrt().max(f32::EPSILON);
+    // Step sideways to the line for each extra pixel of thickness
+    let (nx, ny) = (-dy / length, dx / length);
+    for i in 0..size {
+        let offset = i as f32 - (size - 1) as f32 / 2.0;
+        draw_line_segment_mut(
+            image,
+            (start.0 + nx * offset, start.1 + ny * offset),
+            (end.0 + nx * offset, end.1 + ny * offset),
+            color,
+        );
+    }
+}
+
+/// Draws the text centered on `x` with its top at `y`, over a dark box so it shows up on anything
+fn text(image: &mut RgbImage, text: &str, x: i32, y: i32, size: u32) {
+    let advance = (GLYPH_WIDTH + 1) * size;
+    let width = advance * text.chars().count() as u32 + size;
+    let height = (GLYPH_HEIGHT + 2) * size;
+    let left = x - width as i32 / 2;
+    draw_filled_rect_mut(image, Rect::at(left, y).of_size(width, height), TEXT_BACKGROUND);
+
+    for (i, c) in text.chars().enumerate() {
+        let Some(rows) = glyph(c) else {
+            continue;
+        };
+        let glyph_x = left + (size + i as u32 * advance) as i32;
+        let glyph_y = y + size as i32;
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+                let px = glyph_x + (column * size) as i32;
+                let py = glyph_y + (row as u32 * size) as i32;
+                draw_filled_rect_mut(image, Rect::at(px, py).of_size(size, size), TEXT_COLOR);
+            }
+        }
+    }
+}
+
+fn glyph(c: char) -> Option<[u8; GLYPH_HEIGHT as usize]> {
+    Some(match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        'm' => [0b000, 0b000, 0b110, 0b111, 0b101],
+        _ => return None,
+    })
+}
@@ -1,22 +1,59 @@
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 
-use crate::{config::CameraCalibration, DetectionConfig};
-use apriltag::{Detector, DetectorBuilder, Image, TagParams};
-use apriltag_image::prelude::*;
+use std::f64::consts::PI;
+
+use crate::field::FieldLayout;
+use crate::frame::Frame;
+use crate::geometry::reprojection_error;
+use crate::mailbox::{FrameCounts, FrameReceiver};
+use crate::reload::LiveConfig;
+use crate::roi::{Roi, RoiTracker};
+use crate::robot::{Alliance, MatchState, RobotState};
+use crate::tracking::{FilteredPose, Tracker};
+use crate::uncertainty::{robot_std_devs, tag_std_devs, StdDevs};
+use crate::config::{DetectorParams, OutputKind};
+use crate::DetectionConfig;
+use apriltag::image_buf::DEFAULT_ALIGNMENT_U8;
+use apriltag::{Detection, Detector, DetectorBuilder, Image, Pose, TagParams};
 use bondrewd::Bitfields;
-use crossbeam_channel::*;
-use image::*;
 use nalgebra::*;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+use tracing::{info, trace, trace_span};
+
+/// Layout version sent as the first byte of every `VisionData`, bumped whenever the fields below change
+pub const VISION_DATA_VERSION: u8 = 2;
 
-#[derive(Debug, Clone, Bitfields)]
-#[bondrewd(default_endianness = "le", enforce_bytes = 65)]
+/// The best target as sent over the binary outputs (serial, TCP server and UDP), after the sync bytes.
+///
+/// 90 bytes, little endian:
+///
+/// | Offset | Field | Type | |
+/// |---|---|---|---|
+/// | 0 | `version` | u8 | `VISION_DATA_VERSION` |
+/// | 1 | `detected` | u8 | 1 if there is a target, the rest is zeros otherwise |
+/// | 2 | `tag_id` | u64 | |
+/// | 10 | `timestamp` | f64 | When the camera handed the frame over, seconds since the Unix epoch on the coprocessor |
+/// | 18 | `translation` | [f64; 3] | Camera to tag in meters, camera frame (x right, y down, z forward) |
+/// | 42 | `rotation` | [f64; 3] | Tag rotation in the camera frame as roll, pitch, yaw in radians |
+/// | 66 | `std_devs` | [f64; 3] | Field frame x, y (meters) and heading (radians) for `addVisionMeasurement` |
+///
+/// Version 1 was 65 bytes with no version byte and no standard deviations.
+///
+/// The first byte also tells it apart from the other packet sent after the same sync bytes: `VISION_DATA_VERSION`
+/// stays below 0x80, `HealthData` (57 bytes, laid out in `src/health.rs`) starts with `HEALTH_DATA_VERSION`, 0x81.
+#[derive(Debug, Clone, Bitfields, Serialize)]
+#[bondrewd(default_endianness = "le", enforce_bytes = 90)]
 pub struct VisionData {
+    pub version: u8,
     #[bondrewd(bit_length = 8)]
     pub detected: bool,
     pub tag_id: u64,
     pub timestamp: f64,
     pub translation: [f64; 3],
     pub rotation: [f64; 3],
+    /// Standard deviations of the robot pose in field axes: x, y (meters) and heading (radians)
+    pub std_devs: [f64; 3],
 }
 
 impl VisionData {
@@ -26,94 +63,634 @@ impl VisionData {
         timestamp: f64,
         translation: [f64; 3],
         rotation: [f64; 3],
+        std_devs: [f64; 3],
     ) -> Self {
         VisionData {
+            version: VISION_DATA_VERSION,
             detected,
             tag_id,
             timestamp,
             translation,
-            rotation
+            rotation,
+            std_devs,
+        }
+    }
+}
+
+/// Pose of a tag relative to the camera, as given by the AprilTag library.
+///
+/// The camera frame has x right, y down and z forward out of the lens.
+/// The tag frame has x right, y down and z pointing into the tag.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagPose {
+    /// Translation from the camera to the tag center in meters
+    pub translation: [f64; 3],
+    /// Rotation from the tag frame into the camera frame
+    pub rotation: Rotation3<f64>,
+    /// Object space error of this pose from the pose estimation
+    pub error: f64,
+}
+
+impl TagPose {
+    fn from_pose(pose: &Pose, error: f64) -> Self {
+        let mut rotation = Rotation3::from_matrix(
+            &MatrixView3::from_slice(pose.rotation().data()).transpose(),
+        );
+        rotation.renormalize();
+
+        let translation: Translation3<f64> = MatrixView3x1::from_slice(pose.translation().data())
+            .into_owned()
+            .into();
+
+        TagPose {
+            translation: [translation.x, translation.y, translation.z],
+            rotation,
+            error,
         }
     }
+
+    /// Rotation as euler angles (roll, pitch, yaw)
+    pub fn euler_angles(&self) -> [f64; 3] {
+        let (roll, pitch, yaw) = self.rotation.euler_angles();
+        [roll, pitch, yaw]
+    }
 }
 
+/// A single tag found in a frame
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagTarget {
+    pub id: u32,
+    pub hamming: u32,
+    pub decision_margin: f32,
+    /// Tag center in pixels
+    pub center: [f64; 2],
+    /// Tag corners in pixels, in the order the AprilTag library gives them
+    pub corners: [[f64; 2]; 4],
+    /// Horizontal angle from the camera center to the tag center in degrees, positive to the left (counter-clockwise)
+    pub yaw: f64,
+    /// Vertical angle from the camera center to the tag center in degrees, positive up
+    pub pitch: f64,
+    /// Area the tag takes up as a percent of the frame
+    pub area: f64,
+    /// Angle of the tag's top edge from horizontal in degrees
+    pub skew: f64,
+    /// The pose with the lowest error
+    pub best: TagPose,
+    /// The other pose the tag could be in, a flat tag seen head on can look the same flipped
+    pub alt: Option<TagPose>,
+    /// Ratio of the best pose error to the alternate pose error, close to 1 means we can't tell them apart
+    pub ambiguity: f64,
+    /// Mean distance in pixels between the detected corners and the corners of the best pose projected back
+    pub reprojection_error: f64,
+    /// Standard deviations of the best pose
+    pub std_devs: StdDevs,
+    /// Best pose smoothed across frames, when tracking is enabled
+    pub filtered: Option<FilteredPose>,
+}
+
+/// Milliseconds spent on each stage of the pipeline for a frame
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct StageLatency {
+    /// Picking the region of interest and copying the frame into the detector's image
+    pub preprocess: f64,
+    /// Finding and decoding tags
+    pub detection: f64,
+    /// Pose estimation, filtering and the robot pose
+    pub pose: f64,
+}
+
+/// Everything found in a single frame
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VisionFrame {
+    /// When the camera handed the frame over, in seconds since the Unix epoch
+    pub timestamp: f64,
+    /// Frame size in pixels
+    pub width: u32,
+    pub height: u32,
+    /// Milliseconds spent on detection and pose estimation for this frame
+    pub pipeline_latency: f64,
+    /// How `pipeline_latency` splits up
+    #[serde(default)]
+    pub stages: StageLatency,
+    /// Tags that passed the decision margin, best first (see `sort_best_first`)
+    pub targets: Vec<TagTarget>,
+    /// Field relative robot pose from the least ambiguous tag on the field, when a field layout is configured
+    pub robot_pose: Option<Isometry3<f64>>,
+    /// Standard deviations of the robot pose
+    pub robot_std_devs: Option<StdDevs>,
+    /// Robot pose smoothed across frames, when tracking is enabled and there is a field layout
+    pub filtered_robot_pose: Option<FilteredPose>,
+    /// Match state the robot reported when the frame was processed
+    pub match_state: MatchState,
+    /// Pipeline index the robot asked for when the frame was processed
+    pub pipeline: i64,
+    /// Name of the pipeline that processed the frame
+    #[serde(default)]
+    pub pipeline_name: String,
+    /// Outputs the pipeline sends its results to, every output when `None`
+    #[serde(skip)]
+    pub outputs: Option<Vec<OutputKind>>,
+    /// Part of the frame tags were searched for in, `None` when it was the whole frame
+    #[serde(default)]
+    pub roi: Option<Roi>,
+    /// Frames captured, processed and dropped so far, including this one
+    pub frames: FrameCounts,
+}
+
+impl VisionFrame {
+    /// Packs the best target into the `VisionData` packet the robot reads
+    pub fn vision_data(&self) -> VisionData {
+        match self.targets.first() {
+            Some(tag) => VisionData::new(
+                true,
+                tag.id as u64,
+                self.timestamp,
+                tag.best.translation,
+                tag.best.euler_angles(),
+                // The tag's own are the same along every axis, so they hold in field axes too when there isn't a
+                // robot pose to go with
+                self.robot_std_devs.unwrap_or(tag.std_devs).as_array(),
+            ),
+            None => VisionData::new(
+                false,
+                0,
+                self.timestamp,
+                [0.0, 0.0, 0.0],
+                [0.0, 0.0, 0.0],
+                [0.0, 0.0, 0.0],
+            ),
+        }
+    }
+}
+
+/// Tags with a decision margin at or below this are thrown out as false positives, unless the config says otherwise
+pub const MIN_DECISION_MARGIN: f32 = 55.0;
+
+/// Number of orthogonal iterations to run when estimating tag poses
+pub const POSE_ITERATIONS: usize = 50;
+
 pub struct Process {
-    image_rx: Receiver<DynamicImage>,
-    data_tx: Sender<VisionData>,
+    image_rx: FrameReceiver<Frame>,
+    data_tx: mpsc::Sender<VisionFrame>,
     detector: Detector,
     cal: TagParams,
+    field: Option<FieldLayout>,
+    robot_to_camera: Isometry3<f64>,
+    config: DetectionConfig,
+    robot_rx: watch::Receiver<RobotState>,
+    tracker: Option<Tracker>,
+    live_rx: watch::Receiver<LiveConfig>,
+    live: LiveConfig,
+    /// Name of the pipeline `config` came from
+    pipeline: String,
+    outputs: Option<Vec<OutputKind>>,
+    roi: Option<RoiTracker>,
+    /// Reused between frames as long as the size it needs stays the same
+    detector_image: Option<Image>,
 }
 
 impl Process {
     pub fn new(
-        image_rx: Receiver<DynamicImage>,
-        data_tx: Sender<VisionData>,
-        cal: CameraCalibration,
-        config: DetectionConfig,
+        image_rx: FrameReceiver<Frame>,
+        data_tx: mpsc::Sender<VisionFrame>,
+        field: Option<FieldLayout>,
+        robot_rx: watch::Receiver<RobotState>,
+        mut live_rx: watch::Receiver<LiveConfig>,
     ) -> Self {
-        let detector = DetectorBuilder::new();
-        let detector = detector.add_family_bits(&config.families, 1);
-
-        let mut detector = detector.build().unwrap();
-        detector.set_thread_number(5);
-
-        let cal = (&cal).into();
+        let live = live_rx.borrow_and_update().clone();
+        let (pipeline, pipeline_config) = live.select(&robot_rx.borrow());
+        let (pipeline, pipeline_config) = (pipeline.to_string(), pipeline_config.clone());
 
         Process {
             image_rx,
             data_tx,
-            detector,
-            cal,
-        }
-    }
-
-    pub fn update(&mut self) {
-        if let Ok(image) = self.image_rx.recv() {
-            let image_buf = Image::from_image_buffer(&image.to_luma8());
-            let detections = self.detector.detect(&image_buf);
-            let timestamp = std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_micros(1)).as_secs_f64();
-
-            if !detections.is_empty() {
-                let tag = &detections[0];
-                if tag.decision_margin() > 55.0 {
-                    if let Some(pose) = tag.estimate_tag_pose(&self.cal) {
-                        let mut rotation = Rotation3::from_matrix(
-                            &MatrixView3::from_slice(pose.rotation().data()).transpose(),
-                        );
-                        rotation.renormalize();
-                        let rotation = rotation.euler_angles();
-
-                        let transform: Translation3<f64> =
-                            MatrixView3x1::from_slice(pose.translation().data())
-                                .into_owned()
-                                .into();
-
-                        let _ = self.data_tx.send(VisionData::new(
-                            true,
-                            tag.id() as u64,
-                            timestamp,
-                            [transform.x, transform.y, transform.z],
-                            [rotation.0, rotation.1, rotation.2],
-                        ));
-                    }
-                } else {
-                    let _ = self.data_tx.send(VisionData::new(
-                        false,
-                        0,
-                        timestamp,
-                        [0.0, 0.0, 0.0],
-                        [0.0, 0.0, 0.0],
-                    ));
+            detector: build_detector(&pipeline_config.detection_config),
+            roi: roi_tracker(&pipeline_config.detection_config),
+            detector_image: None,
+            cal: (&live.calibration).into(),
+            field,
+            robot_to_camera: live.robot_to_camera,
+            config: pipeline_config.detection_config,
+            robot_rx,
+            tracker: live.tracking.enabled.then(|| Tracker::new(live.tracking.clone())),
+            live_rx,
+            live,
+            pipeline,
+            outputs: pipeline_config.outputs,
+        }
+    }
+
+    /// Switches to the newest live config if it changed since the last frame, and to whichever pipeline the
+    /// robot wants
+    fn apply_live_config(&mut self, robot: &RobotState) {
+        let changed = self.live_rx.has_changed().unwrap_or(false);
+        if changed {
+            self.live = self.live_rx.borrow_and_update().clone();
+            self.cal = (&self.live.calibration).into();
+            self.robot_to_camera = self.live.robot_to_camera;
+            // Old tracks were filtered with the old calibration and mount, start over
+            self.tracker = self.live.tracking.enabled.then(|| Tracker::new(self.live.tracking.clone()));
+            info!("Process Thread Using the New Config!");
+        }
+
+        let (name, pipeline) = self.live.select(robot);
+        if !changed && name == self.pipeline {
+            return;
+        }
+        if pipeline.detection_config.families != self.config.families {
+            self.detector = build_detector(&pipeline.detection_config);
+        } else {
+            configure_detector(&mut self.detector, &pipeline.detection_config.detector);
+        }
+        self.config = pipeline.detection_config.clone();
+        self.outputs = pipeline.outputs.clone();
+        self.roi = roi_tracker(&self.config);
+        if name != self.pipeline {
+            info!("Switched to the {} Pipeline!", name);
+            self.pipeline = name.to_string();
+        }
+    }
+
+    /// Processes frames until the camera or the outputs go away
+    pub fn run(&mut self) {
+        while self.update() {}
+        info!("Process Thread Stopped!");
+    }
+
+    /// Processes the next frame, returns false once there are no more frames or nowhere to send the results
+    pub fn update(&mut self) -> bool {
+        if let Some(image) = self.image_rx.recv() {
+            let frame = self.process_frame(&image);
+            // Called from the process thread, never from inside the runtime
+            self.data_tx.blocking_send(frame).is_ok()
+        } else {
+            false
+        }
+    }
+
+    /// Runs detection, pose estimation and tracking on a single frame
+    pub fn process_frame(&mut self, image: &Frame) -> VisionFrame {
+        // Stamped on capture, so the time it waited in the mailbox counts towards the latency the robot sees
+        let timestamp = image.timestamp();
+
+        let robot = self.robot_rx.borrow().clone();
+        self.apply_live_config(&robot);
+
+        let frame_span = trace_span!("frame", pipeline = %self.pipeline).entered();
+        let start = Instant::now();
+        let preprocess = trace_span!("preprocess").entered();
+        let roi = self.roi.as_mut().and_then(|roi| roi.next_roi(image.width(), image.height()));
+        let decimation = match roi {
+            Some(_) => self.config.roi.decimation,
+            None => self.config.detector.decimation,
+        };
+        self.detector.set_decimation(decimation);
+        let detector_image = fill_detector_image(&mut self.detector_image, image, roi);
+        drop(preprocess);
+        let preprocess_done = Instant::now();
+        let detections = trace_span!("detection").in_scope(|| match detector_image {
+            Some(detector_image) => self.detector.detect(detector_image),
+            None => vec![],
+        });
+        let detection_done = Instant::now();
+        let pose_span = trace_span!("pose").entered();
+
+        let mut targets = vec![];
+        for tag in detections.iter() {
+            if tag.decision_margin() <= self.config.detector.min_decision_margin {
+                continue;
+            }
+            if !self.tag_allowed(tag.id() as u32, robot.alliance) {
+                continue;
+            }
+            if let Some(mut target) = self.target(tag, image.width(), image.height(), roi) {
+                if let Some(heading) = robot.fresh_heading() {
+                    self.disambiguate(&mut target, heading);
                 }
-            } else {
-                let _ = self.data_tx.send(VisionData::new(
-                    false,
-                    0,
-                    timestamp,
-                    [0.0, 0.0, 0.0],
-                    [0.0, 0.0, 0.0],
-                ));
+                // The best pose may have been swapped, so these go off whichever one won
+                target.reprojection_error = reprojection_error(&target.best, &target.corners, &self.cal);
+                target.std_devs = tag_std_devs(&target, &self.config.uncertainty);
+                targets.push(target);
+            }
+        }
+        sort_best_first(&mut targets);
+
+        if let Some(tracker) = &mut self.roi {
+            tracker.update(&targets);
+        }
+
+        let mut robot_pose = None;
+        let mut robot_std = None;
+        if let Some(field) = &self.field {
+            let on_field = targets.iter().filter(|target| field.tags.contains_key(&target.id));
+            let tag_count = on_field.clone().count();
+            if let Some(target) = on_field.min_by(|a, b| a.ambiguity.total_cmp(&b.ambiguity)) {
+                robot_pose = field.robot_pose(target, &self.robot_to_camera);
+                robot_std = robot_pose
+                    .map(|_| robot_std_devs(target, tag_count, &self.config.uncertainty));
             }
         }
+
+        drop(pose_span);
+        let done = Instant::now();
+        let stages = StageLatency {
+            preprocess: millis(preprocess_done - start),
+            detection: millis(detection_done - preprocess_done),
+            pose: millis(done - detection_done),
+        };
+
+        let mut frame = VisionFrame {
+            timestamp,
+            width: image.width(),
+            height: image.height(),
+            pipeline_latency: millis(done - start),
+            stages,
+            targets,
+            robot_pose,
+            robot_std_devs: robot_std,
+            filtered_robot_pose: None,
+            match_state: robot.match_state,
+            pipeline: robot.pipeline.unwrap_or(0),
+            pipeline_name: self.pipeline.clone(),
+            outputs: self.outputs.clone(),
+            roi,
+            frames: self.image_rx.counts(),
+        };
+        if let Some(tracker) = &mut self.tracker {
+            tracker.update(&mut frame);
+        }
+
+        trace!(
+            targets = frame.targets.len(),
+            preprocess = frame.stages.preprocess,
+            detection = frame.stages.detection,
+            pose = frame.stages.pose,
+            "Processed frame"
+        );
+        drop(frame_span);
+        frame
+    }
+
+    /// Whether the tag is one we care about for the alliance the robot is on
+    fn tag_allowed(&self, id: u32, alliance: Option<Alliance>) -> bool {
+        let tags = match alliance {
+            Some(Alliance::Red) => &self.config.red_tags,
+            Some(Alliance::Blue) => &self.config.blue_tags,
+            None => return true,
+        };
+        tags.is_empty() || tags.contains(&id)
+    }
+
+    /// Swaps in the alternate pose if the robot heading it gives is closer to the gyro heading (radians)
+    fn disambiguate(&self, target: &mut TagTarget, heading: f64) {
+        let (Some(field), Some(alt)) = (&self.field, &target.alt) else {
+            return;
+        };
+        let heading_error = |pose: &TagPose| {
+            field
+                .robot_pose_from(target.id, pose, &self.robot_to_camera)
+                .map(|robot| {
+                    let (_, _, yaw) = robot.rotation.euler_angles();
+                    ((yaw - heading + PI).rem_euclid(2.0 * PI) - PI).abs()
+                })
+        };
+
+        if let (Some(best_error), Some(alt_error)) = (heading_error(&target.best), heading_error(alt)) {
+            if alt_error < best_error {
+                let alt = target.alt.take().unwrap();
+                target.alt = Some(std::mem::replace(&mut target.best, alt));
+            }
+        }
+    }
+
+    /// Estimates both possible poses of the tag and builds the target from the best one, in full frame coordinates
+    /// when it was found in a crop
+    fn target(&self, tag: &Detection, width: u32, height: u32, roi: Option<Roi>) -> Option<TagTarget> {
+        let cal = match roi {
+            Some(roi) => roi.tag_params(&self.cal),
+            None => self.cal.clone(),
+        };
+        let mut poses: Vec<TagPose> = tag
+            .estimate_tag_pose_orthogonal_iteration(&cal, POSE_ITERATIONS)
+            .iter()
+            .map(|estimate| TagPose::from_pose(&estimate.pose, estimate.error))
+            .collect();
+        poses.sort_by(|a, b| a.error.total_cmp(&b.error));
+
+        let mut poses = poses.into_iter();
+        let best = poses.next()?;
+        let alt = poses.next();
+        let ambiguity = match &alt {
+            Some(alt) if alt.error > 0.0 => best.error / alt.error,
+            _ => 0.0,
+        };
+
+        let (center, corners) = match roi {
+            Some(roi) => (roi.to_frame(tag.center()), tag.corners().map(|corner| roi.to_frame(corner))),
+            None => (tag.center(), tag.corners()),
+        };
+        let yaw = ((self.cal.cx - center[0]) / self.cal.fx).atan().to_degrees();
+        let pitch = ((self.cal.cy - center[1]) / self.cal.fy).atan().to_degrees();
+
+        // Shoelace formula for the area of the quad
+        let mut area = 0.0;
+        for i in 0..4 {
+            let [x1, y1] = corners[i];
+            let [x2, y2] = corners[(i + 1) % 4];
+            area += x1 * y2 - x2 * y1;
+        }
+        let area = (area.abs() / 2.0) / (width as f64 * height as f64) * 100.0;
+
+        let [x1, y1] = corners[3];
+        let [x2, y2] = corners[2];
+        let skew = (y1 - y2).atan2(x2 - x1).to_degrees();
+
+        Some(TagTarget {
+            id: tag.id() as u32,
+            hamming: tag.hamming() as u32,
+            decision_margin: tag.decision_margin(),
+            center,
+            corners,
+            yaw,
+            pitch,
+            area,
+            skew,
+            best,
+            alt,
+            ambiguity,
+            reprojection_error: 0.0,
+            std_devs: StdDevs::default(),
+            filtered: None,
+        })
+    }
+}
+
+/// Builds a detector for the configured family with the configured settings
+fn build_detector(config: &DetectionConfig) -> Detector {
+    let detector = DetectorBuilder::new();
+    let detector = detector.add_family_bits(&config.families, 1);
+
+    let mut detector = detector.build().unwrap();
+    configure_detector(&mut detector, &config.detector);
+    detector
+}
+
+/// Copies the frame, or the part of it in the crop, into the detector's image.
+///
+/// The detector wants its rows padded out to an alignment, so this is the one copy the luma needs on its way to
+/// detection. `None` if the image couldn't be made.
+fn fill_detector_image<'a>(image: &'a mut Option<Image>, frame: &Frame, roi: Option<Roi>) -> Option<&'a Image> {
+    let roi = roi.unwrap_or(Roi {
+        x: 0,
+        y: 0,
+        width: frame.width(),
+        height: frame.height(),
+    });
+    let (width, height) = (roi.width as usize, roi.height as usize);
+
+    let reuse = image
+        .as_ref()
+        .is_some_and(|image| image.width() == width && image.height() == height);
+    if !reuse {
+        *image = Image::zeros_with_alignment(width, height, DEFAULT_ALIGNMENT_U8).ok();
+    }
+    let image = image.as_mut()?;
+
+    let stride = image.stride();
+    let frame_width = frame.width() as usize;
+    let luma = frame.luma();
+    let dest = image.as_slice_mut();
+    for row in 0..height {
+        let start = (roi.y as usize + row) * frame_width + roi.x as usize;
+        dest[row * stride..row * stride + width].copy_from_slice(&luma[start..start + width]);
+    }
+    Some(image)
+}
+
+/// Least ambiguous first and the clearest detection among equally ambiguous ones, the outputs that only send one
+/// target send the first
+fn sort_best_first(targets: &mut [TagTarget]) {
+    targets.sort_by(|a, b| {
+        a.ambiguity
+            .total_cmp(&b.ambiguity)
+            .then(b.decision_margin.total_cmp(&a.decision_margin))
+    });
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Region of interest tracking for the config, when it is enabled
+fn roi_tracker(config: &DetectionConfig) -> Option<RoiTracker> {
+    config.roi.enabled.then(|| RoiTracker::new(config.roi.clone()))
+}
+
+/// Applies the settings that can change without building a new detector
+fn configure_detector(detector: &mut Detector, params: &DetectorParams) {
+    detector.set_thread_number(params.threads);
+    detector.set_decimation(params.decimation);
+    detector.set_sigma(params.sigma);
+    detector.set_refine_edges(params.refine_edges);
+    detector.set_shapening(params.sharpening);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A tag two meters in front of the camera and a little off center, facing it
+    pub(crate) fn sample_target() -> TagTarget {
+        TagTarget {
+            id: 7,
+            hamming: 0,
+            decision_margin: 120.0,
+            center: [120.0, 180.0],
+            corners: [[100.0, 200.0], [140.0, 200.0], [140.0, 160.0], [100.0, 160.0]],
+            yaw: 10.0,
+            pitch: -5.0,
+            area: 1.5,
+            skew: 0.0,
+            best: TagPose {
+                translation: [0.5, -0.25, 2.0],
+                rotation: Rotation3::identity(),
+                error: 0.0,
+            },
+            alt: None,
+            ambiguity: 0.125,
+            reprojection_error: 0.5,
+            std_devs: StdDevs {
+                x: 0.02,
+                y: 0.03,
+                theta: 0.01,
+            },
+            filtered: None,
+        }
+    }
+
+    /// A 640x480 frame with `sample_target` in it
+    pub(crate) fn sample_frame() -> VisionFrame {
+        VisionFrame {
+            timestamp: 1_700_000_000.5,
+            width: 640,
+            height: 480,
+            pipeline_latency: 8.0,
+            stages: StageLatency::default(),
+            targets: vec![sample_target()],
+            robot_pose: None,
+            robot_std_devs: None,
+            filtered_robot_pose: None,
+            match_state: MatchState::default(),
+            pipeline: 0,
+            pipeline_name: "default".to_string(),
+            outputs: None,
+            roi: None,
+            frames: FrameCounts::default(),
+        }
+    }
+
+    #[test]
+    fn packs_vision_data() {
+        let frame = sample_frame();
+        let bytes = frame.vision_data().into_bytes();
+        assert_eq!(bytes.len(), 90);
+        assert_eq!(bytes[0], VISION_DATA_VERSION);
+        assert_eq!(bytes[1], 1);
+        assert_eq!(bytes[2..10], 7u64.to_le_bytes());
+        assert_eq!(bytes[10..18], frame.timestamp.to_le_bytes());
+        assert_eq!(bytes[18..26], 0.5f64.to_le_bytes());
+        assert_eq!(bytes[34..42], 2.0f64.to_le_bytes());
+        assert_eq!(bytes[66..74], 0.02f64.to_le_bytes());
+        assert_eq!(bytes[82..90], 0.01f64.to_le_bytes());
+    }
+
+    #[test]
+    fn vision_data_prefers_the_robot_std_devs() {
+        let mut frame = sample_frame();
+        frame.robot_std_devs = Some(StdDevs {
+            x: 0.5,
+            y: 0.6,
+            theta: 0.7,
+        });
+        assert_eq!(frame.vision_data().std_devs, [0.5, 0.6, 0.7]);
+
+        frame.targets.clear();
+        let data = frame.vision_data();
+        assert!(!data.detected);
+        assert_eq!(data.into_bytes()[0], VISION_DATA_VERSION);
+    }
+
+    #[test]
+    fn sorts_the_best_target_first() {
+        let target = |id, ambiguity, decision_margin| TagTarget {
+            id,
+            ambiguity,
+            decision_margin,
+            ..sample_target()
+        };
+        let mut targets = vec![target(1, 0.5, 100.0), target(2, 0.1, 50.0), target(3, 0.1, 80.0), target(4, 0.3, 200.0)];
+        sort_best_first(&mut targets);
+        let ids: Vec<u32> = targets.iter().map(|target| target.id).collect();
+        assert_eq!(ids, [3, 2, 4, 1]);
     }
 }
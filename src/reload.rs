@@ -0,0 +1,309 @@
+//! # Hot reload
+//!
+//! Watches the config and calibration files while the pipeline runs and hands any edit that can be applied live
+//! to the threads that use it through a `watch` channel:
+//!
+//! - Pipelines, with their detector settings, tag filters and uncertainty model, are picked up by the process thread
+//!   on its next frame
+//! - The calibration and camera mount are picked up the same way, the tracker restarts since its old tracks are in
+//!   the old frame
+//! - Camera controls are set by the capture thread
+//! - Log levels and the log file switch over right away
+//!
+//! The outputs, metrics server, stream, dashboard, camera index and field layout are only read at startup, edits to
+//! those print a warning and wait for a restart. An edit that doesn't load or validate is reported and the last good
+//! config stays in use, so a half-saved file never takes the pipeline down.
+//!
+//! The dashboard and the GUI save their edits with `save_edit`, which checks them first and leaves the rest to the
+//! watcher.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use nalgebra::Isometry3;
+use serde_json::{Map, Value};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::cli::Overrides;
+use crate::config::{CameraCalibration, Config, ConfigError, ConfigProblem, PipelineConfig, Severity, TrackingConfig};
+use crate::logging::Logging;
+use crate::robot::RobotState;
+
+/// How often the files are checked for changes
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Held while an edit reads and writes the config, so two edits saved at once can't undo each other
+static SAVING: Mutex<()> = Mutex::new(());
+
+/// The part of the config that can change while running
+#[derive(Debug, Clone)]
+pub struct LiveConfig {
+    /// Every pipeline by name, including the `default` one
+    pub pipelines: BTreeMap<String, PipelineConfig>,
+    /// Pipeline to run when the robot hasn't asked for one
+    pub active_pipeline: String,
+    pub calibration: CameraCalibration,
+    pub robot_to_camera: Isometry3<f64>,
+    pub tracking: TrackingConfig,
+}
+
+impl LiveConfig {
+    pub fn new(config: &Config, calibration: &CameraCalibration) -> Self {
+        LiveConfig {
+            pipelines: config.pipelines(),
+            active_pipeline: config.active_pipeline().to_string(),
+            calibration: calibration.clone(),
+            robot_to_camera: config.robot_to_camera.isometry(),
+            tracking: config.tracking.clone(),
+        }
+    }
+
+    /// The pipeline to run and its name.
+    ///
+    /// The robot picks by name first, then by index, and anything it asks for that doesn't exist falls back to
+    /// the active pipeline from the config.
+    pub fn select(&self, robot: &RobotState) -> (&str, &PipelineConfig) {
+        let by_name = robot
+            .pipeline_name
+            .as_ref()
+            .and_then(|name| self.pipelines.get_key_value(name));
+        let by_index = || {
+            let index = robot.pipeline?;
+            self.pipelines.iter().find(|(_, pipeline)| pipeline.index == Some(index))
+        };
+        let active = || self.pipelines.get_key_value(&self.active_pipeline);
+
+        // The config is checked to have its active pipeline before it gets here
+        let (name, pipeline) = by_name.or_else(by_index).or_else(active).expect("active pipeline exists");
+        (name, pipeline)
+    }
+}
+
+/// Creates the channel live config changes go out on, starting with `initial`
+pub fn live_config_channel(initial: LiveConfig) -> (watch::Sender<LiveConfig>, watch::Receiver<LiveConfig>) {
+    watch::channel(initial)
+}
+
+/// Reloads the files whenever they change until shutdown.
+///
+/// `config` is what the pipeline was started with, the overrides are applied to every reload so they keep winning
+/// over the file.
+pub async fn watch_files(
+    config_path: PathBuf,
+    calibration_path: PathBuf,
+    overrides: Overrides,
+    config: Config,
+    live_tx: Arc<watch::Sender<LiveConfig>>,
+    logging: Arc<Logging>,
+    shutdown: CancellationToken,
+) {
+    let mut last_modified = (modified(&config_path), modified(&calibration_path));
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        let modified = (modified(&config_path), modified(&calibration_path));
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        info!("Config Changed! Reloading...");
+        match reload(&config_path, &calibration_path, &overrides) {
+            Some((new_config, calibration)) => {
+                warn_restart_needed(&config, &new_config);
+                logging.apply(&new_config.logging);
+                live_tx.send_replace(LiveConfig::new(&new_config, &calibration));
+                info!("Reloaded Configs!");
+            }
+            None => warn!("Keeping the last good config!"),
+        }
+    }
+}
+
+/// Merges an edit into the config file and saves it if the result checks out, returning its warnings.
+///
+/// The edit is a JSON merge patch (RFC 7386) of the file. It is written next to the file and renamed over it, so the
+/// watcher never reloads half a file, and from there it is picked up like any other edit.
+pub fn save_edit(
+    config_path: &Path,
+    overrides: &Overrides,
+    edit: Map<String, Value>,
+) -> Result<Vec<ConfigProblem>, ConfigError> {
+    let _saving = SAVING.lock().unwrap();
+    let file = config_path.display().to_string();
+    let text = match std::fs::read_to_string(config_path) {
+        Ok(text) => text,
+        Err(source) => return Err(ConfigError::Read { file, source }),
+    };
+    let mut json: Value = match serde_json::from_str(&text) {
+        Ok(json) => json,
+        Err(err) => {
+            return Err(ConfigError::Parse {
+                file,
+                path: "(root)".to_string(),
+                message: err.to_string(),
+            })
+        }
+    };
+    merge(&mut json, Value::Object(edit));
+
+    let mut config: Config = match serde_path_to_error::deserialize(&json) {
+        Ok(config) => config,
+        Err(err) => {
+            return Err(ConfigError::Parse {
+                file,
+                path: err.path().to_string(),
+                message: err.inner().to_string(),
+            })
+        }
+    };
+    overrides.apply(&mut config);
+    let problems = config.validate();
+    if problems.iter().any(|problem| problem.severity == Severity::Error) {
+        return Err(ConfigError::Invalid(problems));
+    }
+
+    let mut text = vec![];
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut serializer = serde_json::Serializer::with_formatter(&mut text, formatter);
+    let written = serde::Serialize::serialize(&json, &mut serializer)
+        .map_err(std::io::Error::from)
+        .and_then(|_| {
+            text.push(b'\n');
+            let temp = config_path.with_extension("json.tmp");
+            std::fs::write(&temp, &text)?;
+            std::fs::rename(&temp, config_path)
+        });
+    match written {
+        Ok(()) => Ok(problems),
+        Err(source) => Err(ConfigError::Write { file, source }),
+    }
+}
+
+/// Applies a JSON merge patch: objects merge key by key, `null` removes the key and anything else replaces it
+fn merge(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        return;
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            merge(target.entry(key).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Loads and checks both files, printing what is wrong if either can't be used
+fn reload(config_path: &Path, calibration_path: &Path, overrides: &Overrides) -> Option<(Config, CameraCalibration)> {
+    let calibration = match CameraCalibration::load_from_file(calibration_path) {
+        Ok(calibration) => calibration,
+        Err(err) => {
+            error!("{}: {}", calibration_path.display(), err);
+            return None;
+        }
+    };
+    let calibration_problems = calibration.validate();
+    for problem in calibration_problems.iter() {
+        error!("{}: {}", calibration_path.display(), problem);
+    }
+    if !calibration_problems.is_empty() {
+        return None;
+    }
+
+    let mut config = match Config::load_from_file(config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            return None;
+        }
+    };
+    overrides.apply(&mut config);
+    match config.check() {
+        Ok(warnings) => {
+            for warning in warnings {
+                warn!("{}: {}", config_path.display(), warning);
+            }
+        }
+        Err(err) => {
+            error!("{}: {}", config_path.display(), err);
+            return None;
+        }
+    }
+
+    Some((config, calibration))
+}
+
+/// Prints every edit that won't do anything until a restart
+fn warn_restart_needed(running: &Config, new: &Config) {
+    if running.camera_index != new.camera_index {
+        warn!("camera_index changed, restart to use the new camera");
+    }
+    if running.field_layout != new.field_layout {
+        warn!("field_layout changed, restart to load the new layout");
+    }
+    if running.metrics != new.metrics {
+        warn!("metrics changed, restart to apply it to the metrics server");
+    }
+    if running.stream != new.stream {
+        warn!("stream changed, restart to apply it to the stream");
+    }
+    if running.dashboard != new.dashboard {
+        warn!("dashboard changed, restart to apply it to the dashboard");
+    }
+    if serde_json::to_value(&running.interface).ok() != serde_json::to_value(&new.interface).ok() {
+        warn!("interface changed, restart to apply it to the outputs");
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn merged(mut target: Value, patch: Value) -> Value {
+        merge(&mut target, patch);
+        target
+    }
+
+    #[test]
+    fn merges_objects() {
+        let target = json!({ "a": 1, "b": { "c": 2, "d": 3 } });
+        let patch = json!({ "b": { "c": 4 }, "e": [5] });
+        assert_eq!(merged(target, patch), json!({ "a": 1, "b": { "c": 4, "d": 3 }, "e": [5] }));
+    }
+
+    #[test]
+    fn null_removes() {
+        let target = json!({ "a": 1, "b": { "c": 2, "d": 3 } });
+        assert_eq!(merged(target, json!({ "a": null, "b": { "d": null } })), json!({ "b": { "c": 2 } }));
+    }
+
+    #[test]
+    fn replaces_what_isnt_an_object() {
+        assert_eq!(merged(json!({ "a": [1, 2] }), json!({ "a": [3] })), json!({ "a": [3] }));
+        assert_eq!(merged(json!({ "a": 1 }), json!({ "a": { "b": 2 } })), json!({ "a": { "b": 2 } }));
+        assert_eq!(merged(json!([1]), json!({ "a": 1 })), json!({ "a": 1 }));
+        assert_eq!(merged(json!({ "a": 1 }), json!("b")), json!("b"));
+    }
+}
@@ -0,0 +1,59 @@
+//! # Robot state
+//!
+//! Values the robot tells us about itself, used to steer what the vision pipeline does.
+//!
+//! The state lives in a `tokio::sync::watch` channel: whatever listens to the robot (the NetworkTables subscriber)
+//! holds the `Sender` and the process thread reads the latest value from its `Receiver` every frame without blocking.
+use std::time::{Duration, Instant};
+
+use serde::*;
+use tokio::sync::watch;
+
+/// A heading older than this is too stale to disambiguate poses with
+pub const HEADING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Which alliance the robot is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Alliance {
+    Red,
+    Blue,
+}
+
+/// Match state from the FMS control word the driver station publishes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchState {
+    pub enabled: bool,
+    pub autonomous: bool,
+    pub test: bool,
+    pub emergency_stop: bool,
+    pub fms_attached: bool,
+    pub ds_attached: bool,
+}
+
+/// The latest values received from the robot, `None` until the robot has sent one
+#[derive(Debug, Clone, Default)]
+pub struct RobotState {
+    /// Gyro heading in degrees (counter-clockwise positive) and when we got it
+    pub heading: Option<(f64, Instant)>,
+    pub alliance: Option<Alliance>,
+    pub match_state: MatchState,
+    /// Pipeline index the robot asked for
+    pub pipeline: Option<i64>,
+    /// Pipeline name the robot asked for, takes priority over the index
+    pub pipeline_name: Option<String>,
+}
+
+impl RobotState {
+    /// The gyro heading in radians, if the robot sent one recently enough to trust
+    pub fn fresh_heading(&self) -> Option<f64> {
+        match self.heading {
+            Some((heading, at)) if at.elapsed() < HEADING_TIMEOUT => Some(heading.to_radians()),
+            _ => None,
+        }
+    }
+}
+
+/// Creates the channel the robot state gets shared through
+pub fn robot_state_channel() -> (watch::Sender<RobotState>, watch::Receiver<RobotState>) {
+    watch::channel(RobotState::default())
+}
@@ -0,0 +1,250 @@
+//! # Region of interest tracking
+//!
+//! Searching a whole 1920x1080 frame for tags is most of the time spent on a frame. Once tags have been found,
+//! the next frame only needs searching around where they will be, so `RoiTracker` predicts that from the last two
+//! frames and hands back a crop to detect on.
+//!
+//! Every tag's box from the last frame is moved by how far its center moved since the frame before, grown by
+//! `margin` times its size on every side and the boxes are joined into a single crop. The whole frame is searched
+//! again when nothing was found, when the crop would be most of the frame anyway, and every `rescan_interval`
+//! frames so tags coming into view get picked up.
+//!
+//! Detection on a crop gives pixel coordinates inside the crop. Pose estimation runs with the principal point moved
+//! by the crop's offset, which gives the same pose as the full frame would, and the corners and center are moved back
+//! into full frame coordinates before anything else sees them.
+use std::collections::HashMap;
+
+use apriltag::TagParams;
+use serde::*;
+
+use crate::config::RoiConfig;
+use crate::process::TagTarget;
+
+/// A rectangle of the frame in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Roi {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Roi {
+    /// Camera parameters for pose estimation on the crop
+    pub fn tag_params(&self, params: &TagParams) -> TagParams {
+        TagParams {
+            cx: params.cx - self.x as f64,
+            cy: params.cy - self.y as f64,
+            ..params.clone()
+        }
+    }
+
+    /// Moves a point in the crop into full frame coordinates
+    pub fn to_frame(self, [x, y]: [f64; 2]) -> [f64; 2] {
+        [x + self.x as f64, y + self.y as f64]
+    }
+}
+
+/// Picks the region to detect on for each frame from where the tags were in the last ones
+pub struct RoiTracker {
+    config: RoiConfig,
+    /// Bounding box (`[min_x, min_y, max_x, max_y]`) and center of every tag in the last frame
+    last: HashMap<u32, ([f64; 4], [f64; 2])>,
+    /// Centers of every tag in the frame before that
+    previous: HashMap<u32, [f64; 2]>,
+    frames_since_rescan: u32,
+}
+
+impl RoiTracker {
+    pub fn new(config: RoiConfig) -> Self {
+        RoiTracker {
+            config,
+            last: HashMap::new(),
+            previous: HashMap::new(),
+            frames_since_rescan: 0,
+        }
+    }
+
+    /// The crop to detect on in the next frame, `None` to search the whole frame
+    pub fn next_roi(&mut self, width: u32, height: u32) -> Option<Roi> {
+        if self.last.is_empty() || self.frames_since_rescan + 1 >= self.config.rescan_interval {
+            self.frames_since_rescan = 0;
+            return None;
+        }
+
+        let mut region = [f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY];
+        for (id, ([min_x, min_y, max_x, max_y], center)) in self.last.iter() {
+            // Constant velocity in the image, good enough over a frame
+            let [dx, dy] = match self.previous.get(id) {
+                Some(previous) => [center[0] - previous[0], center[1] - previous[1]],
+                None => [0.0, 0.0],
+            };
+            let margin = self.config.margin * (max_x - min_x).max(max_y - min_y);
+            region[0] = region[0].min(min_x + dx - margin);
+            region[1] = region[1].min(min_y + dy - margin);
+            region[2] = region[2].max(max_x + dx + margin);
+            region[3] = region[3].max(max_y + dy + margin);
+        }
+
+        let roi = clamp(region, self.config.min_size, width, height)?;
+        let area = (roi.width as f64 * roi.height as f64) / (width as f64 * height as f64);
+        if area > self.config.max_area {
+            self.frames_since_rescan = 0;
+            return None;
+        }
+        self.frames_since_rescan += 1;
+        Some(roi)
+    }
+
+    /// Records where the tags were found in this frame, in full frame coordinates
+    pub fn update(&mut self, targets: &[TagTarget]) {
+        self.previous = self.last.iter().map(|(id, (_, center))| (*id, *center)).collect();
+        self.last = targets
+            .iter()
+            .map(|target| {
+                let mut bounds = [f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY];
+                for [x, y] in target.corners {
+                    bounds = [bounds[0].min(x), bounds[1].min(y), bounds[2].max(x), bounds[3].max(y)];
+                }
+                (target.id, (bounds, target.center))
+            })
+            .collect();
+    }
+}
+
+/// Grows the region to at least `min_size` around its middle and fits it inside the frame, `None` if it is
+/// entirely outside
+fn clamp([min_x, min_y, max_x, max_y]: [f64; 4], min_size: u32, width: u32, height: u32) -> Option<Roi> {
+    let grow = |min: f64, max: f64, size: u32| {
+        let missing = (min_size.min(size) as f64 - (max - min)).max(0.0) / 2.0;
+        let (min, max) = (min - missing, max + missing);
+        // Slide back inside the frame before cutting off so the minimum size holds at the edges
+        let shift = (-min).max(0.0) - (max - size as f64).max(0.0);
+        let (min, max) = ((min + shift).max(0.0), (max + shift).min(size as f64));
+        (min.floor() as u32, max.ceil() as u32)
+    };
+
+    if max_x < 0.0 || max_y < 0.0 || min_x > width as f64 || min_y > height as f64 {
+        return None;
+    }
+    let (x, right) = grow(min_x, max_x, width);
+    let (y, bottom) = grow(min_y, max_y, height);
+    if right <= x || bottom <= y {
+        return None;
+    }
+    Some(Roi {
+        x,
+        y,
+        width: right - x,
+        height: bottom - y,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::tests::sample_target;
+
+    fn config() -> RoiConfig {
+        RoiConfig {
+            enabled: true,
+            margin: 0.5,
+            min_size: 100,
+            rescan_interval: 10,
+            max_area: 0.5,
+            ..RoiConfig::default()
+        }
+    }
+
+    fn roi(x: u32, y: u32, width: u32, height: u32) -> Option<Roi> {
+        Some(Roi { x, y, width, height })
+    }
+
+    #[test]
+    fn clamp_grows_to_the_min_size() {
+        assert_eq!(clamp([200.0, 200.0, 240.0, 260.0], 100, 640, 480), roi(170, 180, 100, 100));
+        assert_eq!(clamp([200.0, 200.0, 400.0, 260.0], 100, 640, 480), roi(200, 180, 200, 100));
+    }
+
+    #[test]
+    fn clamp_slides_inside_the_frame() {
+        assert_eq!(clamp([10.0, 20.0, 50.0, 60.0], 100, 640, 480), roi(0, 0, 100, 100));
+        assert_eq!(clamp([600.0, 450.0, 650.0, 500.0], 100, 640, 480), roi(540, 380, 100, 100));
+        // Never bigger than the frame
+        assert_eq!(clamp([0.0, 0.0, 10.0, 10.0], 1000, 640, 480), roi(0, 0, 640, 480));
+    }
+
+    #[test]
+    fn clamp_outside_the_frame_is_none() {
+        assert_eq!(clamp([700.0, 10.0, 750.0, 50.0], 100, 640, 480), None);
+        assert_eq!(clamp([-90.0, -90.0, -10.0, -10.0], 100, 640, 480), None);
+    }
+
+    #[test]
+    fn searches_everything_until_something_is_found() {
+        let mut tracker = RoiTracker::new(config());
+        assert_eq!(tracker.next_roi(640, 480), None);
+        tracker.update(&[]);
+        assert_eq!(tracker.next_roi(640, 480), None);
+    }
+
+    #[test]
+    fn crops_around_the_last_tags() {
+        let mut tracker = RoiTracker::new(config());
+        tracker.update(&[sample_target()]);
+        // The tag is 40 pixels with 20 around it, grown to the min size
+        assert_eq!(tracker.next_roi(640, 480), roi(70, 130, 100, 100));
+    }
+
+    #[test]
+    fn crop_follows_the_tag() {
+        let mut tracker = RoiTracker::new(config());
+        let mut target = sample_target();
+        tracker.update(&[target.clone()]);
+        target.center[0] += 10.0;
+        for corner in target.corners.iter_mut() {
+            corner[0] += 10.0;
+        }
+        tracker.update(&[target]);
+        // Another 10 pixels on from where it was last
+        assert_eq!(tracker.next_roi(640, 480), roi(90, 130, 100, 100));
+    }
+
+    #[test]
+    fn rescans_every_interval() {
+        let mut tracker = RoiTracker::new(RoiConfig {
+            rescan_interval: 3,
+            ..config()
+        });
+        tracker.update(&[sample_target()]);
+        assert!(tracker.next_roi(640, 480).is_some());
+        assert!(tracker.next_roi(640, 480).is_some());
+        assert_eq!(tracker.next_roi(640, 480), None);
+        assert!(tracker.next_roi(640, 480).is_some());
+    }
+
+    #[test]
+    fn big_crops_search_everything() {
+        let mut tracker = RoiTracker::new(RoiConfig {
+            max_area: 0.01,
+            ..config()
+        });
+        tracker.update(&[sample_target()]);
+        assert_eq!(tracker.next_roi(640, 480), None);
+    }
+
+    #[test]
+    fn crop_params_give_the_frame_pose() {
+        let params = TagParams {
+            tagsize: 0.1651,
+            fx: 600.0,
+            fy: 600.0,
+            cx: 320.0,
+            cy: 240.0,
+        };
+        let crop = Roi { x: 70, y: 130, width: 100, height: 100 };
+        let crop_params = crop.tag_params(&params);
+        assert_eq!((crop_params.cx, crop_params.cy), (250.0, 110.0));
+        assert_eq!(crop.to_frame([30.0, 50.0]), [100.0, 180.0]);
+    }
+}
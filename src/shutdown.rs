@@ -0,0 +1,54 @@
+//! # Shutdown
+//!
+//! Everything that runs until the program stops holds a clone of the same `CancellationToken`.
+//! A SIGINT or SIGTERM (or closing the GUI) cancels it, and then everything winds down in order:
+//!
+//! 1. The capture thread stops grabbing frames, closes the camera stream and drops its side of the frame mailbox
+//! 2. The process thread finishes the frame it is on and stops once the mailbox is closed and empty
+//! 3. The comms task sends on whatever results were still queued and closes the outputs, flushing the file log
+//!    and the serial port
+//!
+//! `main` waits up to `SHUTDOWN_TIMEOUT` for all of that before giving up and exiting the process anyway.
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// How long to wait for everything to stop before exiting without it
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for SIGINT (Ctrl+C) or, on unix, SIGTERM
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => info!("Got SIGINT!"),
+                    _ = terminate.recv() => info!("Got SIGTERM!"),
+                }
+                return;
+            }
+            Err(err) => error!("Failed to listen for SIGTERM: {}", err),
+        }
+    }
+
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => info!("Got SIGINT!"),
+        Err(err) => {
+            error!("Failed to listen for SIGINT: {}", err);
+            // Nothing will ever come, don't treat that as a signal
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Cancels the token when a signal comes in
+pub async fn cancel_on_signal(shutdown: CancellationToken) {
+    tokio::select! {
+        _ = wait_for_signal() => shutdown.cancel(),
+        _ = shutdown.cancelled() => {}
+    }
+}
@@ -0,0 +1,386 @@
+//! # Output sinks for VisionFrames
+//!
+//! This module contains the `Sinks` fan-out and the `Sink` outputs it drives.
+//!
+//! Every output listed in `InterfaceConfig::outputs` gets its own task and its own small queue.
+//! `Sinks::publish` never waits on an output: if an output's queue is full (it is slow, or it is
+//! reconnecting) the result is dropped for that output only, so one bad output cannot stall the others
+//! or the process thread.
+//!
+//! When an output fails to open or a write fails, its task drops the connection, waits `RECONNECT_DELAY`
+//! and opens it again, throwing away anything that queued up in the meantime since it would be stale.
+//!
+//! Health statuses from `Sinks::publish_health` go through the same queues, to every output no matter which ones the
+//! pipeline sends its results to. Failed opens and writes are counted as link errors in the health counters.
+//!
+//! On shutdown `Sinks::close` lets every output write out what is left in its queue and then closes it,
+//! flushing anything buffered.
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bondrewd::Bitfields;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::config::{InterfaceConfig, OutputKind};
+use crate::field::FieldLayout;
+use crate::health::{HealthCounters, HealthStatus};
+#[cfg(feature = "nt")]
+use crate::config::NtMode;
+use crate::interface::*;
+use crate::process::VisionFrame;
+
+/// How many results can wait on a single output before new ones get dropped
+pub const SINK_QUEUE_DEPTH: usize = 4;
+
+/// How long to wait before trying to reopen an output that failed
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How long an output gets to write out its queue and close on shutdown
+pub const SINK_CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Anything that goes out on an output
+#[derive(Debug, Clone)]
+pub enum Message {
+    Frame(Box<VisionFrame>),
+    Health(HealthStatus),
+}
+
+// --- Implementation of Sinks ---
+/// Handle to every running output, results given to `publish` get sent to all of them
+pub struct Sinks {
+    outputs: Vec<Output>,
+    tasks: Vec<(OutputKind, JoinHandle<()>)>,
+}
+
+impl Sinks {
+    /// Starts a task on the runtime for every output in the config
+    ///
+    /// The field layout is only used by outputs that publish field relative poses.
+    /// Once `shutdown` is cancelled outputs that aren't open stop trying to open.
+    pub fn spawn(
+        runtime: &Handle,
+        config: &InterfaceConfig,
+        field: Option<FieldLayout>,
+        health: Arc<HealthCounters>,
+        shutdown: CancellationToken,
+    ) -> Self {
+        let mut outputs = vec![];
+        let mut tasks = vec![];
+        for kind in config.outputs.iter().copied() {
+            let (tx, rx) = mpsc::channel(SINK_QUEUE_DEPTH);
+            let task = runtime.spawn(run_sink(
+                kind,
+                config.clone(),
+                field.clone(),
+                rx,
+                health.clone(),
+                shutdown.clone(),
+            ));
+            outputs.push(Output {
+                kind,
+                tx,
+                stopped: AtomicBool::new(false),
+            });
+            tasks.push((kind, task));
+        }
+        Sinks { outputs, tasks }
+    }
+
+    /// Lets every output finish its queue and close, giving up on any still going after `SINK_CLOSE_TIMEOUT`
+    pub async fn close(self) {
+        let Sinks { outputs, tasks } = self;
+        // Closing the queues is what tells the outputs to finish up
+        drop(outputs);
+
+        // They all close at once under the same deadline, a stuck output doesn't hold up the others
+        let deadline = Instant::now() + SINK_CLOSE_TIMEOUT;
+        join_all(tasks.into_iter().map(|(kind, mut task)| async move {
+            match tokio::time::timeout_at(deadline, &mut task).await {
+                Ok(_) => info!("{:?} output closed!", kind),
+                Err(_) => {
+                    warn!("{:?} output didn't close in time!", kind);
+                    task.abort();
+                }
+            }
+        }))
+        .await;
+    }
+
+    /// Queues the data on every output the frame's pipeline sends to, without waiting on any of them
+    pub fn publish(&self, data: &VisionFrame) {
+        for output in self.outputs.iter() {
+            if data.outputs.as_ref().is_some_and(|outputs| !outputs.contains(&output.kind)) {
+                continue;
+            }
+            output.send(Message::Frame(Box::new(data.clone())));
+        }
+    }
+
+    /// Queues the status on every output, without waiting on any of them
+    pub fn publish_health(&self, status: &HealthStatus) {
+        for output in self.outputs.iter() {
+            output.send(Message::Health(status.clone()));
+        }
+    }
+}
+
+/// Queue of one output's task
+struct Output {
+    kind: OutputKind,
+    tx: mpsc::Sender<Message>,
+    /// Set once the task is found to have ended, so that is only reported once
+    stopped: AtomicBool,
+}
+
+impl Output {
+    fn send(&self, message: Message) {
+        if self.stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        match self.tx.try_send(message) {
+            Ok(()) => {}
+            // The output is behind or reconnecting, it will catch up on the next one
+            Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Closed(_)) => {
+                self.stopped.store(true, Ordering::Relaxed);
+                error!("{:?} output has stopped!", self.kind);
+            }
+        }
+    }
+}
+
+/// Keeps a single output open and writes everything from its queue to it
+async fn run_sink(
+    kind: OutputKind,
+    config: InterfaceConfig,
+    field: Option<FieldLayout>,
+    mut rx: mpsc::Receiver<Message>,
+    health: Arc<HealthCounters>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let mut sink = match Sink::open(kind, &config, field.as_ref()).await {
+            Ok(sink) => sink,
+            Err(err) => {
+                warn!("Failed to open {:?} output: {}", kind, err);
+                health.link_failed(kind);
+                if shutdown.is_cancelled() {
+                    return;
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        info!("Connected to {:?} output!", kind);
+
+        // Anything that queued up while we were connecting is stale
+        while rx.try_recv().is_ok() {}
+
+        loop {
+            let Some(message) = rx.recv().await else {
+                if let Err(err) = sink.close().await {
+                    warn!("Failed to close {:?} output: {}", kind, err);
+                }
+                return;
+            };
+            let written = match &message {
+                Message::Frame(data) => sink.write(data).await,
+                Message::Health(status) => sink.write_health(status).await,
+            };
+            if let Err(err) = written {
+                warn!("Failed to write to {:?} output: {}", kind, err);
+                health.link_failed(kind);
+                break;
+            }
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+// --- Implementation of Sinks ---
+
+// --- Implementation of Sink ---
+/// A single opened output
+pub enum Sink {
+    /// Serial port or TCP client, anything wrapped in a `DataInterface`
+    Stream(DataInterface),
+    /// UDP socket and the address to send to
+    Udp(UdpSocket, SocketAddr),
+    /// File the results get appended to as JSON lines, and whether to only log while the robot is enabled
+    File(File, bool),
+    /// NetworkTables 4 client with our own topics
+    #[cfg(feature = "nt")]
+    NetworkTables(Box<crate::nt::client::NT>),
+    /// NetworkTables 4 client with PhotonVision topics
+    #[cfg(feature = "nt")]
+    PhotonVision(Box<crate::nt::photon::PhotonNT>),
+    /// NetworkTables 4 client with Limelight entries
+    #[cfg(feature = "nt")]
+    Limelight(Box<crate::nt::limelight::LimelightNT>),
+}
+
+impl Sink {
+    /// Opens the given kind of output with the settings from the config
+    pub async fn open(
+        kind: OutputKind,
+        config: &InterfaceConfig,
+        #[allow(unused_variables)] field: Option<&FieldLayout>,
+    ) -> Result<Self, DataError> {
+        match kind {
+            OutputKind::Serial => Ok(Sink::Stream(open_serial_port(config).await?)),
+            OutputKind::Server => Ok(Sink::Stream(start_tcp_server(config).await?)),
+            OutputKind::Udp => {
+                let target: SocketAddr = config.udp_target.parse()?;
+                let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+                Ok(Sink::Udp(socket, target))
+            }
+            OutputKind::FileLog => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config.log_path)
+                    .await?;
+                Ok(Sink::File(file, config.log_only_enabled))
+            }
+            #[cfg(feature = "nt")]
+            OutputKind::NetworkTables => match config.nt_mode {
+                NtMode::Vision => Ok(Sink::NetworkTables(Box::new(
+                    crate::nt::client::NT::new(config).await?,
+                ))),
+                NtMode::PhotonVision => Ok(Sink::PhotonVision(Box::new(
+                    crate::nt::photon::PhotonNT::new(config).await?,
+                ))),
+                NtMode::Limelight => Ok(Sink::Limelight(Box::new(
+                    crate::nt::limelight::LimelightNT::new(config, field).await?,
+                ))),
+            },
+            #[cfg(not(feature = "nt"))]
+            OutputKind::NetworkTables => Err(DataError::Unsupported(
+                "NetworkTables output needs the `nt` feature".to_string(),
+            )),
+        }
+    }
+
+    /// Writes a single result to the output
+    pub async fn write(&mut self, data: &VisionFrame) -> Result<(), DataError> {
+        match self {
+            Sink::Stream(interface) => interface.write_vision_data(data.vision_data()).await,
+            Sink::Udp(socket, target) => {
+                let mut packet = DEFAULT_SYNC_BYTES.to_vec();
+                packet.extend_from_slice(&data.vision_data().into_bytes());
+                socket.send_to(&packet, *target).await?;
+                Ok(())
+            }
+            Sink::File(file, only_enabled) => {
+                if *only_enabled && !data.match_state.enabled {
+                    return Ok(());
+                }
+                let mut line = serde_json::to_vec(data)?;
+                line.push(b'\n');
+                file.write_all(&line).await?;
+                Ok(())
+            }
+            #[cfg(feature = "nt")]
+            Sink::NetworkTables(nt) => nt.publish(data).await,
+            #[cfg(feature = "nt")]
+            Sink::PhotonVision(nt) => nt.publish(data).await,
+            #[cfg(feature = "nt")]
+            Sink::Limelight(nt) => nt.publish(data).await,
+        }
+    }
+
+    /// Writes a health status to the output
+    pub async fn write_health(&mut self, status: &HealthStatus) -> Result<(), DataError> {
+        match self {
+            Sink::Stream(interface) => interface.write_health_data(status.health_data()).await,
+            Sink::Udp(socket, target) => {
+                let mut packet = DEFAULT_SYNC_BYTES.to_vec();
+                packet.extend_from_slice(&status.health_data().into_bytes());
+                socket.send_to(&packet, *target).await?;
+                Ok(())
+            }
+            // Written even when only logging while enabled, it is once a second and says why the results stopped
+            Sink::File(file, _) => {
+                let mut line = serde_json::to_vec(&HealthLine { health: status })?;
+                line.push(b'\n');
+                file.write_all(&line).await?;
+                Ok(())
+            }
+            #[cfg(feature = "nt")]
+            Sink::NetworkTables(nt) => nt.publish_health(status).await,
+            #[cfg(feature = "nt")]
+            Sink::PhotonVision(nt) => nt.publish_health(status).await,
+            #[cfg(feature = "nt")]
+            Sink::Limelight(nt) => nt.publish_health(status).await,
+        }
+    }
+
+    /// Flushes anything buffered, the output itself is released when it is dropped
+    pub async fn close(&mut self) -> Result<(), DataError> {
+        match self {
+            Sink::Stream(interface) => interface.close().await,
+            Sink::File(file, _) => {
+                file.flush().await?;
+                file.sync_all().await?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+// --- Implementation of Sink ---
+
+/// How a health status is written to the log, so it can't be mistaken for a result
+#[derive(Serialize, Deserialize)]
+pub struct HealthLine<S> {
+    pub health: S,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::tests::sample_frame;
+
+    #[test]
+    fn stopped_output_is_marked_once() {
+        let (tx, rx) = mpsc::channel(SINK_QUEUE_DEPTH);
+        drop(rx);
+        let output = Output {
+            kind: OutputKind::Udp,
+            tx,
+            stopped: AtomicBool::new(false),
+        };
+        output.send(Message::Frame(Box::new(sample_frame())));
+        assert!(output.stopped.load(Ordering::Relaxed));
+        // Nothing is tried on it again
+        output.send(Message::Frame(Box::new(sample_frame())));
+        assert!(output.stopped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn full_output_keeps_going() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let output = Output {
+            kind: OutputKind::Udp,
+            tx,
+            stopped: AtomicBool::new(false),
+        };
+        output.send(Message::Frame(Box::new(sample_frame())));
+        output.send(Message::Frame(Box::new(sample_frame())));
+        assert!(!output.stopped.load(Ordering::Relaxed));
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+}
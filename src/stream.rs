@@ -0,0 +1,150 @@
+//! # Camera stream
+//!
+//! Serves the camera as an MJPEG stream at `/stream.mjpg` on `stream.port` when `stream.enabled` is set, with every
+//! target's outline, axes, id and distance drawn on, so the drive team can see what the coprocessor sees from the
+//! driver station dashboard.
+//!
+//! A `stream` thread peeks at the newest frame at most `stream.fps` times a second, decodes it in color, scales it
+//! down to `stream.width`, draws the latest results on and encodes it. It only does any of that while someone is
+//! watching, and it never takes frames from the process thread. Each viewer gets the newest encoded frame whenever
+//! there is one, so a slow connection skips frames instead of falling behind.
+//!
+//! The results drawn are the newest the process thread has finished, which can be a frame or two behind the image
+//! when detection is slow.
+use std::io::Cursor;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::config::StreamConfig;
+use crate::frame::Frame;
+use crate::http::{self, Request, Response};
+use crate::interface::DataError;
+use crate::mailbox::FrameReceiver;
+use crate::overlay;
+use crate::process::VisionFrame;
+use crate::reload::LiveConfig;
+
+/// Where the stream is served, `/` works too so the bare address can be pasted into a dashboard
+pub const STREAM_PATH: &str = "/stream.mjpg";
+
+/// Separates the JPEGs in the multipart response
+pub const BOUNDARY: &str = "frame";
+
+/// Newest encoded frame, `None` until the first one
+type JpegSender = watch::Sender<Option<Arc<Vec<u8>>>>;
+
+/// Starts encoding frames for the stream on its own thread and serves it until shutdown
+pub async fn serve(
+    config: StreamConfig,
+    frames: FrameReceiver<Frame>,
+    results: watch::Receiver<Option<Arc<VisionFrame>>>,
+    live_rx: watch::Receiver<LiveConfig>,
+    shutdown: CancellationToken,
+) -> Result<(), DataError> {
+    let (jpeg_tx, _) = watch::channel(None);
+    let jpeg_tx = Arc::new(jpeg_tx);
+
+    let encoder_tx = jpeg_tx.clone();
+    let encoder_shutdown = shutdown.clone();
+    let port = config.port;
+    thread::Builder::new()
+        .name("stream".to_string())
+        .spawn(move || encode(config, frames, results, live_rx, &encoder_tx, &encoder_shutdown))
+        .map_err(DataError::ServerCreationFailed)?;
+
+    let client_shutdown = shutdown.clone();
+    http::serve(
+        "Stream",
+        port,
+        move |request: Request, mut stream: TcpStream| {
+            let jpeg_tx = jpeg_tx.clone();
+            let shutdown = client_shutdown.clone();
+            async move {
+                match request.path.as_str() {
+                    _ if request.method != "GET" => Response::method_not_allowed().write(&mut stream).await,
+                    STREAM_PATH | "/" => send_stream(&mut stream, &jpeg_tx, shutdown).await,
+                    _ => Response::not_found().write(&mut stream).await,
+                }
+            }
+        },
+        shutdown,
+    )
+    .await
+}
+
+/// Encodes the newest frame every tick while anyone is watching
+fn encode(
+    config: StreamConfig,
+    mut frames: FrameReceiver<Frame>,
+    results: watch::Receiver<Option<Arc<VisionFrame>>>,
+    live_rx: watch::Receiver<LiveConfig>,
+    jpeg_tx: &JpegSender,
+    shutdown: &CancellationToken,
+) {
+    let interval = Duration::from_secs_f64(1.0 / config.fps);
+    let mut next = Instant::now();
+    while !shutdown.is_cancelled() {
+        // Don't try to catch up on ticks missed while encoding took too long
+        next = (next + interval).max(Instant::now());
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+
+        if jpeg_tx.receiver_count() == 0 {
+            continue;
+        }
+        // Only frames the camera sent since the last tick, the viewers already have the last one
+        let Some(mut image) = frames.peek().and_then(|frame| frame.to_rgb()) else {
+            continue;
+        };
+        if config.width > 0 && config.width < image.width() {
+            let height = (image.height() as u64 * config.width as u64 / image.width() as u64).max(1) as u32;
+            image = imageops::resize(&image, config.width, height, FilterType::Triangle);
+        }
+        if let Some(results) = results.borrow().clone() {
+            let params = live_rx.borrow().calibration.tag_params();
+            overlay::annotate(&mut image, &results, &params);
+        }
+
+        let mut jpeg = vec![];
+        if let Err(err) = JpegEncoder::new_with_quality(Cursor::new(&mut jpeg), config.quality).encode_image(&image) {
+            warn!("Failed to encode a stream frame [{}]", err);
+            continue;
+        }
+        jpeg_tx.send_replace(Some(Arc::new(jpeg)));
+    }
+}
+
+/// Sends every new frame as its own part of a `multipart/x-mixed-replace` response until the viewer goes away
+async fn send_stream(stream: &mut TcpStream, jpeg_tx: &JpegSender, shutdown: CancellationToken) -> std::io::Result<()> {
+    let mut jpeg_rx = jpeg_tx.subscribe();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        BOUNDARY
+    );
+    stream.write_all(head.as_bytes()).await?;
+
+    loop {
+        tokio::select! {
+            changed = jpeg_rx.changed() => if changed.is_err() {
+                return Ok(());
+            },
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+        let Some(jpeg) = jpeg_rx.borrow_and_update().clone() else {
+            continue;
+        };
+        let part = format!("--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n", BOUNDARY, jpeg.len());
+        stream.write_all(part.as_bytes()).await?;
+        stream.write_all(&jpeg).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+    }
+}
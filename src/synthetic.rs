@@ -0,0 +1,224 @@
+//! # Synthetic frames
+//!
+//! Renders tags from the configured family into frames the size the camera captures, through the pinhole model of
+//! the calibration, so `bench` can run without a camera or a folder of images. The tags drift and turn a little
+//! from frame to frame, like they would with the robot moving, so region of interest tracking gets exercised too.
+//!
+//! Frames are encoded to JPEG like the camera sends them, color with the chroma subsampled 4:2:0, so decoding gets
+//! timed the way capture does it.
+use std::f64::consts::PI;
+
+use apriltag::{Image, TagParams};
+use apriltag_sys as sys;
+use image::{DynamicImage, GrayImage, Luma, RgbImage};
+use imageproc::geometric_transformations::Projection;
+use nalgebra::{Point3, Rotation3, Vector3};
+use nokhwa::utils::{FrameFormat, Resolution};
+use nokhwa::Buffer;
+
+use crate::config::{AprilTagFamily, CameraCalibration};
+
+/// Frame size, the same as the camera is opened with
+pub const SYNTHETIC_WIDTH: u32 = 1920;
+pub const SYNTHETIC_HEIGHT: u32 = 1080;
+
+/// Tags in every frame, each one meter further away than the last
+pub const SYNTHETIC_TAGS: u32 = 3;
+
+/// JPEG quality, about what webcams send
+const JPEG_QUALITY: u8 = 90;
+
+/// Background brightness and how far the noise on it goes either way
+const BACKGROUND: i32 = 110;
+const NOISE: i32 = 12;
+
+/// Renders `count` frames as MJPEG buffers, `None` if the family can't be rendered
+pub fn render_frames(count: u32, family: &AprilTagFamily, calibration: &CameraCalibration) -> Option<Vec<Buffer>> {
+    let tags: Vec<TagImage> = (0..SYNTHETIC_TAGS).map(|id| tag_image(family, id)).collect::<Option<_>>()?;
+    let params = calibration.tag_params();
+
+    (0..count)
+        .map(|index| mjpeg_buffer(&DynamicImage::from(render_frame(index, &tags, &params)).to_rgb8()))
+        .collect()
+}
+
+/// Encodes the image the way an MJPEG camera would send it, baseline YCbCr with the chroma at half size both ways
+pub fn mjpeg_buffer(image: &RgbImage) -> Option<Buffer> {
+    let (width, height) = image.dimensions();
+    let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    // libjpeg-turbo's settings rather than mozjpeg's progressive ones, cameras encode with the former
+    compress.set_fastest_defaults();
+    compress.set_color_space(mozjpeg::ColorSpace::JCS_YCbCr);
+    compress.set_chroma_sampling_pixel_sizes((2, 2), (2, 2));
+    compress.set_quality(JPEG_QUALITY as f32);
+    compress.set_size(width as usize, height as usize);
+    compress.set_mem_dest();
+    compress.start_compress();
+    if !compress.write_scanlines(image.as_raw()) {
+        return None;
+    }
+    compress.finish_compress();
+    let jpeg = compress.data_to_vec().ok()?;
+    Some(Buffer::new(Resolution::new(width, height), &jpeg, FrameFormat::MJPEG))
+}
+
+fn render_frame(index: u32, tags: &[TagImage], params: &TagParams) -> GrayImage {
+    // Cheap deterministic noise, so every run of the bench sees the same frames
+    let mut seed = 0x9e37_79b9_u32.wrapping_mul(index + 1);
+    let mut frame = GrayImage::from_fn(SYNTHETIC_WIDTH, SYNTHETIC_HEIGHT, |_, _| {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        let noise = (seed % (2 * NOISE as u32 + 1)) as i32 - NOISE;
+        Luma([(BACKGROUND + noise) as u8])
+    });
+
+    let t = index as f64 / 30.0;
+    for (i, tag) in tags.iter().enumerate() {
+        let phase = i as f64 * 2.0 * PI / tags.len() as f64;
+        let distance = 1.5 + i as f64;
+        let translation = Vector3::new(
+            (t + phase).sin() * 0.4 * distance + (i as f64 - 1.0) * 0.5 * distance,
+            (t * 0.7 + phase).cos() * 0.1 * distance,
+            distance,
+        );
+        let rotation = Rotation3::from_euler_angles(0.0, (t * 0.5 + phase).sin() * 0.5, (t * 0.3).sin() * 0.2);
+        draw_tag(&mut frame, tag, params, &rotation, &translation);
+    }
+    frame
+}
+
+/// Draws the tag at the pose (camera frame, EDN) by mapping every pixel it could cover back onto the tag image
+fn draw_tag(
+    frame: &mut GrayImage,
+    tag: &TagImage,
+    params: &TagParams,
+    rotation: &Rotation3<f64>,
+    translation: &Vector3<f64>,
+) {
+    let half = params.tagsize / 2.0;
+    let project = |x: f64, y: f64| {
+        let point = rotation * Point3::new(x, y, 0.0) + translation;
+        (
+            (params.fx * point.x / point.z + params.cx) as f32,
+            (params.fy * point.y / point.z + params.cy) as f32,
+        )
+    };
+    let corners = [
+        project(-half, -half),
+        project(half, -half),
+        project(half, half),
+        project(-half, half),
+    ];
+
+    // `tagsize` is the outside of the border, the tag image can have more around that
+    let total = tag.image.width() as f32;
+    let low = (total - tag.width_at_border as f32) / 2.0;
+    let high = low + tag.width_at_border as f32;
+    let Some(to_tag) = Projection::from_control_points(corners, [(low, low), (high, low), (high, high), (low, high)])
+    else {
+        return;
+    };
+
+    // Grow the box by the part of the tag image outside the border, with the tag's widest side as its size
+    let size = corners
+        .iter()
+        .map(|(x, y)| (x - corners[0].0).abs().max((y - corners[0].1).abs()))
+        .fold(0.0, f32::max);
+    let pad = size * low / tag.width_at_border as f32 + 1.0;
+    let min_x = corners.iter().map(|c| c.0).fold(f32::INFINITY, f32::min) - pad;
+    let max_x = corners.iter().map(|c| c.0).fold(f32::NEG_INFINITY, f32::max) + pad;
+    let min_y = corners.iter().map(|c| c.1).fold(f32::INFINITY, f32::min) - pad;
+    let max_y = corners.iter().map(|c| c.1).fold(f32::NEG_INFINITY, f32::max) + pad;
+
+    let x_range = (min_x.max(0.0) as u32)..(max_x.min(frame.width() as f32) as u32);
+    for y in (min_y.max(0.0) as u32)..(max_y.min(frame.height() as f32) as u32) {
+        for x in x_range.clone() {
+            let (u, v) = to_tag * (x as f32 + 0.5, y as f32 + 0.5);
+            if u >= 0.0 && v >= 0.0 && u < total && v < total {
+                frame.put_pixel(x, y, *tag.image.get_pixel(u as u32, v as u32));
+            }
+        }
+    }
+}
+
+/// A tag's bits as an image, one pixel per bit
+struct TagImage {
+    image: GrayImage,
+    /// Pixels across the outside of the border, which is what `tagsize` measures
+    width_at_border: u32,
+}
+
+fn tag_image(family: &AprilTagFamily, id: u32) -> Option<TagImage> {
+    let family = RawFamily::new(family);
+    let (image, width_at_border) = unsafe {
+        if id >= (*family.ptr).ncodes {
+            return None;
+        }
+        let ptr = sys::apriltag_to_image(family.ptr, id as i32);
+        if ptr.is_null() {
+            return None;
+        }
+        // Made with `image_u8_create`, the image frees it
+        (Image::from_raw(ptr), (*family.ptr).width_at_border as u32)
+    };
+
+    let (width, height, stride) = (image.width(), image.height(), image.stride());
+    let pixels = image.as_slice();
+    Some(TagImage {
+        image: GrayImage::from_fn(width as u32, height as u32, |x, y| {
+            Luma([pixels[y as usize * stride + x as usize]])
+        }),
+        width_at_border,
+    })
+}
+
+/// A family the renderer can read the codes of, the `apriltag` crate doesn't give access to them
+struct RawFamily {
+    ptr: *mut sys::apriltag_family_t,
+    destroy: unsafe extern "C" fn(*mut sys::apriltag_family_t),
+}
+
+impl RawFamily {
+    fn new(family: &AprilTagFamily) -> Self {
+        let (create, destroy): (unsafe extern "C" fn() -> _, unsafe extern "C" fn(_)) = match family {
+            AprilTagFamily::Tag16H5 => (sys::tag16h5_create, sys::tag16h5_destroy),
+            AprilTagFamily::Tag25H9 => (sys::tag25h9_create, sys::tag25h9_destroy),
+            AprilTagFamily::Tag36H11 => (sys::tag36h11_create, sys::tag36h11_destroy),
+            AprilTagFamily::TagCircle21H7 => (sys::tagCircle21h7_create, sys::tagCircle21h7_destroy),
+            AprilTagFamily::TagCircle49h12 => (sys::tagCircle49h12_create, sys::tagCircle49h12_destroy),
+            AprilTagFamily::TagStandard41h12 => (sys::tagStandard41h12_create, sys::tagStandard41h12_destroy),
+            AprilTagFamily::TagStandard52h13 => (sys::tagStandard52h13_create, sys::tagStandard52h13_destroy),
+            AprilTagFamily::TagCustom48h12 => (sys::tagCustom48h12_create, sys::tagCustom48h12_destroy),
+        };
+        RawFamily {
+            ptr: unsafe { create() },
+            destroy,
+        }
+    }
+}
+
+impl Drop for RawFamily {
+    fn drop(&mut self) {
+        unsafe { (self.destroy)(self.ptr) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_4_2_0() {
+        let image = RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, 128]));
+        let buffer = mjpeg_buffer(&image).unwrap();
+        let decompress = mozjpeg::Decompress::new_mem(buffer.buffer()).unwrap();
+        assert_eq!(decompress.size(), (64, 48));
+        let sampling: Vec<_> = decompress
+            .components()
+            .iter()
+            .map(|component| (component.h_samp_factor, component.v_samp_factor))
+            .collect();
+        assert_eq!(sampling, [(2, 2), (1, 1), (1, 1)]);
+    }
+}
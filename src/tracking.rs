@@ -0,0 +1,402 @@
+//! # Pose tracking
+//!
+//! Optional stage that runs after detection and smooths poses across frames.
+//!
+//! Every tag in view gets a `PoseTrack`, and when a field layout is configured the robot gets one as well.
+//! A track is a pair of constant velocity Kalman filters, one over the translation and one over the euler angles.
+//! Each new measurement is gated on its Mahalanobis distance from the prediction so a single bad frame (a flipped
+//! pose, a reflection) is thrown out instead of dragging the estimate. If a track keeps rejecting measurements it is
+//! restarted from the latest one, since at that point the track is the thing that's wrong.
+//! On frames without a robot pose the robot track is predicted to the frame time, so it keeps moving with the robot
+//! until it hasn't been seen for `TrackingConfig::timeout_ms`. Tracks that old are dropped.
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use nalgebra::{Isometry3, Matrix3, Matrix3x6, Matrix6, Vector3, Vector6};
+use serde::*;
+
+use crate::config::TrackingConfig;
+use crate::process::{TagPose, VisionFrame};
+
+/// Wraps an angle into [-π, π)
+fn wrap_angle(angle: f64) -> f64 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+// --- Implementation of CvFilter ---
+/// Constant velocity Kalman filter over three axes.
+///
+/// The state is the three positions followed by the three velocities.
+#[derive(Debug, Clone)]
+pub struct CvFilter {
+    state: Vector6<f64>,
+    covariance: Matrix6<f64>,
+    /// Spectral density of the acceleration noise
+    process_noise: f64,
+    /// Whether the axes are angles that need wrapping
+    angular: bool,
+}
+
+impl CvFilter {
+    /// Starts a filter at the measurement with no velocity
+    pub fn new(measurement: Vector3<f64>, measurement_std: f64, process_noise: f64, angular: bool) -> Self {
+        let mut state = Vector6::zeros();
+        state.fixed_rows_mut::<3>(0).copy_from(&measurement);
+
+        // We know where it is about as well as the measurement, and nothing about how fast it's going
+        let mut covariance = Matrix6::zeros();
+        covariance
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(Matrix3::identity() * measurement_std.powi(2)));
+        covariance
+            .fixed_view_mut::<3, 3>(3, 3)
+            .copy_from(&(Matrix3::identity() * process_noise));
+
+        CvFilter {
+            state,
+            covariance,
+            process_noise,
+            angular,
+        }
+    }
+
+    /// Current position estimate
+    pub fn position(&self) -> Vector3<f64> {
+        self.state.fixed_rows::<3>(0).into_owned()
+    }
+
+    /// Current velocity estimate
+    pub fn velocity(&self) -> Vector3<f64> {
+        self.state.fixed_rows::<3>(3).into_owned()
+    }
+
+    /// Covariance of the position estimate
+    pub fn position_covariance(&self) -> Matrix3<f64> {
+        self.covariance.fixed_view::<3, 3>(0, 0).into_owned()
+    }
+
+    /// Moves the state forward by `dt` seconds
+    pub fn predict(&mut self, dt: f64) {
+        let mut transition = Matrix6::identity();
+        transition
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(Matrix3::identity() * dt));
+
+        // Continuous white noise acceleration model
+        let q = self.process_noise;
+        let mut noise = Matrix6::zeros();
+        noise
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(Matrix3::identity() * (q * dt.powi(3) / 3.0)));
+        noise
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(Matrix3::identity() * (q * dt.powi(2) / 2.0)));
+        noise
+            .fixed_view_mut::<3, 3>(3, 0)
+            .copy_from(&(Matrix3::identity() * (q * dt.powi(2) / 2.0)));
+        noise
+            .fixed_view_mut::<3, 3>(3, 3)
+            .copy_from(&(Matrix3::identity() * (q * dt)));
+
+        self.state = transition * self.state;
+        self.covariance = transition * self.covariance * transition.transpose() + noise;
+        if self.angular {
+            for i in 0..3 {
+                self.state[i] = wrap_angle(self.state[i]);
+            }
+        }
+    }
+
+    /// Squared Mahalanobis distance of the measurement from the current estimate
+    pub fn mahalanobis(&self, measurement: &Vector3<f64>, measurement_std: f64) -> Option<f64> {
+        let (innovation, innovation_cov) = self.innovation(measurement, measurement_std);
+        let inverse = innovation_cov.try_inverse()?;
+        Some((innovation.transpose() * inverse * innovation)[0])
+    }
+
+    /// Folds a measurement into the estimate
+    pub fn update(&mut self, measurement: &Vector3<f64>, measurement_std: f64) {
+        let (innovation, innovation_cov) = self.innovation(measurement, measurement_std);
+        let Some(inverse) = innovation_cov.try_inverse() else {
+            return;
+        };
+        let observation = Self::observation();
+        let gain = self.covariance * observation.transpose() * inverse;
+
+        self.state += gain * innovation;
+        self.covariance = (Matrix6::identity() - gain * observation) * self.covariance;
+        if self.angular {
+            for i in 0..3 {
+                self.state[i] = wrap_angle(self.state[i]);
+            }
+        }
+    }
+
+    fn observation() -> Matrix3x6<f64> {
+        let mut observation = Matrix3x6::zeros();
+        observation
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&Matrix3::identity());
+        observation
+    }
+
+    fn innovation(&self, measurement: &Vector3<f64>, measurement_std: f64) -> (Vector3<f64>, Matrix3<f64>) {
+        let mut innovation = measurement - self.position();
+        if self.angular {
+            innovation.apply(|angle| *angle = wrap_angle(*angle));
+        }
+        let observation = Self::observation();
+        let innovation_cov = observation * self.covariance * observation.transpose()
+            + Matrix3::identity() * measurement_std.powi(2);
+        (innovation, innovation_cov)
+    }
+}
+// --- Implementation of CvFilter ---
+
+// --- Implementation of PoseTrack ---
+/// Smoothed pose, along with how fast it is changing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilteredPose {
+    /// Translation in meters
+    pub translation: [f64; 3],
+    /// Rotation as euler angles (roll, pitch, yaw) in radians
+    pub rotation: [f64; 3],
+    /// Translation velocity in m/s
+    pub velocity: [f64; 3],
+    /// Rotation velocity in rad/s
+    pub angular_velocity: [f64; 3],
+    /// Standard deviation of the translation estimate on each axis, in meters
+    pub translation_std: [f64; 3],
+}
+
+/// A pose being tracked across frames
+#[derive(Debug, Clone)]
+pub struct PoseTrack {
+    translation: CvFilter,
+    rotation: CvFilter,
+    /// Timestamp of the last frame this track was predicted to
+    last_time: f64,
+    /// Timestamp of the last accepted measurement
+    last_seen: f64,
+    rejects: u32,
+}
+
+impl PoseTrack {
+    fn new(translation: Vector3<f64>, rotation: Vector3<f64>, timestamp: f64, config: &TrackingConfig) -> Self {
+        PoseTrack {
+            translation: CvFilter::new(
+                translation,
+                config.translation_measurement_noise,
+                config.translation_process_noise,
+                false,
+            ),
+            rotation: CvFilter::new(
+                rotation,
+                config.rotation_measurement_noise,
+                config.rotation_process_noise,
+                true,
+            ),
+            last_time: timestamp,
+            last_seen: timestamp,
+            rejects: 0,
+        }
+    }
+
+    /// Moves the estimate forward to the timestamp
+    fn predict(&mut self, timestamp: f64) {
+        let dt = (timestamp - self.last_time).max(0.0);
+        self.translation.predict(dt);
+        self.rotation.predict(dt);
+        self.last_time = self.last_time.max(timestamp);
+    }
+
+    /// Predicts to the timestamp then gates and applies the measurement.
+    ///
+    /// `noise_scale` multiplies the configured measurement noise, for measurements known to be worse than usual.
+    /// Returns whether the measurement was accepted.
+    fn update(
+        &mut self,
+        translation: Vector3<f64>,
+        rotation: Vector3<f64>,
+        timestamp: f64,
+        noise_scale: f64,
+        config: &TrackingConfig,
+    ) -> bool {
+        self.predict(timestamp);
+
+        let translation_std = config.translation_measurement_noise * noise_scale;
+        let rotation_std = config.rotation_measurement_noise * noise_scale;
+        let accepted = match (
+            self.translation.mahalanobis(&translation, translation_std),
+            self.rotation.mahalanobis(&rotation, rotation_std),
+        ) {
+            (Some(t), Some(r)) => t <= config.gate && r <= config.gate,
+            _ => false,
+        };
+
+        if accepted {
+            self.translation.update(&translation, translation_std);
+            self.rotation.update(&rotation, rotation_std);
+            self.last_seen = timestamp;
+            self.rejects = 0;
+        } else {
+            self.rejects += 1;
+            if self.rejects > config.max_rejects {
+                // The measurements agree with each other and not with us, start over from them
+                *self = PoseTrack::new(translation, rotation, timestamp, config);
+                return true;
+            }
+        }
+        accepted
+    }
+
+    fn filtered(&self) -> FilteredPose {
+        let covariance = self.translation.position_covariance();
+        FilteredPose {
+            translation: self.translation.position().into(),
+            rotation: self.rotation.position().into(),
+            velocity: self.translation.velocity().into(),
+            angular_velocity: self.rotation.velocity().into(),
+            translation_std: [covariance[(0, 0)].sqrt(), covariance[(1, 1)].sqrt(), covariance[(2, 2)].sqrt()],
+        }
+    }
+}
+// --- Implementation of PoseTrack ---
+
+// --- Implementation of Tracker ---
+/// Keeps a track for every tag in view, and for the robot when there is a field layout
+pub struct Tracker {
+    config: TrackingConfig,
+    tags: HashMap<u32, PoseTrack>,
+    robot: Option<PoseTrack>,
+}
+
+impl Tracker {
+    pub fn new(config: TrackingConfig) -> Self {
+        Tracker {
+            config,
+            tags: HashMap::new(),
+            robot: None,
+        }
+    }
+
+    /// Runs every pose in the frame through its track and fills in the filtered poses
+    pub fn update(&mut self, frame: &mut VisionFrame) {
+        let timestamp = frame.timestamp;
+        let timeout = self.config.timeout_ms as f64 / 1000.0;
+        self.tags.retain(|_, track| timestamp - track.last_seen <= timeout);
+        if matches!(&self.robot, Some(track) if timestamp - track.last_seen > timeout) {
+            self.robot = None;
+        }
+
+        for target in frame.targets.iter_mut() {
+            let (translation, rotation) = tag_measurement(&target.best);
+            let noise_scale = 1.0 + target.ambiguity;
+            let track = match self.tags.entry(target.id) {
+                Entry::Occupied(entry) => {
+                    let track = entry.into_mut();
+                    track.update(translation, rotation, timestamp, noise_scale, &self.config);
+                    track
+                }
+                Entry::Vacant(entry) => entry.insert(PoseTrack::new(translation, rotation, timestamp, &self.config)),
+            };
+            target.filtered = Some(track.filtered());
+        }
+
+        if let Some(pose) = &frame.robot_pose {
+            let (translation, rotation) = isometry_measurement(pose);
+            match &mut self.robot {
+                Some(track) => {
+                    track.update(translation, rotation, timestamp, 1.0, &self.config);
+                }
+                None => {
+                    self.robot = Some(PoseTrack::new(translation, rotation, timestamp, &self.config));
+                }
+            }
+        } else if let Some(track) = &mut self.robot {
+            // Nothing to fold in, but the pose sent out still has to be for this frame
+            track.predict(timestamp);
+        }
+        frame.filtered_robot_pose = self.robot.as_ref().map(PoseTrack::filtered);
+    }
+}
+
+fn tag_measurement(pose: &TagPose) -> (Vector3<f64>, Vector3<f64>) {
+    (Vector3::from(pose.translation), Vector3::from(pose.euler_angles()))
+}
+
+fn isometry_measurement(pose: &Isometry3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    let (roll, pitch, yaw) = pose.rotation.euler_angles();
+    (pose.translation.vector, Vector3::new(roll, pitch, yaw))
+}
+// --- Implementation of Tracker ---
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::tests::sample_frame;
+
+    #[test]
+    fn new_track_starts_at_the_first_measurement() {
+        let config = TrackingConfig::default();
+        let mut tracker = Tracker::new(config.clone());
+        let mut frame = sample_frame();
+        tracker.update(&mut frame);
+
+        // Folding the first measurement in a second time would have shrunk the uncertainty below one measurement's
+        let filtered = frame.targets[0].filtered.clone().unwrap();
+        assert_eq!(filtered.translation, [0.5, -0.25, 2.0]);
+        for std in filtered.translation_std {
+            assert!((std - config.translation_measurement_noise).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn track_follows_later_measurements() {
+        let mut tracker = Tracker::new(TrackingConfig::default());
+        let mut frame = sample_frame();
+        tracker.update(&mut frame);
+        let first = frame.targets[0].filtered.clone().unwrap();
+
+        frame.timestamp += 0.02;
+        frame.targets[0].best.translation[2] = 2.01;
+        tracker.update(&mut frame);
+        let second = frame.targets[0].filtered.clone().unwrap();
+        assert!(second.translation[2] > 2.0 && second.translation[2] < 2.01);
+        assert!(second.translation_std[2] < first.translation_std[2]);
+    }
+
+    #[test]
+    fn robot_track_coasts_to_the_frame_time() {
+        let config = TrackingConfig::default();
+        let mut tracker = Tracker::new(config.clone());
+        let mut frame = sample_frame();
+        for step in 0..10 {
+            frame.robot_pose = Some(Isometry3::translation(step as f64 * 0.02, 0.0, 0.0));
+            tracker.update(&mut frame);
+            frame.timestamp += 0.02;
+        }
+        let seen = frame.filtered_robot_pose.clone().unwrap();
+
+        // Moving at 1 m/s, the frames without a pose keep it moving and less sure of itself
+        frame.robot_pose = None;
+        frame.timestamp += 0.1;
+        tracker.update(&mut frame);
+        let coasted = frame.filtered_robot_pose.clone().unwrap();
+        assert!(coasted.velocity[0] > 0.5);
+        assert!(coasted.translation[0] > seen.translation[0] + 0.05);
+        assert!(coasted.translation_std[0] > seen.translation_std[0]);
+
+        // Past the timeout there is nothing to send
+        frame.timestamp += config.timeout_ms as f64 / 1000.0;
+        tracker.update(&mut frame);
+        assert!(frame.filtered_robot_pose.is_none());
+    }
+
+    #[test]
+    fn wraps_angles() {
+        assert!((wrap_angle(3.0 * PI / 2.0) + PI / 2.0).abs() < 1e-12);
+        assert!((wrap_angle(-3.0 * PI / 2.0) - PI / 2.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,151 @@
+//! # Measurement uncertainty
+//!
+//! Estimates standard deviations for every pose so the robot's pose estimator knows how much to trust it.
+//!
+//! The model is in `UncertaintyConfig`: a detection starts from the base standard deviations and is scaled up by
+//! its distance, a low decision margin, reprojection error and ambiguity. The robot pose is then scaled down by
+//! how many field tags are in view, since a frame full of tags that agree is worth more than a lone one.
+//!
+//! The standard deviations are x, y and theta in the order WPILib's `addVisionMeasurement` takes them.
+use serde::*;
+
+use crate::config::UncertaintyConfig;
+use crate::process::TagTarget;
+
+/// Tags closer than this are treated as this far away, so a tag right in front of the lens doesn't get a
+/// standard deviation of zero
+pub const MIN_TAG_DISTANCE: f64 = 0.25;
+
+/// Standard deviations of a pose
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct StdDevs {
+    /// Along x in meters
+    pub x: f64,
+    /// Along y in meters
+    pub y: f64,
+    /// Of the heading in radians
+    pub theta: f64,
+}
+
+impl StdDevs {
+    /// As `[x, y, theta]`
+    pub fn as_array(&self) -> [f64; 3] {
+        [self.x, self.y, self.theta]
+    }
+
+    fn scaled(&self, factor: f64) -> Self {
+        StdDevs {
+            x: self.x * factor,
+            y: self.y * factor,
+            theta: self.theta * factor,
+        }
+    }
+}
+
+/// Standard deviations of a single tag's best pose, the translation ones are the same along every axis so they
+/// don't depend on which frame the pose is read in
+pub fn tag_std_devs(target: &TagTarget, config: &UncertaintyConfig) -> StdDevs {
+    let [x, y, z] = target.best.translation;
+    let distance = (x * x + y * y + z * z).sqrt().max(MIN_TAG_DISTANCE);
+
+    let margin = target.decision_margin as f64;
+    let margin_factor = if margin > 0.0 {
+        (config.reference_margin / margin).max(1.0)
+    } else {
+        f64::INFINITY
+    };
+
+    let factor = distance.powf(config.distance_exponent)
+        * margin_factor
+        * (1.0 + config.reprojection_weight * target.reprojection_error)
+        * (1.0 + config.ambiguity_weight * target.ambiguity);
+
+    StdDevs {
+        x: config.translation_std * factor,
+        y: config.translation_std * factor,
+        theta: config.rotation_std * factor,
+    }
+}
+
+/// Standard deviations of a robot pose worked out from `target`, with `tag_count` field tags in view
+pub fn robot_std_devs(target: &TagTarget, tag_count: usize, config: &UncertaintyConfig) -> StdDevs {
+    let count = tag_count.max(1) as f64;
+    target.std_devs.scaled(1.0 / count.powf(config.tag_count_exponent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::tests::sample_target;
+
+    fn at_distance(distance: f64) -> TagTarget {
+        let mut target = sample_target();
+        target.best.translation = [0.0, 0.0, distance];
+        target
+    }
+
+    #[test]
+    fn grows_with_distance() {
+        let config = UncertaintyConfig::default();
+        let near = tag_std_devs(&at_distance(1.0), &config);
+        let far = tag_std_devs(&at_distance(4.0), &config);
+        assert!(far.x > near.x && far.theta > near.theta);
+        // Squared by default
+        assert!((far.x / near.x - 16.0).abs() < 1e-9);
+        assert_eq!(near.x, near.y);
+    }
+
+    #[test]
+    fn grows_with_ambiguity() {
+        let config = UncertaintyConfig::default();
+        let mut target = sample_target();
+        target.ambiguity = 0.0;
+        let clear = tag_std_devs(&target, &config);
+        target.ambiguity = 0.2;
+        let ambiguous = tag_std_devs(&target, &config);
+        assert!(ambiguous.x > clear.x && ambiguous.theta > clear.theta);
+    }
+
+    #[test]
+    fn shrinks_with_decision_margin() {
+        let config = UncertaintyConfig::default();
+        let mut target = sample_target();
+        target.decision_margin = 50.0;
+        let weak = tag_std_devs(&target, &config);
+        target.decision_margin = 100.0;
+        let strong = tag_std_devs(&target, &config);
+        assert!(strong.x < weak.x && strong.theta < weak.theta);
+
+        // Past the reference margin it stops helping
+        target.decision_margin = config.reference_margin as f32;
+        let reference = tag_std_devs(&target, &config);
+        target.decision_margin = config.reference_margin as f32 * 4.0;
+        assert_eq!(tag_std_devs(&target, &config).x, reference.x);
+
+        target.decision_margin = 0.0;
+        assert!(tag_std_devs(&target, &config).x.is_infinite());
+    }
+
+    #[test]
+    fn has_a_floor() {
+        let config = UncertaintyConfig::default();
+        let touching = tag_std_devs(&at_distance(0.0), &config);
+        let closest = tag_std_devs(&at_distance(MIN_TAG_DISTANCE), &config);
+        assert!(touching.x > 0.0 && touching.theta > 0.0);
+        assert_eq!(touching.x, closest.x);
+        assert_eq!(touching.theta, closest.theta);
+    }
+
+    #[test]
+    fn shrinks_with_more_tags() {
+        let config = UncertaintyConfig::default();
+        let target = sample_target();
+        let one = robot_std_devs(&target, 1, &config);
+        let four = robot_std_devs(&target, 4, &config);
+        assert_eq!(one.as_array(), target.std_devs.as_array());
+        assert!((four.x - one.x / 2.0).abs() < 1e-12);
+        assert!(four.y < one.y && four.theta < one.theta);
+        // No tags is treated as one
+        assert_eq!(robot_std_devs(&target, 0, &config).as_array(), one.as_array());
+    }
+}